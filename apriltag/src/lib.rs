@@ -3,7 +3,7 @@
 mod sys;
 
 use std::mem::MaybeUninit;
-use nalgebra::{Matrix3, Rotation3, Point3};
+use nalgebra::{Matrix3, Rotation3, Point3, Isometry3, Translation3, UnitQuaternion};
 use sys::*;
 use std::os::raw::c_int;
 use std::slice;
@@ -146,10 +146,89 @@ impl ApriltagDetection {
         ApriltagDetection { error, rotation, translation, tag_id, image_center, image_tag_corners }
     }
 
+    /// Puts a detection back together from what its getters return, like after it was sent over
+    /// the network
+    pub fn from_parts(
+        error: f64,
+        tag_id: usize,
+        image_center: (u32, u32),
+        image_tag_corners: [(u32, u32); 4],
+        rotation: Rotation3<f64>,
+        translation: Point3<f64>,
+    ) -> ApriltagDetection {
+        ApriltagDetection { error, tag_id, image_center, image_tag_corners, rotation, translation }
+    }
+
+    /// Where the camera is and which way it is facing, in the coordinate frame of the tag.
+    /// The camera looks down its positive z axis, with positive y pointing down in the image
+    pub fn camera_pose(&self) -> Isometry3<f64> {
+        // `translation` is the position of the tag in the frame of the camera and `rotation`
+        // takes the frame of the camera to the frame of the tag
+        let camera_position = -(self.rotation * self.translation.coords);
+
+        Isometry3::from_parts(
+            Translation3::from(camera_position),
+            UnitQuaternion::from_rotation_matrix(&self.rotation),
+        )
+    }
+
+    pub fn rotation(&self) -> Rotation3<f64> {
+        self.rotation
+    }
+
+    pub fn translation(&self) -> Point3<f64> {
+        self.translation
+    }
+
     pub fn euler_angles(&self) -> EulerAngles {
         let (pitch, yaw, roll) = self.rotation.euler_angles();
         EulerAngles { pitch, roll, yaw }
     }
+
+    /// The euler angles we would have measured if the image had been rotated clockwise by
+    /// `clockwise_quarter_turns` before searching for the tag
+    pub fn euler_angles_in_rotated_image(&self, clockwise_quarter_turns: u32) -> EulerAngles {
+        // rotating the image clockwise about its center is the same as rotating the camera
+        // frame about its optical axis, (x, y, z) -> (-y, x, z) for each quarter turn
+        let quarter_turn = Rotation3::from_matrix_unchecked(Matrix3::new(
+            0.0, -1.0, 0.0,
+            1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0,
+        ));
+
+        let mut rotation = self.rotation;
+        for _ in 0..clockwise_quarter_turns % 4 {
+            rotation = rotation * quarter_turn.inverse();
+        }
+
+        let (pitch, yaw, roll) = rotation.euler_angles();
+        EulerAngles { pitch, roll, yaw }
+    }
+
+    /// How far the tag appears to be rotated clockwise in the image, measured along its bottom edge.
+    /// Zero when the tag is upright
+    pub fn image_rotation(&self) -> f64 {
+        // the corners wrap counter-clockwise around the tag starting at the bottom left
+        let (x0, y0) = self.image_tag_corners[0];
+        let (x1, y1) = self.image_tag_corners[1];
+        (y1 as f64 - y0 as f64).atan2(x1 as f64 - x0 as f64)
+    }
+
+    pub fn tag_id(&self) -> usize {
+        self.tag_id
+    }
+
+    pub fn error(&self) -> f64 {
+        self.error
+    }
+
+    pub fn image_center(&self) -> (u32, u32) {
+        self.image_center
+    }
+
+    pub fn image_tag_corners(&self) -> [(u32, u32); 4] {
+        self.image_tag_corners
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
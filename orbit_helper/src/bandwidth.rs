@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::{error, fmt, fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use orbit_types::CameraFormat;
+
+use crate::known_devices::DeviceFileIndex;
+
+// periodic transfers, which is what cameras use, only get this much of a USB bus
+const PERIODIC_SHARE: f64 = 0.8;
+// a high speed isochronous endpoint sends at most three 1024 byte packets every 125µs microframe,
+// no matter how much room is left on the bus
+const MAX_ENDPOINT_BYTES_PER_SEC: u64 = 3 * 1024 * 8000;
+// MJPG frames are compressed, but UVC cameras reserve bandwidth for the worst case. This is about
+// what ours reserve, going by the 1080p streams that fail
+const MJPG_BYTES_PER_PIXEL: f64 = 0.5;
+const YUYV_BYTES_PER_PIXEL: f64 = 2.0;
+
+/// Keeps track of how much of each USB bus the open cameras have reserved, so we can pick formats
+/// that fit instead of finding out when `STREAMON` fails. Cloning it shares the same reservations
+#[derive(Clone)]
+pub struct BandwidthLedger {
+    /// Bytes per second reserved on each bus, by the bus's root hub directory in sysfs
+    reserved: Arc<Mutex<HashMap<PathBuf, u64>>>,
+}
+
+/// Bandwidth reserved by an open camera, given back when it's dropped
+pub struct Reservation {
+    ledger: BandwidthLedger,
+    bus: Option<PathBuf>,
+    bytes_per_sec: u64,
+}
+
+/// How much bandwidth a camera can use, or `None` if it isn't on a USB bus we know about
+#[derive(Copy, Clone, Debug)]
+pub struct Allowance {
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl BandwidthLedger {
+    pub fn new() -> BandwidthLedger {
+        BandwidthLedger { reserved: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// How much is left on the camera's bus, up to what a single camera can use
+    pub fn allowance(&self, device_index: DeviceFileIndex) -> Allowance {
+        let bus = match usb_bus(device_index) {
+            Some(bus) => bus,
+            None => return Allowance { bytes_per_sec: None },
+        };
+
+        let reserved = self.reserved.lock().unwrap().get(&bus).copied().unwrap_or(0);
+        let remaining = bus_budget(&bus).saturating_sub(reserved);
+
+        Allowance { bytes_per_sec: Some(remaining.min(MAX_ENDPOINT_BYTES_PER_SEC)) }
+    }
+
+    /// What the camera could use if nothing else on its bus were open
+    pub fn idle_allowance(&self, device_index: DeviceFileIndex) -> Allowance {
+        let bytes_per_sec = usb_bus(device_index)
+            .map(|bus| bus_budget(&bus).min(MAX_ENDPOINT_BYTES_PER_SEC));
+
+        Allowance { bytes_per_sec }
+    }
+
+    /// Reserves what `format` needs, failing with a `BandwidthExceeded` error if it doesn't fit.
+    /// Cameras are opened on several threads at once, so the bus might have filled up since
+    /// `allowance`
+    pub fn reserve(&self, device_index: DeviceFileIndex, format: CameraFormat) -> io::Result<Reservation> {
+        let bytes_per_sec = estimate(format);
+
+        let bus = match usb_bus(device_index) {
+            Some(bus) => bus,
+            None => return Ok(Reservation { ledger: self.clone(), bus: None, bytes_per_sec }),
+        };
+
+        let mut reserved = self.reserved.lock().unwrap();
+        let already_reserved = reserved.get(&bus).copied().unwrap_or(0);
+        let available = bus_budget(&bus).saturating_sub(already_reserved).min(MAX_ENDPOINT_BYTES_PER_SEC);
+
+        if bytes_per_sec > available {
+            return Err(Allowance { bytes_per_sec: Some(available) }.exceeded_by(format));
+        }
+
+        *reserved.entry(bus.clone()).or_insert(0) += bytes_per_sec;
+
+        Ok(Reservation { ledger: self.clone(), bus: Some(bus), bytes_per_sec })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(ref bus) = self.bus {
+            if let Some(reserved) = self.ledger.reserved.lock().unwrap().get_mut(bus) {
+                *reserved = reserved.saturating_sub(self.bytes_per_sec);
+            }
+        }
+    }
+}
+
+impl Allowance {
+    pub fn fits(self, format: CameraFormat) -> bool {
+        match self.bytes_per_sec {
+            Some(bytes_per_sec) => estimate(format) <= bytes_per_sec,
+            None => true,
+        }
+    }
+
+    /// The error for a camera that can't do anything smaller than `format`
+    pub fn exceeded_by(self, format: CameraFormat) -> io::Error {
+        let error = BandwidthExceeded {
+            format,
+            needed: estimate(format),
+            available: self.bytes_per_sec.unwrap_or(0),
+        };
+
+        io::Error::other(error)
+    }
+}
+
+#[derive(Debug)]
+pub struct BandwidthExceeded {
+    format: CameraFormat,
+    needed: u64,
+    available: u64,
+}
+
+impl fmt::Display for BandwidthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} at {}fps needs {:.1}MB/s of USB bandwidth, but only {:.1}MB/s is left on the camera's bus",
+            self.format.width,
+            self.format.height,
+            self.format.fps,
+            self.needed as f64 / 1e6,
+            self.available as f64 / 1e6,
+        )
+    }
+}
+
+impl error::Error for BandwidthExceeded {}
+
+/// Whether the camera failed because there wasn't enough bandwidth for it, which no amount of
+/// reopening will fix
+pub fn is_exceeded(error: &io::Error) -> bool {
+    matches!(error.get_ref(), Some(inner) if inner.is::<BandwidthExceeded>())
+}
+
+/// The isochronous bandwidth a camera reserves for `format`, in bytes per second
+pub fn estimate(format: CameraFormat) -> u64 {
+    let bytes_per_pixel = match &format.fourcc {
+        b"YUYV" => YUYV_BYTES_PER_PIXEL,
+        _ => MJPG_BYTES_PER_PIXEL,
+    };
+
+    (format.width as f64 * format.height as f64 * bytes_per_pixel * format.fps as f64) as u64
+}
+
+/// The root hub of the bus the camera is plugged into, like `/sys/devices/platform/soc/1c1b000.usb/usb3`
+fn usb_bus(device_index: DeviceFileIndex) -> Option<PathBuf> {
+    let device = format!("/sys/class/video4linux/video{}/device", device_index.file_index());
+    let device = fs::canonicalize(device).ok()?;
+
+    device.ancestors()
+        .find(|dir| matches!(dir.file_name().and_then(|name| name.to_str()), Some(name) if name.starts_with("usb")))
+        .map(|bus| bus.to_path_buf())
+}
+
+/// The periodic bandwidth of the bus in bytes per second, going by the speed of its root hub
+fn bus_budget(bus: &Path) -> u64 {
+    // in megabits per second: 1.5, 12, 480, 5000, ...
+    let speed: f64 = fs::read_to_string(bus.join("speed")).ok()
+        .and_then(|speed| speed.trim().parse().ok())
+        .unwrap_or(480.0);
+
+    (speed * 1e6 / 8.0 * PERIODIC_SHARE) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(width: u32, height: u32, fps: u32, fourcc: &[u8; 4]) -> CameraFormat {
+        CameraFormat { width, height, fps, fourcc: *fourcc }
+    }
+
+    #[test]
+    fn mjpg_reserves_half_a_byte_per_pixel() {
+        assert_eq!(estimate(format(1280, 720, 30, b"MJPG")), 13_824_000);
+    }
+
+    #[test]
+    fn yuyv_reserves_two_bytes_per_pixel() {
+        assert_eq!(estimate(format(640, 480, 30, b"YUYV")), 18_432_000);
+    }
+
+    #[test]
+    fn formats_fit_up_to_the_allowance() {
+        let allowance = Allowance { bytes_per_sec: Some(13_824_000) };
+
+        assert!(allowance.fits(format(1280, 720, 30, b"MJPG")));
+        assert!(!allowance.fits(format(1280, 720, 31, b"MJPG")));
+        assert!(!allowance.fits(format(1280, 720, 30, b"YUYV")));
+    }
+
+    #[test]
+    fn cameras_off_usb_fit_anything() {
+        assert!(Allowance { bytes_per_sec: None }.fits(format(3840, 2160, 60, b"YUYV")));
+    }
+
+    #[test]
+    fn exceeded_allowances_are_told_apart_from_other_errors() {
+        let error = Allowance { bytes_per_sec: Some(1_000_000) }.exceeded_by(format(1280, 720, 30, b"MJPG"));
+
+        assert!(is_exceeded(&error));
+        assert_eq!(
+            error.to_string(),
+            "1280x720 at 30fps needs 13.8MB/s of USB bandwidth, but only 1.0MB/s is left on the camera's bus",
+        );
+        assert!(!is_exceeded(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(!is_exceeded(&io::Error::other("something else")));
+    }
+}
@@ -0,0 +1,143 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use apriltag::{ApriltagDetector, ApriltagDetection, TagFamily};
+use image::{GrayImage, ImageFormat, ImageError, ImageResult};
+use image::error::{ParameterError, ParameterErrorKind};
+use orbit_types::{CapturedFrame, CameraDetections, DetectResponse, TagDetection, TagSearch};
+
+/// A few threads that search stills for tags for as long as the helper runs, each with a detector
+/// of its own, since the detector can't be shared between threads. Cloning it shares the same
+/// threads
+#[derive(Clone)]
+pub struct Detectors {
+    jobs: Arc<Mutex<Sender<Job>>>,
+}
+
+struct Job {
+    index: usize,
+    still: CapturedFrame,
+    search: TagSearch,
+    answer: Sender<(usize, CameraDetections)>,
+}
+
+impl Detectors {
+    pub fn start(count: usize) -> Detectors {
+        let (jobs, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..count {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || work(&receiver));
+        }
+
+        Detectors { jobs: Arc::new(Mutex::new(jobs)) }
+    }
+
+    /// Searches every still for tags, as many at a time as there are detectors. A still that
+    /// couldn't be searched gets an error instead of detections
+    pub fn detect(&self, stills: Vec<CapturedFrame>, search: TagSearch) -> DetectResponse {
+        let (answer, answers) = mpsc::channel();
+        let mut cameras: Vec<CameraDetections> = stills.iter()
+            .map(|still| CameraDetections {
+                device_id: still.device_id(),
+                captured_at: *still.captured_at(),
+                detections: Vec::new(),
+                still: None,
+                error: Some("the detector crashed".to_string()),
+            })
+            .collect();
+
+        {
+            let jobs = self.jobs.lock().unwrap();
+            for (index, still) in stills.into_iter().enumerate() {
+                let _ = jobs.send(Job { index, still, search, answer: answer.clone() });
+            }
+        }
+        // so we stop waiting once every job has been answered or dropped
+        drop(answer);
+
+        for (index, camera) in answers {
+            cameras[index] = camera;
+        }
+
+        DetectResponse { cameras }
+    }
+}
+
+fn work(jobs: &Mutex<Receiver<Job>>) {
+    let mut detector = ApriltagDetector::new(TagFamily::Tag36h11);
+
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let Job { index, still, search, answer } = job;
+        let device_id = still.device_id();
+        match panic::catch_unwind(AssertUnwindSafe(|| detect_one(&mut detector, still, search))) {
+            Ok(camera) => { let _ = answer.send((index, camera)); },
+            Err(_) => {
+                // dropping the answer reports the crash, and the detector might not be usable anymore
+                println!("the detector crashed on the still from {:?}", device_id);
+                detector = ApriltagDetector::new(TagFamily::Tag36h11);
+            },
+        }
+    }
+}
+
+fn detect_one(detector: &mut ApriltagDetector, still: CapturedFrame, search: TagSearch) -> CameraDetections {
+    let (detections, error) = match luma(&still) {
+        Ok(image) => {
+            let detections = detector.search(image.as_raw(), image.width(), image.height(), search.tag_size_meters, search.focal_length_at(image.width()))
+                .iter()
+                .map(to_tag_detection)
+                .collect();
+            (detections, None)
+        },
+        Err(e) => {
+            println!("couldn't decode the still from {:?}: {}", still.device_id(), e);
+            (Vec::new(), Some(format!("couldn't decode the still: {}", e)))
+        },
+    };
+
+    CameraDetections {
+        device_id: still.device_id(),
+        captured_at: *still.captured_at(),
+        detections,
+        still: if search.include_stills { Some(still) } else { None },
+        error,
+    }
+}
+
+/// The brightness of every pixel, which is all the detector looks at
+fn luma(still: &CapturedFrame) -> ImageResult<GrayImage> {
+    if still.is_yuyv() {
+        return orbit_types::yuyv_to_luma(still.frame_data(), still.width(), still.height())
+            .and_then(|luma| GrayImage::from_raw(still.width(), still.height(), luma))
+            .ok_or_else(|| ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)));
+    }
+
+    Ok(image::load_from_memory_with_format(still.frame_data(), ImageFormat::Jpeg)?.into_luma8())
+}
+
+fn to_tag_detection(detection: &ApriltagDetection) -> TagDetection {
+    let matrix = detection.rotation().into_inner();
+    let translation = detection.translation();
+
+    TagDetection {
+        tag_id: detection.tag_id(),
+        error: detection.error(),
+        image_center: detection.image_center(),
+        image_tag_corners: detection.image_tag_corners(),
+        rotation: [
+            [matrix[(0, 0)], matrix[(0, 1)], matrix[(0, 2)]],
+            [matrix[(1, 0)], matrix[(1, 1)], matrix[(1, 2)]],
+            [matrix[(2, 0)], matrix[(2, 1)], matrix[(2, 2)]],
+        ],
+        translation: [translation.x, translation.y, translation.z],
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::mem::MaybeUninit;
+use std::net::TcpStream;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use chrono::Utc;
+use orbit_types::{HelperHealth, StreamResponse};
+
+use crate::{STATUS_INTERVAL, STOP_CHECK};
+use crate::watchdog::Watchdog;
+
+/// Pushes a report to the station every `STATUS_INTERVAL` until the session ends
+pub fn spawn_reporter(watchdog: Watchdog, writer: Arc<Mutex<TcpStream>>, should_stop: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_report = Instant::now();
+
+        while !should_stop.load(Ordering::Relaxed) {
+            thread::sleep(STOP_CHECK);
+            if last_report.elapsed() < STATUS_INTERVAL { continue }
+            last_report = Instant::now();
+
+            let response = StreamResponse::Status(report(&watchdog));
+            if response.serialize_into(&mut *writer.lock().unwrap()).is_err() { break }
+        }
+    })
+}
+
+/// Reads how the board is doing from `/proc` and `/sys`, so one missing file doesn't hide the
+/// rest. What can't be read is `None`, apart from the uptime and load, which are left at 0
+pub fn report(watchdog: &Watchdog) -> HelperHealth {
+    let memory = meminfo();
+    let memory_field = |name: &str| memory.get(name).copied();
+
+    HelperHealth {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        clock: Utc::now(),
+        uptime_secs: uptime_secs(),
+        cpu_temp_celsius: cpu_temp_celsius(),
+        load: load(),
+        memory_total_bytes: memory_field("MemTotal"),
+        memory_available_bytes: memory_field("MemAvailable"),
+        swap_total_bytes: memory_field("SwapTotal"),
+        swap_free_bytes: memory_field("SwapFree"),
+        disk_free_bytes: disk_free_bytes(),
+        camera_fps: watchdog.frame_rates(),
+    }
+}
+
+fn uptime_secs() -> u64 {
+    fs::read_to_string("/proc/uptime").ok()
+        .and_then(|uptime| uptime.split_whitespace().next()?.parse::<f64>().ok())
+        .map_or(0, |secs| secs as u64)
+}
+
+fn cpu_temp_celsius() -> Option<f32> {
+    let zones = fs::read_dir("/sys/class/thermal").ok()?;
+
+    zones.filter_map(Result::ok)
+        .filter(|zone| zone.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|zone| fs::read_to_string(zone.path().join("temp")).ok())
+        // in thousandths of a degree
+        .filter_map(|temp| temp.trim().parse::<f32>().ok())
+        .map(|millidegrees| millidegrees / 1000.0)
+        .fold(None, |hottest: Option<f32>, temp| Some(hottest.map_or(temp, |hottest| hottest.max(temp))))
+}
+
+fn load() -> [f32; 3] {
+    let loadavg = fs::read_to_string("/proc/loadavg").unwrap_or_default();
+    let mut averages = loadavg.split_whitespace().map(|average| average.parse().unwrap_or(0.0));
+
+    [
+        averages.next().unwrap_or(0.0),
+        averages.next().unwrap_or(0.0),
+        averages.next().unwrap_or(0.0),
+    ]
+}
+
+/// The fields of `/proc/meminfo`, in bytes
+fn meminfo() -> HashMap<String, u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+
+    meminfo.lines()
+        .filter_map(|line| {
+            // like "MemAvailable:     123456 kB"
+            let mut parts = line.split_whitespace();
+            let name = parts.next()?.trim_end_matches(':');
+            let kilobytes: u64 = parts.next()?.parse().ok()?;
+            Some((name.to_string(), kilobytes * 1024))
+        })
+        .collect()
+}
+
+/// Recordings go in the home directory
+fn disk_free_bytes() -> Option<u64> {
+    let home = std::env::var_os("HOME").and_then(|home| CString::new(home.as_bytes()).ok())?;
+
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(home.as_ptr(), stats.as_mut_ptr()) } != 0 { return None }
+    let stats = unsafe { stats.assume_init() };
+
+    // they're only 32 bits on some boards
+    #[allow(clippy::unnecessary_cast)]
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
@@ -0,0 +1,120 @@
+use std::ffi::CString;
+use std::{io, mem, ptr, thread};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use libc::{c_int, c_void, inotify_event, IN_CLOEXEC, IN_CREATE, IN_DELETE};
+use orbit_types::{DeviceId, RecoveryAttempt};
+
+use crate::{SharedHelper, HOTPLUG_SETTLE};
+use crate::known_devices::DeviceFileIndex;
+
+#[derive(Copy, Clone, Debug)]
+pub enum DeviceChange {
+    Added(DeviceFileIndex, DeviceId),
+    Removed(DeviceId),
+    /// The watchdog is trying to get a stalled camera going again
+    Recovering(DeviceId, RecoveryAttempt),
+}
+
+/// Everyone who wants to hear about device changes. Cloning it shares the same subscribers
+#[derive(Clone)]
+pub struct DeviceSubscribers(Arc<Mutex<Vec<Sender<DeviceChange>>>>);
+
+impl DeviceSubscribers {
+    pub fn new() -> DeviceSubscribers {
+        DeviceSubscribers(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    /// Finds out about cameras being plugged in and unplugged from now on
+    pub fn subscribe(&self) -> Receiver<DeviceChange> {
+        let (sender, receiver) = mpsc::channel();
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn broadcast(&self, change: DeviceChange) {
+        // forget about subscribers that have gone away
+        self.0.lock().unwrap().retain(|subscriber| subscriber.send(change).is_ok());
+    }
+}
+
+/// Watches `/dev` for cameras being plugged in and unplugged, updates the known devices, and tells
+/// everyone who subscribed to the helper's device changes
+pub fn spawn_watcher(helper: &SharedHelper) {
+    let helper = Arc::clone(helper);
+
+    thread::spawn(move || {
+        if let Err(e) = watch(&helper) {
+            println!("stopped watching for cameras being plugged in: {:?}", e);
+        }
+    });
+}
+
+/// Closes the inotify instance however the watcher stops
+struct Inotify(c_int);
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn watch(helper: &SharedHelper) -> io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(IN_CLOEXEC) };
+    if fd < 0 { return Err(io::Error::last_os_error()) }
+    let inotify = Inotify(fd);
+
+    let path = CString::new("/dev").unwrap();
+    if unsafe { libc::inotify_add_watch(inotify.0, path.as_ptr(), IN_CREATE | IN_DELETE) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        let len = unsafe { libc::read(inotify.0, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+        if len < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted { continue }
+            return Err(error);
+        }
+
+        // a single read can return several events, each followed by its file name
+        let mut offset = 0;
+        while offset + mem::size_of::<inotify_event>() <= len as usize {
+            let event: inotify_event = unsafe { ptr::read_unaligned(buffer.as_ptr().add(offset) as *const inotify_event) };
+            let name_start = offset + mem::size_of::<inotify_event>();
+            let name_end = (name_start + event.len as usize).min(len as usize);
+            offset = name_end;
+
+            // the name is padded with zeroes
+            let name = &buffer[name_start..name_end];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+            let index = match std::str::from_utf8(name).ok().and_then(DeviceFileIndex::from_file_name) {
+                Some(index) => index,
+                None => continue,
+            };
+
+            if event.mask & IN_CREATE != 0 {
+                // udev is still setting up the device when it first appears. The helper isn't
+                // locked until we know it's a camera, so nobody waits on the settle or the probe
+                thread::sleep(HOTPLUG_SETTLE);
+                if !index.is_video_device() { continue }
+
+                let mut helper = helper.lock().unwrap();
+                if let Some(device_id) = helper.known_devices.add(index) {
+                    println!("{:?} plugged in as {:?}", index, device_id);
+                    helper.device_subscribers.broadcast(DeviceChange::Added(index, device_id));
+                }
+            } else if event.mask & IN_DELETE != 0 {
+                let mut helper = helper.lock().unwrap();
+                if let Some(device_id) = helper.known_devices.remove(index) {
+                    println!("{:?} unplugged, it was {:?}", index, device_id);
+                    helper.device_subscribers.broadcast(DeviceChange::Removed(device_id));
+                }
+            }
+        }
+    }
+}
@@ -2,16 +2,14 @@ use std::collections::{HashMap};
 use std::fs;
 use orbit_types::{DeviceId, DeviceIdGenerator};
 use std::fs::DirEntry;
-use crate::ACCEPTABLE_FORMAT;
+use crate::negotiation::SUPPORTED_FOURCCS;
 
+/// The cameras that are plugged in. We scan for them once at startup, and after that `hotplug`
+/// tells us when they come and go
 pub struct KnownDevices {
     index_to_id: HashMap<DeviceFileIndex, DeviceId>,
     id_to_index: HashMap<DeviceId, DeviceFileIndex>,
     device_id_generator: DeviceIdGenerator,
-
-    current_devices: Vec<DeviceFileIndex>,
-    to_add: Vec<DeviceFileIndex>,
-    to_remove: Vec<DeviceFileIndex>,
 }
 
 impl KnownDevices {
@@ -20,64 +18,36 @@ impl KnownDevices {
             index_to_id: HashMap::new(),
             id_to_index: HashMap::new(),
             device_id_generator: DeviceIdGenerator::new(),
-
-            current_devices: Vec::new(),
-            to_add: Vec::new(),
-            to_remove: Vec::new(),
         };
 
-        known_devices.update();
-
-        known_devices
-    }
-
-    pub fn update(&mut self) {
-        self.current_devices.clear();
         match fs::read_dir("/sys/class/video4linux") {
-            Ok(dir) => self.current_devices.extend(dir
-                .filter_map(Result::ok)
-                .filter_map(DeviceFileIndex::from_dir_entry)),
+            Ok(dir) => for index in dir.filter_map(Result::ok).filter_map(DeviceFileIndex::from_dir_entry) {
+                known_devices.add(index);
+            },
             Err(_) => {},
         };
 
-        self.to_add.clear();
-        for index in self.current_devices.iter() {
-            if !self.index_to_id.contains_key(index) {
-                self.to_add.push(*index);
-            }
-        }
-
-        self.to_remove.clear();
-        for index in self.index_to_id.keys() {
-            if !self.current_devices.contains(index) {
-                self.to_remove.push(*index);
-            }
-        }
+        known_devices
+    }
 
-        for &index in self.to_add.iter() {
-            let id = self.device_id_generator.next();
-            self.index_to_id.insert(index, id);
-            self.id_to_index.insert(id, index);
-        }
+    /// Returns the new device's id, or `None` if we already knew about it
+    pub fn add(&mut self, index: DeviceFileIndex) -> Option<DeviceId> {
+        if self.index_to_id.contains_key(&index) { return None }
 
-        for &index in self.to_remove.iter() {
-            let id: DeviceId = self.index_to_id[&index];
-            self.index_to_id.remove(&index);
-            self.id_to_index.remove(&id);
-        }
+        let id = self.device_id_generator.next();
+        self.index_to_id.insert(index, id);
+        self.id_to_index.insert(id, index);
+        Some(id)
     }
 
-    pub fn recently_added(&mut self) ->  impl Iterator<Item=(DeviceFileIndex, DeviceId)> + '_ {
-        self.update();
-
-        self.to_add.iter()
-            .map(|index| (*index, self.index_to_id[index]))
-            .collect::<Vec<_>>()
-            .into_iter()
+    /// Returns the removed device's id, or `None` if we didn't know about it
+    pub fn remove(&mut self, index: DeviceFileIndex) -> Option<DeviceId> {
+        let id = self.index_to_id.remove(&index)?;
+        self.id_to_index.remove(&id);
+        Some(id)
     }
 
-    pub fn video_devices(&mut self) -> impl Iterator<Item=(DeviceFileIndex, DeviceId)> + '_ {
-        self.update();
+    pub fn video_devices(&self) -> impl Iterator<Item=(DeviceFileIndex, DeviceId)> + '_ {
         self.index_to_id.iter()
             .map(|(&k, &v)| (k, v))
     }
@@ -89,19 +59,28 @@ pub struct DeviceFileIndex(usize);
 impl DeviceFileIndex {
     pub fn from_dir_entry(dir_entry: DirEntry) -> Option<DeviceFileIndex> {
         let s = dir_entry.file_name();
-        let s = s.to_str()?;
+        let index = DeviceFileIndex::from_file_name(s.to_str()?)?;
+
+        if index.is_video_device() {
+            Some(index)
+        } else {
+            None
+        }
+    }
 
+    /// Parses names like `video2`, without checking that the device works
+    pub fn from_file_name(s: &str) -> Option<DeviceFileIndex> {
         if !s.starts_with("video") { return None }
 
         let start = "video".len();
 
         let inner = s[start..].parse().ok()?;
 
-        if is_video_device(inner) {
-            Some(DeviceFileIndex(inner))
-        } else {
-            None
-        }
+        Some(DeviceFileIndex(inner))
+    }
+
+    pub fn is_video_device(self) -> bool {
+        is_video_device(self.0)
     }
 
     pub fn file_index(self) -> usize {
@@ -110,11 +89,12 @@ impl DeviceFileIndex {
 }
 
 /// For some reason, when I updated to Ubuntu 20, every video device creates two files in /sys/class/video4linux.
-/// One doesn't work. Let's make sure all the video devices we find work
+/// One doesn't work. Let's make sure all the video devices we find work, and capture something we
+/// can decode
 fn is_video_device(index: usize) -> bool {
     match v4l::capture::Device::new(index) {
         Ok(dev) => match dev.enum_formats() {
-            Ok(formats) => formats.iter().any(|f| &f.fourcc.repr == ACCEPTABLE_FORMAT),
+            Ok(formats) => formats.iter().any(|f| SUPPORTED_FOURCCS.contains(&&f.fourcc.repr)),
             Err(_) => false,
         },
         Err(_) => false,
@@ -1,22 +1,52 @@
 
 // cp etomicbomb@192.168.2.1:/home/etomicbomb/Desktop/orbit_helper/target/armv7-unknown-linux-gnueabihf/release/orbit_helper .
 
-use std::net::{TcpListener};
-use std::time::{Duration};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
 use crate::known_devices::KnownDevices;
+use crate::ring_buffer::ArmedDevices;
+use crate::hotplug::{DeviceChange, DeviceSubscribers};
+use crate::watchdog::Watchdog;
+use crate::negotiation::FormatRequest;
+use crate::bandwidth::BandwidthLedger;
+use crate::originals::OriginalCache;
+use crate::snap::{Capture, Cameras, Latencies};
+#[cfg(feature = "detection")]
+use crate::detection::Detectors;
+#[cfg(not(feature = "detection"))]
+use crate::no_detection::Detectors;
 use libc::c_int;
 use v4l::{Format, FourCC};
-use orbit_types::{Request};
+use orbit_types::{Request, StreamSettings, DeviceId};
+use orbit_metrics::Metrics;
 use v4l::format::{FieldOrder, Colorspace, Quantization, TransferFunction, Flags};
 use std::{thread, io, io::Write};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
 use chrono::Local;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
 
 mod stream;
 mod snap;
 mod known_devices;
 mod polling_stream_fork;
+mod ring_buffer;
+mod session;
+mod hotplug;
+mod watchdog;
+mod negotiation;
+mod bandwidth;
+mod preview;
+#[cfg(feature = "detection")]
+mod detection;
+#[cfg(not(feature = "detection"))]
+mod no_detection;
+mod recording;
+mod originals;
+mod health;
 
 // TODO:
 // replace
@@ -24,52 +54,287 @@ mod polling_stream_fork;
 // need same types in orbit_station, perhaps put everything in cargo workspace
 
 const POLL_TIMEOUT_MILLIS: c_int = 1000;
-// sleep between checking for new devices while streaming
-// remember
-const NEW_DEVICE_CHECK: Duration = Duration::from_secs(1);
-const SNAP_FORMAT: Format = new_format(1280, 720, ACCEPTABLE_FORMAT);
-const STREAM_FORMAT: Format = new_format(640, 360, ACCEPTABLE_FORMAT);
-const ACCEPTABLE_FORMAT: &[u8; 4] = b"MJPG";
+// cameras that were just plugged in aren't ready to be opened right away
+const HOTPLUG_SETTLE: Duration = Duration::from_millis(500);
+// how often the preview threads check whether they've been asked to stop
+const STOP_CHECK: Duration = Duration::from_millis(50);
+const SNAP_FORMAT: FormatRequest = FormatRequest { width: 1280, height: 720, fps: 30 };
+const STREAM_FORMAT: FormatRequest = FormatRequest { width: 640, height: 360, fps: 30 };
 const CRASH_RETRY_DELAY: Duration = Duration::from_secs(2);
+// armed cameras keep a few buffers queued so they don't miss frames while we copy one out
+const ARMED_BUFFER_COUNT: u32 = 4;
+// armed cameras remember this far back, so that a snap can be requested after the fact
+const RING_BUFFER_HISTORY_MILLIS: i64 = 3000;
+// shared between all of the armed cameras, leaving plenty of the NanoPi's RAM for everything else
+const RING_BUFFER_BUDGET_BYTES: usize = 64 * 1024 * 1024;
+// how often previews report the frames they dropped, if they dropped any
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// the preview of an armed camera is full resolution, so send it less often
+const ARMED_PREVIEW_INTERVAL: Duration = Duration::from_millis(200);
+// how long after the target time we wait for an armed camera to produce a frame
+const ARMED_FRAME_WAIT_MILLIS: i64 = 500;
+// a camera that hasn't delivered a frame for this long has stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(3);
+// how many times a stalled camera gets closed and reopened before we reset its USB port
+const MAX_REOPENS: u32 = 2;
+// a camera that has been delivering frames for this long since its last recovery is healthy again
+const RECOVERY_SETTLE: Duration = Duration::from_secs(10);
+// don't reset the same USB port more often than this, in case the reset doesn't help
+const PORT_RESET_COOLDOWN: Duration = Duration::from_secs(60);
+// how many frames can wait to be written to disk while recording, about a second of every camera
+const RECORDING_QUEUE_FRAMES: usize = 100;
+// the originals of thumbnail snaps wait in this much RAM for the station, a couple dozen snaps' worth
+const ORIGINALS_BUDGET_BYTES: usize = 32 * 1024 * 1024;
+// stills are searched for tags this many at a time, one per core of the NanoPi
+const DETECTOR_COUNT: usize = 4;
+// how often sessions get told how the helper is holding up
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+// set to serve Prometheus metrics at /metrics on that port
+const METRICS_PORT_VARIABLE: &str = "ORBIT_METRICS_PORT";
 
 
 fn main() {
     let mut log_file = get_log_file();
 
+    // outside of the loop, so the port stays ours and the counters keep counting across restarts
+    let metrics = Metrics::new();
+    if let Some(port) = std::env::var(METRICS_PORT_VARIABLE).ok().and_then(|port| port.parse().ok()) {
+        match metrics.serve(port) {
+            Ok(()) => println!("serving metrics on port {}", port),
+            Err(e) => println!("couldn't serve metrics on port {}: {:?}", port, e),
+        }
+    }
+
     loop {
         let _ = writeln!(log_file, "{}: started", Local::now());
         println!("started");
-        let error = run();
+        let error = run(metrics.clone());
         let _ = writeln!(log_file, "{}: restarting because of error {:?}", Local::now(), error);
         println!("restarting");
         thread::sleep(CRASH_RETRY_DELAY);
     }
 }
 
-fn run() -> io::Result<()> {
-    let mut known_devices = KnownDevices::new();
+/// Everything the connections share. Only one thread can have a camera open at a time, so arming
+/// and the previews open the cameras while holding the lock. Captures only hold it long enough to
+/// take what they need, see `Capture`
+pub struct Helper {
+    known_devices: KnownDevices,
+    armed: Option<ArmedDevices>,
+    /// The cameras were armed to record, rather than because the station asked
+    armed_for_recording: bool,
+    /// The cameras were armed so a session could capture without stopping its previews, rather
+    /// than because the station asked
+    armed_for_captures: bool,
+    /// How many sessions are open
+    session_count: usize,
+    /// Captures that open the cameras themselves take turns with this
+    closed_captures: Arc<Mutex<()>>,
+    latencies: Latencies,
+    device_subscribers: DeviceSubscribers,
+    watchdog: Watchdog,
+    bandwidth: BandwidthLedger,
+    originals: OriginalCache,
+    detectors: Detectors,
+    metrics: Metrics,
+}
+
+pub type SharedHelper = Arc<Mutex<Helper>>;
+
+impl Helper {
+    /// Takes what a capture needs in one go, so the cameras can't be armed or disarmed between
+    /// checking and taking them, and the capture itself doesn't hold up anyone else
+    fn capture(&self, settings: StreamSettings) -> Capture {
+        let cameras = match self.armed {
+            Some(ref armed) => Cameras::Armed(armed.rings().cloned().collect()),
+            None => Cameras::Closed {
+                devices: self.known_devices.video_devices().collect(),
+                bandwidth: self.bandwidth.clone(),
+                turn: Arc::clone(&self.closed_captures),
+            },
+        };
+
+        Capture::new(cameras, settings.buffer_count, self.latencies.clone(), self.metrics.clone())
+    }
+
+    fn set_latencies(&mut self, latencies: Vec<(DeviceId, i64)>) {
+        self.latencies = latencies.into_iter()
+            .map(|(device_id, micros)| (device_id, chrono::Duration::microseconds(micros)))
+            .collect();
+    }
+
+    fn arm(&mut self) {
+        self.armed_for_recording = false;
+        self.armed_for_captures = false;
+        if self.armed.is_none() {
+            // wait for the captures that opened the cameras themselves to close them again
+            let turn = Arc::clone(&self.closed_captures);
+            let _turn = turn.lock().unwrap();
+            self.armed = Some(ArmedDevices::arm(&self.known_devices, &self.watchdog, &self.bandwidth));
+        }
+    }
+
+    /// Arms the cameras for a session that wants to capture, unless they already are
+    fn arm_for_captures(&mut self) {
+        if self.armed.is_none() {
+            self.arm();
+            self.armed_for_captures = true;
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.armed_for_recording = false;
+        self.armed_for_captures = false;
+        if let Some(armed) = self.armed.take() {
+            armed.disarm();
+        }
+    }
+
+    fn start_recording(&mut self) {
+        if self.armed.is_none() {
+            self.arm();
+            self.armed_for_recording = true;
+        }
+
+        if let Some(ref armed) = self.armed {
+            armed.start_recording();
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(ref armed) = self.armed {
+            armed.stop_recording();
+        }
+
+        if self.armed_for_recording {
+            self.disarm();
+        }
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.is_some()
+    }
+
+    /// Finds out about cameras being plugged in, unplugged, and recovered from now on
+    fn subscribe(&self) -> Receiver<DeviceChange> {
+        self.device_subscribers.subscribe()
+    }
+}
+
+fn run(metrics: Metrics) -> io::Result<()> {
+    let device_subscribers = DeviceSubscribers::new();
+
+    let helper = Arc::new(Mutex::new(Helper {
+        known_devices: KnownDevices::new(),
+        armed: None,
+        armed_for_recording: false,
+        armed_for_captures: false,
+        session_count: 0,
+        closed_captures: Arc::new(Mutex::new(())),
+        latencies: Latencies::new(),
+        watchdog: Watchdog::new(device_subscribers.clone(), metrics.clone()),
+        device_subscribers,
+        bandwidth: BandwidthLedger::new(),
+        originals: OriginalCache::new(),
+        detectors: Detectors::start(DETECTOR_COUNT),
+        metrics,
+    }));
+
+    hotplug::spawn_watcher(&helper);
 
     let listener = TcpListener::bind("0.0.0.0:2000")?;
     for connection in listener.incoming() {
-        if let Ok(mut connection) = connection {
+        if let Ok(connection) = connection {
             println!("handling new connection");
-            match bincode::deserialize_from(&mut connection) {
-                Ok(Request::Stream) => stream::stream(connection, &mut known_devices),
-                Ok(Request::Snap(target_time)) => {
-                    snap::snap(target_time, &mut known_devices, connection);
-                },
-                Err(_) => {},
-            }
+            let helper = Arc::clone(&helper);
+            thread::spawn(move || handle_connection(connection, helper));
         }
     }
 
     Ok(())
 }
 
-fn get_log_file() -> File {
+/// A connection either starts a session, or sends a single request and gets a single response
+fn handle_connection(mut connection: TcpStream, helper: SharedHelper) {
+    match bincode::deserialize_from(&mut connection) {
+        Ok(Request::Stream(settings)) => session::session(connection, helper, settings),
+        Ok(Request::Snap(target_time)) => {
+            let capture = helper.lock().unwrap().capture(StreamSettings::default());
+            let _ = bincode::serialize_into(&mut connection, &capture.snap(target_time));
+        },
+        Ok(Request::Burst { start, end, max_frames }) => {
+            let capture = helper.lock().unwrap().capture(StreamSettings::default());
+            let _ = bincode::serialize_into(&mut connection, &capture.burst(start, end, max_frames));
+        },
+        Ok(Request::Detect { target_time, search }) => {
+            let (capture, detectors) = {
+                let helper = helper.lock().unwrap();
+                (helper.capture(StreamSettings::default()), helper.detectors.clone())
+            };
+            let _ = bincode::serialize_into(&mut connection, &detectors.detect(capture.snap(target_time).stills, search));
+        },
+        Ok(Request::Arm) => helper.lock().unwrap().arm(),
+        Ok(Request::Disarm) => helper.lock().unwrap().disarm(),
+        Ok(Request::StartRecording) => helper.lock().unwrap().start_recording(),
+        Ok(Request::StopRecording) => helper.lock().unwrap().stop_recording(),
+        Ok(Request::FetchRecording { start, end, max_frames }) => {
+            let _ = bincode::serialize_into(&mut connection, &recording::fetch(start, end, max_frames));
+        },
+        Ok(Request::SnapThumbnails { target_time, capture_id, thumbnail }) => {
+            let (capture, originals) = {
+                let helper = helper.lock().unwrap();
+                (helper.capture(StreamSettings::default()), helper.originals.clone())
+            };
+            let _ = bincode::serialize_into(&mut connection, &capture.snap_thumbnails(target_time, capture_id, thumbnail, &originals));
+        },
+        Ok(Request::FetchOriginal { capture_id, device_id, offset, max_len }) => {
+            let originals = helper.lock().unwrap().originals.clone();
+            let _ = bincode::serialize_into(&mut connection, &originals.chunk(capture_id, device_id, offset, max_len));
+        },
+        Ok(Request::Status) => {
+            let watchdog = helper.lock().unwrap().watchdog.clone();
+            let _ = bincode::serialize_into(&mut connection, &health::report(&watchdog));
+        },
+        Ok(Request::Log) => {
+            let log = fs::read_to_string(log_path()).unwrap_or_default();
+            let _ = bincode::serialize_into(&mut connection, &log);
+        },
+        Ok(Request::Restart) => restart(&helper),
+        Ok(Request::Devices) => {
+            let (devices, bandwidth) = {
+                let helper = helper.lock().unwrap();
+                (helper.known_devices.video_devices().collect::<Vec<_>>(), helper.bandwidth.clone())
+            };
+            let _ = bincode::serialize_into(&mut connection, &stream::list_devices(&devices, &bandwidth));
+        },
+        // there are no previews outside of a session
+        Ok(Request::Preview(..)) => {},
+        Ok(Request::Latencies(latencies)) => helper.lock().unwrap().set_latencies(latencies),
+        Err(_) => {},
+    }
+}
+
+/// Replaces the process with a fresh copy of itself. Disarming first makes sure a recording in
+/// progress is written out, and everything else is closed when the process is replaced
+fn restart(helper: &SharedHelper) {
+    helper.lock().unwrap().disarm();
+
+    let _ = writeln!(get_log_file(), "{}: restarting because it was asked to", Local::now());
+    println!("restarting on request");
+
+    let error = std::env::current_exe()
+        .map(|exe| Command::new(exe).args(std::env::args_os().skip(1)).exec());
+    println!("couldn't restart: {:?}", error);
+}
+
+fn log_path() -> PathBuf {
     let path = std::env::var_os("HOME").unwrap();
+    PathBuf::from(path).join(".orbit_log")
+}
+
+fn get_log_file() -> File {
+    let path = log_path();
     println!("opening log file at {:?}", path);
-    let path = PathBuf::from(path).join(".orbit_log");
 
     OpenOptions::new()
         .create(true)
@@ -0,0 +1,369 @@
+use std::io;
+
+use v4l::{Format, FourCC};
+use v4l::capture::Parameters;
+use v4l::device::QueryDevice;
+use v4l::fraction::Fraction;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::prelude::CaptureDevice;
+use orbit_types::CameraFormat;
+
+use crate::new_format;
+use crate::bandwidth::{Allowance, BandwidthLedger, Reservation};
+use crate::known_devices::DeviceFileIndex;
+
+/// The encodings the station can decode, the ones we'd rather have first. MJPG frames are much
+/// smaller, so cameras can usually send them at higher resolutions and frame rates
+pub const SUPPORTED_FOURCCS: [&[u8; 4]; 2] = [b"MJPG", b"YUYV"];
+
+/// What we'd like a camera to capture. Cameras often can't do exactly this, so we pick the closest
+/// thing they can do
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FormatRequest {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+/// Picks the closest thing to `request` that the camera says it can do and that fits in what's left
+/// of its USB bus, and sets the camera to it. Returns the format the camera actually ended up with,
+/// and the bandwidth reserved for it, which has to be kept until the camera is closed
+pub fn negotiate(
+    device: &mut CaptureDevice,
+    device_index: DeviceFileIndex,
+    request: FormatRequest,
+    bandwidth: &BandwidthLedger,
+) -> io::Result<(Format, CameraFormat, Reservation)> {
+    let chosen = choose(device, request, bandwidth.allowance(device_index))?;
+    let (used_format, actual) = apply(device, chosen)?;
+    let reservation = bandwidth.reserve(device_index, actual)?;
+
+    Ok((used_format, actual, reservation))
+}
+
+/// Picks the closest thing to `request` from the camera's enumerated capabilities, without
+/// changing anything on the camera. Formats that don't fit in `allowance` are left out. Getting
+/// close to the requested size matters most, then the frame rate, then the encoding
+pub fn choose(device: &CaptureDevice, request: FormatRequest, allowance: Allowance) -> io::Result<CameraFormat> {
+    let mut candidates = Vec::new();
+
+    for description in device.enum_formats()? {
+        let fourcc_rank = match SUPPORTED_FOURCCS.iter().position(|&fourcc| *fourcc == description.fourcc.repr) {
+            Some(rank) => rank,
+            None => continue,
+        };
+
+        let sizes = match device.enum_framesizes(description.fourcc) {
+            Ok(sizes) => sizes,
+            Err(_) => continue,
+        };
+
+        for size in sizes {
+            let (width, height) = frame_size(&size.size, request);
+
+            let candidate = CameraFormat {
+                width,
+                height,
+                fourcc: description.fourcc.repr,
+                fps: closest_fps(device, description.fourcc, width, height, request.fps),
+            };
+
+            candidates.push((candidate, fourcc_rank));
+        }
+    }
+
+    best_fit(request, allowance, candidates)
+}
+
+/// The candidate closest to `request` that fits in `allowance`. Each comes with the rank of its
+/// encoding in `SUPPORTED_FOURCCS`
+fn best_fit(request: FormatRequest, allowance: Allowance, candidates: Vec<(CameraFormat, usize)>) -> io::Result<CameraFormat> {
+    let mut best: Option<(Score, CameraFormat)> = None;
+    // the format we would have picked, for the error message if nothing fits
+    let mut best_too_big: Option<(Score, CameraFormat)> = None;
+
+    for (candidate, fourcc_rank) in candidates {
+        let score = Score::new(request, candidate, fourcc_rank);
+        let best = if allowance.fits(candidate) { &mut best } else { &mut best_too_big };
+        let is_best = match best {
+            Some((best_score, _)) => score < *best_score,
+            None => true,
+        };
+        if is_best {
+            *best = Some((score, candidate));
+        }
+    }
+
+    match (best, best_too_big) {
+        (Some((_, format)), _) => Ok(format),
+        (None, Some((_, format))) => Err(allowance.exceeded_by(format)),
+        (None, None) => Err(io::Error::new(io::ErrorKind::NotFound, "the camera can't capture anything we can decode")),
+    }
+}
+
+/// Sets the camera to `format`, and returns what it actually ended up with, since drivers are free
+/// to pick something else
+fn apply(device: &mut CaptureDevice, format: CameraFormat) -> io::Result<(Format, CameraFormat)> {
+    let used_format = device.set_format(&new_format(format.width, format.height, &format.fourcc))?;
+
+    // not every camera lets us choose the frame rate
+    let fps = match device.set_params(&Parameters::with_fps(format.fps)) {
+        Ok(params) => fps_from_interval(params.interval).unwrap_or(format.fps),
+        Err(_) => format.fps,
+    };
+
+    let actual = CameraFormat {
+        width: used_format.width,
+        height: used_format.height,
+        fourcc: used_format.fourcc.repr,
+        fps,
+    };
+
+    if actual != format {
+        println!("asked for {:?} but the camera chose {:?}", format, actual);
+    }
+
+    Ok((used_format, actual))
+}
+
+/// Lower is better, compared field by field
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Score {
+    /// Scaling a smaller image up loses detail, so anything at least as big as requested is better
+    too_small: bool,
+    size_difference: u64,
+    fps_shortfall: u32,
+    fourcc_rank: usize,
+}
+
+impl Score {
+    fn new(request: FormatRequest, candidate: CameraFormat, fourcc_rank: usize) -> Score {
+        let requested_area = request.width as i64 * request.height as i64;
+        let area = candidate.width as i64 * candidate.height as i64;
+
+        Score {
+            too_small: candidate.width < request.width || candidate.height < request.height,
+            size_difference: (area - requested_area).unsigned_abs(),
+            fps_shortfall: request.fps.saturating_sub(candidate.fps),
+            fourcc_rank,
+        }
+    }
+}
+
+/// The size a camera would capture closest to `request`, out of the sizes it described with `size`
+fn frame_size(size: &FrameSizeEnum, request: FormatRequest) -> (u32, u32) {
+    match size {
+        FrameSizeEnum::Discrete(discrete) => (discrete.width, discrete.height),
+        FrameSizeEnum::Stepwise(stepwise) => (
+            nearest_step(request.width, stepwise.min_width, stepwise.max_width, stepwise.step_width),
+            nearest_step(request.height, stepwise.min_height, stepwise.max_height, stepwise.step_height),
+        ),
+    }
+}
+
+/// The slowest frame rate that's at least `fps`, or the fastest one if they're all slower. If the
+/// camera won't say, we ask for `fps` and hope for the best
+fn closest_fps(device: &CaptureDevice, fourcc: FourCC, width: u32, height: u32, fps: u32) -> u32 {
+    match device.enum_frameintervals(fourcc, width, height) {
+        Ok(intervals) => closest_rate(intervals.into_iter().map(|interval| interval.interval), fps),
+        Err(_) => fps,
+    }
+}
+
+/// `closest_fps`, out of the frame intervals the camera described
+fn closest_rate(intervals: impl IntoIterator<Item = FrameIntervalEnum>, fps: u32) -> u32 {
+    let mut rates: Vec<u32> = Vec::new();
+    for interval in intervals {
+        match interval {
+            FrameIntervalEnum::Discrete(interval) => rates.extend(fps_from_interval(interval)),
+            FrameIntervalEnum::Stepwise(stepwise) => {
+                // the shortest interval is the fastest frame rate
+                let fastest = fps_from_interval(stepwise.min).unwrap_or(fps);
+                let slowest = fps_from_interval(stepwise.max).unwrap_or(fps);
+                rates.push(fps.max(slowest).min(fastest));
+            },
+        }
+    }
+
+    let fast_enough = rates.iter().copied().filter(|&rate| rate >= fps).min();
+    let fastest = rates.iter().copied().max();
+
+    fast_enough.or(fastest).unwrap_or(fps)
+}
+
+fn fps_from_interval(interval: Fraction) -> Option<u32> {
+    if interval.numerator == 0 { return None }
+
+    Some((interval.denominator as f64 / interval.numerator as f64).round() as u32)
+}
+
+fn nearest_step(value: u32, min: u32, max: u32, step: u32) -> u32 {
+    let value = value.max(min).min(max);
+    if step == 0 { return value }
+
+    let stepped = min + (value - min + step / 2) / step * step;
+    if stepped > max { stepped - step } else { stepped }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use v4l::framesize::{Discrete, Stepwise};
+    use v4l::frameinterval::Stepwise as StepwiseInterval;
+    use crate::bandwidth;
+
+    const REQUEST: FormatRequest = FormatRequest { width: 1280, height: 720, fps: 30 };
+
+    fn format(width: u32, height: u32, fps: u32) -> CameraFormat {
+        CameraFormat { width, height, fourcc: *b"MJPG", fps }
+    }
+
+    fn score(width: u32, height: u32, fps: u32, fourcc_rank: usize) -> Score {
+        Score::new(REQUEST, format(width, height, fps), fourcc_rank)
+    }
+
+    /// A frame interval of one over `fps` seconds
+    fn interval(fps: u32) -> Fraction {
+        Fraction::new(1, fps)
+    }
+
+    #[test]
+    fn steps_round_to_the_nearest() {
+        assert_eq!(nearest_step(1280, 160, 1920, 16), 1280);
+        assert_eq!(nearest_step(1287, 160, 1920, 16), 1280);
+        assert_eq!(nearest_step(1289, 160, 1920, 16), 1296);
+        // the steps count from the minimum, not from zero
+        assert_eq!(nearest_step(100, 10, 200, 30), 100);
+        assert_eq!(nearest_step(114, 10, 200, 30), 100);
+        assert_eq!(nearest_step(116, 10, 200, 30), 130);
+    }
+
+    #[test]
+    fn steps_stay_in_range() {
+        assert_eq!(nearest_step(50, 160, 1920, 16), 160);
+        assert_eq!(nearest_step(4000, 160, 1920, 16), 1920);
+        // rounding up would go past the maximum, which isn't itself a step
+        assert_eq!(nearest_step(10, 0, 10, 4), 8);
+    }
+
+    #[test]
+    fn continuous_sizes_have_no_step() {
+        assert_eq!(nearest_step(1283, 160, 1920, 0), 1283);
+        assert_eq!(nearest_step(2000, 160, 1920, 0), 1920);
+    }
+
+    #[test]
+    fn discrete_sizes_are_taken_as_they_are() {
+        let size = FrameSizeEnum::Discrete(Discrete { width: 640, height: 480 });
+        assert_eq!(frame_size(&size, REQUEST), (640, 480));
+    }
+
+    #[test]
+    fn stepwise_sizes_come_as_close_as_they_can() {
+        let size = FrameSizeEnum::Stepwise(Stepwise {
+            min_width: 160,
+            max_width: 1024,
+            step_width: 8,
+            min_height: 120,
+            max_height: 768,
+            step_height: 2,
+        });
+        assert_eq!(frame_size(&size, REQUEST), (1024, 720));
+    }
+
+    #[test]
+    fn bigger_is_better_than_smaller() {
+        assert!(score(1920, 1080, 30, 0) < score(1024, 768, 30, 0));
+        // even when the smaller one is closer in area
+        assert!(score(1280, 960, 30, 0) < score(1200, 720, 30, 0));
+    }
+
+    #[test]
+    fn size_matters_more_than_frame_rate_and_encoding() {
+        assert!(score(1280, 720, 5, 1) < score(1920, 1080, 30, 0));
+        assert!(score(1280, 720, 15, 0) < score(1280, 720, 10, 0));
+        assert_eq!(score(1280, 720, 30, 0).fps_shortfall, score(1280, 720, 60, 0).fps_shortfall);
+    }
+
+    #[test]
+    fn encoding_breaks_ties() {
+        assert!(score(1280, 720, 30, 0) < score(1280, 720, 30, 1));
+        assert!(score(1280, 720, 30, 0) == score(1280, 720, 60, 0));
+    }
+
+    #[test]
+    fn the_slowest_rate_that_is_fast_enough() {
+        let intervals = vec![60, 15, 30, 25].into_iter().map(|fps| FrameIntervalEnum::Discrete(interval(fps)));
+        assert_eq!(closest_rate(intervals, 24), 25);
+    }
+
+    #[test]
+    fn the_fastest_rate_if_none_are_fast_enough() {
+        let intervals = vec![5, 15, 10].into_iter().map(|fps| FrameIntervalEnum::Discrete(interval(fps)));
+        assert_eq!(closest_rate(intervals, 30), 15);
+    }
+
+    #[test]
+    fn stepwise_rates_are_clamped_to_their_range() {
+        let stepwise = |slowest, fastest| FrameIntervalEnum::Stepwise(StepwiseInterval {
+            min: interval(fastest),
+            max: interval(slowest),
+            step: Fraction::new(1, 1),
+        });
+
+        assert_eq!(closest_rate(vec![stepwise(5, 60)], 30), 30);
+        assert_eq!(closest_rate(vec![stepwise(5, 20)], 30), 20);
+        assert_eq!(closest_rate(vec![stepwise(40, 60)], 30), 40);
+    }
+
+    #[test]
+    fn no_rates_asks_for_the_requested_one() {
+        assert_eq!(closest_rate(Vec::new(), 30), 30);
+        // an interval of zero seconds says nothing
+        assert_eq!(closest_rate(vec![FrameIntervalEnum::Discrete(Fraction::new(0, 30))], 30), 30);
+    }
+
+    #[test]
+    fn the_closest_format_wins_when_everything_fits() {
+        let candidates = vec![(format(1920, 1080, 30), 0), (format(1280, 720, 30), 0), (format(640, 480, 30), 0)];
+
+        assert_eq!(best_fit(REQUEST, Allowance { bytes_per_sec: None }, candidates).unwrap(), format(1280, 720, 30));
+    }
+
+    #[test]
+    fn formats_that_dont_fit_fall_back_to_the_nearest_that_does() {
+        // 1280x720 MJPG needs 13.8MB/s at 30fps and 6.9MB/s at 15fps
+        let allowance = Allowance { bytes_per_sec: Some(10_000_000) };
+        let yuyv = CameraFormat { fourcc: *b"YUYV", ..format(1280, 720, 30) };
+        let candidates = vec![(format(1280, 720, 30), 0), (yuyv, 1), (format(1280, 720, 15), 0), (format(640, 480, 30), 0)];
+
+        assert_eq!(best_fit(REQUEST, allowance, candidates).unwrap(), format(1280, 720, 15));
+    }
+
+    #[test]
+    fn nothing_fitting_names_what_would_have_been_picked() {
+        let allowance = Allowance { bytes_per_sec: Some(1_000_000) };
+        let candidates = vec![(format(1920, 1080, 30), 0), (format(1280, 720, 30), 0)];
+
+        let error = best_fit(REQUEST, allowance, candidates).unwrap_err();
+
+        assert!(bandwidth::is_exceeded(&error));
+        assert!(error.to_string().starts_with("1280x720 at 30fps"), "{}", error);
+    }
+
+    #[test]
+    fn no_candidates() {
+        let error = best_fit(REQUEST, Allowance { bytes_per_sec: None }, Vec::new()).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rates_are_rounded() {
+        // NTSC cameras say 1001/30000 seconds
+        assert_eq!(fps_from_interval(Fraction::new(1001, 30000)), Some(30));
+        assert_eq!(fps_from_interval(Fraction::new(0, 1)), None);
+    }
+}
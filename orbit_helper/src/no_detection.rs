@@ -0,0 +1,27 @@
+use orbit_types::{CapturedFrame, CameraDetections, DetectResponse, TagSearch};
+
+/// Stands in for `detection::Detectors` when the helper is built without the `detection`
+/// feature, so the apriltag C library doesn't have to be cross compiled. Every still comes back
+/// with an error instead of detections
+#[derive(Clone)]
+pub struct Detectors;
+
+impl Detectors {
+    pub fn start(_count: usize) -> Detectors {
+        Detectors
+    }
+
+    pub fn detect(&self, stills: Vec<CapturedFrame>, _search: TagSearch) -> DetectResponse {
+        let cameras = stills.into_iter()
+            .map(|still| CameraDetections {
+                device_id: still.device_id(),
+                captured_at: *still.captured_at(),
+                detections: Vec::new(),
+                still: None,
+                error: Some("the helper was built without the detection feature".to_string()),
+            })
+            .collect();
+
+        DetectResponse { cameras }
+    }
+}
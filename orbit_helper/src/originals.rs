@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use orbit_types::{CaptureId, CapturedFrame, DeviceId, OriginalChunk};
+
+use crate::ORIGINALS_BUDGET_BYTES;
+
+/// The full resolution stills of the latest thumbnail snaps, already serialized so they can be
+/// sent a chunk at a time. The oldest captures are forgotten once they don't fit in
+/// `ORIGINALS_BUDGET_BYTES`. Cloning it shares the same stills
+#[derive(Clone)]
+pub struct OriginalCache {
+    captures: Arc<Mutex<Captures>>,
+}
+
+/// The serialized stills of one capture, by camera
+type Originals = Vec<(DeviceId, Arc<Vec<u8>>)>;
+
+struct Captures {
+    captures: VecDeque<(CaptureId, Originals)>,
+    total_bytes: usize,
+}
+
+impl OriginalCache {
+    pub fn new() -> OriginalCache {
+        OriginalCache {
+            captures: Arc::new(Mutex::new(Captures { captures: VecDeque::new(), total_bytes: 0 })),
+        }
+    }
+
+    pub fn keep(&self, capture_id: CaptureId, stills: &[CapturedFrame]) {
+        let originals: Originals = stills.iter()
+            .filter_map(|still| match bincode::serialize(still) {
+                Ok(bytes) => Some((still.device_id(), Arc::new(bytes))),
+                Err(e) => {
+                    println!("couldn't keep the original still from {:?}: {:?}", still.device_id(), e);
+                    None
+                },
+            })
+            .collect();
+
+        let mut captures = self.captures.lock().unwrap();
+        captures.total_bytes += originals.iter().map(|(_, bytes)| bytes.len()).sum::<usize>();
+        captures.captures.push_back((capture_id, originals));
+
+        // always keep the newest capture, even if it's over budget on its own
+        while captures.captures.len() > 1 && captures.total_bytes > ORIGINALS_BUDGET_BYTES {
+            if let Some((forgotten, originals)) = captures.captures.pop_front() {
+                captures.total_bytes -= originals.iter().map(|(_, bytes)| bytes.len()).sum::<usize>();
+                println!("forgot the originals of {:?} to make room", forgotten);
+            }
+        }
+    }
+
+    pub fn chunk(&self, capture_id: CaptureId, device_id: DeviceId, offset: u64, max_len: u32) -> OriginalChunk {
+        let original = self.captures.lock().unwrap().captures.iter()
+            .filter(|(id, _)| *id == capture_id)
+            .flat_map(|(_, originals)| originals.iter())
+            .find(|(id, _)| *id == device_id)
+            .map(|(_, bytes)| Arc::clone(bytes));
+
+        let (total_len, data) = match original {
+            Some(bytes) => {
+                let start = (offset as usize).min(bytes.len());
+                let end = start.saturating_add(max_len as usize).min(bytes.len());
+                (Some(bytes.len() as u64), bytes[start..end].to_vec())
+            },
+            None => (None, Vec::new()),
+        };
+
+        OriginalChunk { capture_id, device_id, offset, total_len, data }
+    }
+}
@@ -2,7 +2,7 @@ use std::sync::Arc;
 use v4l::{device, v4l2, Buffer, Memory};
 use std::{io, mem};
 use v4l::buffer::{StreamItem, Metadata};
-use std::os::raw::c_short;
+use std::os::raw::{c_int, c_short};
 use crate::POLL_TIMEOUT_MILLIS;
 use crate::polling_stream_fork::public_arena::{Arena};
 use v4l::v4l_sys::*;
@@ -197,6 +197,22 @@ impl<'a> ActiveStream<'a> {
         self.dequeue()
     }
 
+    /// Like `next`, but skips over every frame that was already waiting behind the first one, so
+    /// we always get the newest frame. Also returns how many frames were skipped
+    pub fn latest(&mut self) -> io::Result<(StreamItem<'a, Buffer<'a>>, u32)> {
+        let mut frame = self.next()?;
+        let mut skipped = 0;
+
+        // giving the skipped buffer back to the driver means it can be overwritten, but we're done
+        // with it anyway
+        while self.poll(0) {
+            frame = self.next()?;
+            skipped += 1;
+        }
+
+        Ok((frame, skipped))
+    }
+
     pub fn queue(&mut self) -> io::Result<()> {
         if self.queued {
             return Ok(());
@@ -220,17 +236,22 @@ impl<'a> ActiveStream<'a> {
         Ok(())
     }
 
-    pub fn dequeue(&mut self) -> io::Result<StreamItem<'a, Buffer<'a>>> {
-        unsafe {
+    /// Whether a frame is ready to be dequeued, waiting up to `timeout_millis` for one
+    fn poll(&self, timeout_millis: c_int) -> bool {
+        let devices_set = unsafe {
             let mut poll_fd = libc::pollfd {
                 fd: self.inner.handle.fd(),
                 events: v4l2::vidioc::VIDIOC_DQBUF as c_short,
                 revents: 0,
             };
-            let devices_set = libc::poll(&mut poll_fd, 1, POLL_TIMEOUT_MILLIS);
+            libc::poll(&mut poll_fd, 1, timeout_millis)
+        };
 
-            if devices_set != 1 { return Err(io::ErrorKind::TimedOut.into()) }
-        }
+        devices_set == 1
+    }
+
+    pub fn dequeue(&mut self) -> io::Result<StreamItem<'a, Buffer<'a>>> {
+        if !self.poll(POLL_TIMEOUT_MILLIS) { return Err(io::ErrorKind::TimedOut.into()) }
 
         let mut v4l2_buf: v4l2_buffer;
         unsafe {
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use image::{DynamicImage, ImageError, ImageResult, RgbImage};
+use image::error::{ParameterError, ParameterErrorKind};
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use image::imageops::FilterType;
+use orbit_types::{CapturedFrame, DeviceId, PreviewSettings};
+
+/// Trims a camera's frames down to what the station asked for: fewer of them, and smaller
+pub struct PreviewShaper {
+    settings: PreviewSettings,
+    last_sent: Option<DateTime<Utc>>,
+}
+
+impl PreviewShaper {
+    pub fn new(settings: PreviewSettings) -> PreviewShaper {
+        PreviewShaper { settings, last_sent: None }
+    }
+
+    pub fn settings(&self) -> PreviewSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: PreviewSettings) {
+        self.settings = settings;
+    }
+
+    /// The frame as it should be sent, or `None` if it should be skipped to keep to the frame rate
+    pub fn shape(&mut self, mut frame: CapturedFrame) -> Option<CapturedFrame> {
+        if !self.is_due(*frame.captured_at()) { return None }
+        self.last_sent = Some(*frame.captured_at());
+
+        if let Err(e) = shrink(&mut frame, self.settings) {
+            println!("couldn't scale down a preview of {:?}, sending it as is: {}", frame.device_id(), e);
+        }
+
+        Some(frame)
+    }
+
+    fn is_due(&self, captured_at: DateTime<Utc>) -> bool {
+        let last_sent = match self.last_sent {
+            Some(last_sent) => last_sent,
+            None => return true,
+        };
+
+        // frames never arrive exactly on time, so anything three quarters of the way to the next
+        // one is close enough. Otherwise 15fps from a 30fps camera would come out as 10fps
+        let interval_micros = 1_000_000 / self.settings.fps.max(1) as i64;
+        match (captured_at - last_sent).num_microseconds() {
+            Some(elapsed) => elapsed >= interval_micros * 3 / 4,
+            // too long ago to count in microseconds
+            None => true,
+        }
+    }
+}
+
+/// A still scaled down to fit in `settings`, for the station to look at before the original
+/// arrives. Only the size and quality of the settings matter
+pub fn thumbnail(mut still: CapturedFrame, settings: PreviewSettings) -> CapturedFrame {
+    if let Err(e) = shrink(&mut still, settings) {
+        println!("couldn't make a thumbnail of the still from {:?}, sending it as is: {}", still.device_id(), e);
+    }
+    still
+}
+
+/// Scales the frame down to fit in the preview size. Most of the work for MJPG frames happens
+/// while decoding, by only decoding a fraction of each JPEG block, so it's cheap enough to do for
+/// every frame. Scaled down YUYV frames are sent as JPEGs too
+fn shrink(frame: &mut CapturedFrame, settings: PreviewSettings) -> ImageResult<()> {
+    if frame.width() <= settings.width && frame.height() <= settings.height { return Ok(()) }
+
+    let image = if frame.is_yuyv() {
+        orbit_types::yuyv_to_rgb(frame.frame_data(), frame.width(), frame.height())
+            .and_then(|rgb| RgbImage::from_raw(frame.width(), frame.height(), rgb))
+            .map(DynamicImage::ImageRgb8)
+            .ok_or_else(|| ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)))?
+    } else if frame.encoding_repr() == *b"MJPG" {
+        let mut decoder = JpegDecoder::new(Cursor::new(frame.frame_data()))?;
+        // at least as big as requested, so it only gets smaller from here
+        decoder.scale(clamp_u16(settings.width), clamp_u16(settings.height))?;
+        DynamicImage::from_decoder(decoder)?
+    } else {
+        return Ok(());
+    };
+
+    let image = image.resize(settings.width, settings.height, FilterType::Triangle).into_rgb8();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, settings.quality.clamp(1, 100)).encode_image(&image)?;
+
+    frame.replace_with_jpeg(image.width(), image.height(), jpeg);
+
+    Ok(())
+}
+
+fn clamp_u16(value: u32) -> u16 {
+    value.min(u16::MAX as u32) as u16
+}
+
+/// The preview settings of every camera in a session. They start out as the session's, and the
+/// station can change them one camera at a time. Cloning it shares the same settings
+#[derive(Clone)]
+pub struct PreviewTargets {
+    default: PreviewSettings,
+    per_camera: Arc<Mutex<HashMap<DeviceId, PreviewSettings>>>,
+}
+
+impl PreviewTargets {
+    pub fn new(default: PreviewSettings) -> PreviewTargets {
+        PreviewTargets { default, per_camera: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn get(&self, device_id: DeviceId) -> PreviewSettings {
+        self.per_camera.lock().unwrap().get(&device_id).copied().unwrap_or(self.default)
+    }
+
+    /// `None` puts the camera back to the session's settings
+    pub fn set(&self, device_id: DeviceId, settings: Option<PreviewSettings>) {
+        let mut per_camera = self.per_camera.lock().unwrap();
+        match settings {
+            Some(settings) => per_camera.insert(device_id, settings),
+            None => per_camera.remove(&device_id),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use orbit_types::DeviceIdGenerator;
+
+    fn settings(width: u32, height: u32) -> PreviewSettings {
+        PreviewSettings { width, height, ..PreviewSettings::default() }
+    }
+
+    fn yuyv(width: u32, height: u32) -> CapturedFrame {
+        let device_id = DeviceIdGenerator::new().next();
+        let data = vec![128; (width * height * 2) as usize];
+        CapturedFrame::new(device_id, width, height, orbit_types::YUYV, Utc.timestamp_millis(0), 0, data)
+    }
+
+    #[test]
+    fn yuyv_thumbnails_are_small_jpegs() {
+        let still = yuyv(128, 72);
+        let original_len = still.frame_data().len();
+
+        let thumbnail = thumbnail(still, settings(32, 32));
+
+        assert_eq!(thumbnail.encoding_repr(), *b"MJPG");
+        assert_eq!((thumbnail.width(), thumbnail.height()), (32, 18));
+        assert!(thumbnail.frame_data().len() < original_len);
+        assert!(image::load_from_memory(thumbnail.frame_data()).is_ok());
+    }
+
+    #[test]
+    fn jpeg_thumbnails_stay_jpegs() {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg).encode_image(&RgbImage::new(128, 72)).unwrap();
+        let mut still = yuyv(128, 72);
+        still.replace_with_jpeg(128, 72, jpeg);
+
+        let thumbnail = thumbnail(still, settings(64, 64));
+
+        assert_eq!(thumbnail.encoding_repr(), *b"MJPG");
+        assert_eq!((thumbnail.width(), thumbnail.height()), (64, 36));
+    }
+
+    #[test]
+    fn small_frames_are_left_alone() {
+        let thumbnail = thumbnail(yuyv(16, 8), settings(32, 32));
+
+        assert!(thumbnail.is_yuyv());
+        assert_eq!(thumbnail.frame_data().len(), 16 * 8 * 2);
+    }
+}
@@ -0,0 +1,283 @@
+use std::ffi::OsStr;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use chrono::{DateTime, TimeZone, Utc};
+use image::ImageDecoder;
+use image::codecs::jpeg::JpegDecoder;
+use orbit_types::{frame_jpeg, BurstResponse, CapturedFrame, DeviceId};
+
+use crate::RECORDING_QUEUE_FRAMES;
+use crate::snap::evenly_spaced;
+
+/// Writes the frames of the armed cameras to disk, each one a JPEG named after the microsecond it
+/// was captured and its sequence number, like `1600000000000000_42.jpg`, in
+/// `~/orbit_recordings/<when the recording started>/<camera>/`. The SD card is slower than the
+/// cameras, so frames wait in a queue, and are dropped when it's full
+pub struct Recording {
+    sender: SyncSender<CapturedFrame>,
+    writer: JoinHandle<()>,
+    dropped: AtomicU32,
+}
+
+impl Recording {
+    pub fn start() -> io::Result<Recording> {
+        let recordings_dir = recordings_dir()?;
+        fs::create_dir_all(&recordings_dir)?;
+        // to the microsecond, and never an existing one, so two recordings don't end up together
+        let dir = recordings_dir.join(Utc::now().format("%Y-%m-%dT%H-%M-%S%.6f").to_string());
+        fs::create_dir(&dir)?;
+        println!("recording to {:?}", dir);
+
+        let (sender, receiver) = mpsc::sync_channel::<CapturedFrame>(RECORDING_QUEUE_FRAMES);
+        let writer = thread::spawn(move || {
+            for frame in receiver {
+                if let Err(e) = write_frame(&dir, &frame) {
+                    println!("couldn't record a frame from {:?}: {:?}", frame.device_id(), e);
+                }
+            }
+        });
+
+        Ok(Recording { sender, writer, dropped: AtomicU32::new(0) })
+    }
+
+    pub fn record(&self, frame: &CapturedFrame) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(frame.clone()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns once every queued frame has been written
+    pub fn stop(self) {
+        drop(self.sender);
+        let _ = self.writer.join();
+
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        println!("stopped recording, {} frames were dropped because the disk couldn't keep up", dropped);
+    }
+}
+
+/// The frames of every camera captured between `start` and `end` in the newest recording that has
+/// any, at most `max_frames` of them per camera, evenly spaced. Camera ids start over whenever the
+/// helper does, so the same id in two recordings might not be the same camera, and a fetch never
+/// mixes frames from different recordings
+pub fn fetch(start: DateTime<Utc>, end: DateTime<Utc>, max_frames: u32) -> BurstResponse {
+    match recordings_dir() {
+        Ok(recordings_dir) => fetch_from(&recordings_dir, start, end, max_frames),
+        Err(e) => {
+            println!("couldn't find the recordings: {:?}", e);
+            BurstResponse { bursts: Vec::new() }
+        },
+    }
+}
+
+fn fetch_from(recordings_dir: &Path, start: DateTime<Utc>, end: DateTime<Utc>, max_frames: u32) -> BurstResponse {
+    let start_micros = start.timestamp_nanos() / 1000;
+    let end_micros = end.timestamp_nanos() / 1000;
+
+    let mut recordings = subdirectories(recordings_dir);
+    // named after when they started, so the newest comes last
+    recordings.sort();
+
+    let cameras = recordings.iter().rev()
+        .map(|recording| recorded_files(recording, start_micros, end_micros))
+        .find(|cameras| !cameras.is_empty())
+        .unwrap_or_default();
+
+    let bursts = cameras.into_iter()
+        .map(|(device_id, mut files)| {
+            files.sort();
+            let files = evenly_spaced(files, max_frames);
+
+            files.into_iter()
+                .filter_map(|(name, path)| match read_frame(&path, device_id, name) {
+                    Ok(frame) => Some(frame),
+                    Err(e) => {
+                        println!("couldn't read the recorded frame {:?}: {:?}", path, e);
+                        None
+                    },
+                })
+                .collect()
+        })
+        .collect();
+
+    BurstResponse { bursts }
+}
+
+/// The files of each camera in `recording` that were captured between the two times, along with
+/// what their names say about them
+fn recorded_files(recording: &Path, start_micros: i64, end_micros: i64) -> BTreeMap<DeviceId, Vec<(FrameName, PathBuf)>> {
+    let mut cameras: BTreeMap<DeviceId, Vec<(FrameName, PathBuf)>> = BTreeMap::new();
+
+    for camera in subdirectories(recording) {
+        let device_id = match camera.file_name().and_then(|name| name.to_str()).and_then(DeviceId::from_name) {
+            Some(device_id) => device_id,
+            None => continue,
+        };
+
+        let files = match fs::read_dir(&camera) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+
+        for file in files.filter_map(Result::ok) {
+            let path = file.path();
+            if path.extension() != Some(OsStr::new("jpg")) { continue }
+
+            let name = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(FrameName::parse);
+
+            if let Some(name) = name.filter(|name| start_micros <= name.captured_at_micros && name.captured_at_micros <= end_micros) {
+                cameras.entry(device_id).or_default().push((name, path));
+            }
+        }
+    }
+
+    cameras
+}
+
+fn recordings_dir() -> io::Result<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME isn't set"))?;
+    Ok(PathBuf::from(home).join("orbit_recordings"))
+}
+
+fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn write_frame(dir: &Path, frame: &CapturedFrame) -> io::Result<()> {
+    let camera_dir = dir.join(frame.device_id().to_string());
+    fs::create_dir_all(&camera_dir)?;
+
+    let name = FrameName {
+        captured_at_micros: frame.captured_at().timestamp_nanos() / 1000,
+        sequence: frame.sequence(),
+    };
+    fs::write(camera_dir.join(format!("{}.jpg", name)), frame_jpeg(frame)?)
+}
+
+/// Everything about a recorded frame besides the camera and the picture, kept in its file name.
+/// Sorting by it puts the frames in the order they were captured
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct FrameName {
+    captured_at_micros: i64,
+    sequence: u32,
+}
+
+impl FrameName {
+    /// Parses names like `1600000000000000_42`, without the extension
+    fn parse(s: &str) -> Option<FrameName> {
+        let (captured_at_micros, sequence) = s.split_once('_')?;
+        Some(FrameName { captured_at_micros: captured_at_micros.parse().ok()?, sequence: sequence.parse().ok()? })
+    }
+}
+
+impl fmt::Display for FrameName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}_{}", self.captured_at_micros, self.sequence)
+    }
+}
+
+/// The camera comes from the directory the frame was written to, and everything else but the
+/// picture from its name
+fn read_frame(path: &Path, device_id: DeviceId, name: FrameName) -> io::Result<CapturedFrame> {
+    let jpeg = fs::read(path)?;
+    let (width, height) = JpegDecoder::new(Cursor::new(&jpeg))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .dimensions();
+
+    let captured_at = Utc.timestamp_nanos(name.captured_at_micros * 1000);
+    Ok(CapturedFrame::new(device_id, width, height, *b"MJPG", captured_at, name.sequence, jpeg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orbit_types::DeviceIdGenerator;
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis(1_600_000_000_000 + millis)
+    }
+
+    /// A gray YUYV frame, which gets compressed on its way to disk like a real one
+    fn frame(device_id: DeviceId, millis: i64, sequence: u32) -> CapturedFrame {
+        CapturedFrame::new(device_id, 4, 2, orbit_types::YUYV, at(millis), sequence, vec![128; 4 * 2 * 2])
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("orbit_recording_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn frame_names_round_trip() {
+        let name = FrameName { captured_at_micros: 1_600_000_000_000_000, sequence: 42 };
+
+        assert_eq!(name.to_string(), "1600000000000000_42");
+        assert_eq!(FrameName::parse(&name.to_string()), Some(name));
+        assert_eq!(FrameName::parse("1600000000000000"), None);
+        assert_eq!(FrameName::parse("soon_42"), None);
+    }
+
+    #[test]
+    fn fetched_frames_of_a_camera_each_get_a_file() {
+        let recordings_dir = scratch_dir("fetch");
+        let recording = recordings_dir.join("2020-09-13T12-26-40.000000");
+        let camera = DeviceIdGenerator::new().next();
+        for (i, millis) in [0, 33, 66, 100].iter().enumerate() {
+            write_frame(&recording, &frame(camera, *millis, 7 + i as u32)).unwrap();
+        }
+
+        let response = fetch_from(&recordings_dir, at(0), at(100), 10);
+        fs::remove_dir_all(&recordings_dir).unwrap();
+
+        assert_eq!(response.bursts.len(), 1);
+        let frames = &response.bursts[0];
+        assert_eq!(frames.iter().map(CapturedFrame::sequence).collect::<Vec<_>>(), vec![7, 8, 9, 10]);
+        assert!(frames.iter().all(|frame| frame.device_id() == camera && (frame.width(), frame.height()) == (4, 2)));
+
+        // the station names the files it saves after the sequence numbers
+        let saved_dir = scratch_dir("save");
+        fs::create_dir_all(&saved_dir).unwrap();
+        for frame in frames {
+            fs::write(saved_dir.join(format!("seq{:06}.jpg", frame.sequence())), frame.frame_data()).unwrap();
+        }
+        let saved = fs::read_dir(&saved_dir).unwrap().count();
+        fs::remove_dir_all(&saved_dir).unwrap();
+
+        assert_eq!(saved, frames.len());
+    }
+
+    #[test]
+    fn fetches_only_the_window_from_the_newest_recording() {
+        let recordings_dir = scratch_dir("newest");
+        let mut devices = DeviceIdGenerator::new();
+        let camera = devices.next();
+        write_frame(&recordings_dir.join("2020-09-13T12-26-40.000000"), &frame(camera, 10, 1)).unwrap();
+        write_frame(&recordings_dir.join("2020-09-13T12-30-00.000000"), &frame(camera, 20, 2)).unwrap();
+        write_frame(&recordings_dir.join("2020-09-13T12-30-00.000000"), &frame(camera, 500, 3)).unwrap();
+
+        let response = fetch_from(&recordings_dir, at(0), at(100), 10);
+        fs::remove_dir_all(&recordings_dir).unwrap();
+
+        let sequences: Vec<Vec<u32>> = response.bursts.iter()
+            .map(|frames| frames.iter().map(CapturedFrame::sequence).collect())
+            .collect();
+        assert_eq!(sequences, vec![vec![2]]);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use v4l::prelude::CaptureDevice;
+use orbit_types::{CapturedFrame, DeviceId, CameraFormat};
+use crate::{SNAP_FORMAT, ARMED_BUFFER_COUNT, RING_BUFFER_HISTORY_MILLIS, RING_BUFFER_BUDGET_BYTES};
+use crate::known_devices::{KnownDevices, DeviceFileIndex};
+use crate::polling_stream_fork::Stream;
+use crate::negotiation;
+use crate::bandwidth::BandwidthLedger;
+use crate::snap::{boot_time_utc, duration_abs};
+use crate::watchdog::{Watchdog, CameraWatch, Recovery};
+use crate::recording::Recording;
+
+/// The frames captured by one camera over the last `RING_BUFFER_HISTORY_MILLIS`, or fewer if they
+/// don't fit in `budget_bytes`
+pub struct FrameRing {
+    frames: Mutex<Frames>,
+    new_frame: Condvar,
+    budget_bytes: usize,
+    /// Set when the camera stops delivering frames for good
+    stopped: AtomicBool,
+    /// What the camera ended up capturing, once it's been opened
+    format: Mutex<Option<CameraFormat>>,
+}
+
+struct Frames {
+    frames: VecDeque<CapturedFrame>,
+    total_bytes: usize,
+}
+
+impl FrameRing {
+    fn new(budget_bytes: usize) -> FrameRing {
+        FrameRing {
+            frames: Mutex::new(Frames { frames: VecDeque::new(), total_bytes: 0 }),
+            new_frame: Condvar::new(),
+            budget_bytes,
+            stopped: AtomicBool::new(false),
+            format: Mutex::new(None),
+        }
+    }
+
+    fn push(&self, frame: CapturedFrame) {
+        let history = chrono::Duration::milliseconds(RING_BUFFER_HISTORY_MILLIS);
+        let oldest_allowed = *frame.captured_at() - history;
+
+        let mut frames = self.frames.lock().unwrap();
+        frames.total_bytes += frame.frame_data().len();
+        frames.frames.push_back(frame);
+
+        // always keep the newest frame, even if it's over budget on its own
+        while frames.frames.len() > 1 {
+            let oldest = &frames.frames[0];
+            if frames.total_bytes <= self.budget_bytes && *oldest.captured_at() >= oldest_allowed { break }
+
+            let oldest_len = oldest.frame_data().len();
+            frames.frames.pop_front();
+            frames.total_bytes -= oldest_len;
+        }
+
+        self.new_frame.notify_all();
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.new_frame.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub fn format(&self) -> Option<CameraFormat> {
+        *self.format.lock().unwrap()
+    }
+
+    pub fn latest(&self) -> Option<CapturedFrame> {
+        self.frames.lock().unwrap().frames.back().cloned()
+    }
+
+    /// Returns the frame captured closest to `target_time`, which can be in the past as long as
+    /// it's still in the buffer. If the target time hasn't come yet, waits until there is a frame
+    /// on both sides of it, or until `deadline`
+    pub fn nearest(&self, target_time: DateTime<Utc>, deadline: DateTime<Utc>) -> Option<CapturedFrame> {
+        let timeout = (deadline - Utc::now()).to_std().unwrap_or(Duration::from_secs(0));
+
+        let frames = self.frames.lock().unwrap();
+        let (frames, _) = self.new_frame.wait_timeout_while(frames, timeout, |frames| {
+            !self.is_stopped() && !matches!(frames.frames.back(), Some(newest) if *newest.captured_at() >= target_time)
+        }).unwrap();
+
+        if let Some(oldest) = frames.frames.front() {
+            if target_time < *oldest.captured_at() {
+                println!("requested frame at {:?} but the oldest buffered frame is from {:?}", target_time, oldest.captured_at());
+            }
+        }
+
+        frames.frames.iter()
+            .min_by_key(|frame| duration_abs(target_time - *frame.captured_at()))
+            .cloned()
+    }
+
+    /// Returns the frames captured between `start` and `end`, waiting until `end` has passed or
+    /// until `deadline`
+    pub fn window(&self, start: DateTime<Utc>, end: DateTime<Utc>, deadline: DateTime<Utc>) -> Vec<CapturedFrame> {
+        let timeout = (deadline - Utc::now()).to_std().unwrap_or(Duration::from_secs(0));
+
+        let frames = self.frames.lock().unwrap();
+        let (frames, _) = self.new_frame.wait_timeout_while(frames, timeout, |frames| {
+            !self.is_stopped() && !matches!(frames.frames.back(), Some(newest) if *newest.captured_at() >= end)
+        }).unwrap();
+
+        frames.frames.iter()
+            .filter(|frame| start <= *frame.captured_at() && *frame.captured_at() <= end)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Every camera, opened at snap resolution and streaming into its own `FrameRing`, and to disk
+/// while recording
+pub struct ArmedDevices {
+    rings: Vec<(DeviceId, Arc<FrameRing>)>,
+    recording: Arc<Mutex<Option<Recording>>>,
+    should_stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl ArmedDevices {
+    pub fn arm(known_devices: &KnownDevices, watchdog: &Watchdog, bandwidth: &BandwidthLedger) -> ArmedDevices {
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let recording = Arc::new(Mutex::new(None));
+        let mut rings = Vec::new();
+        let mut handles = Vec::new();
+
+        let devices: Vec<_> = known_devices.video_devices().collect();
+        let budget_bytes = RING_BUFFER_BUDGET_BYTES / devices.len().max(1);
+
+        for (device_index, device_id) in devices {
+            let ring = Arc::new(FrameRing::new(budget_bytes));
+            rings.push((device_id, Arc::clone(&ring)));
+
+            let should_stop = Arc::clone(&should_stop);
+            let recording = Arc::clone(&recording);
+            let mut watch = watchdog.watch(device_index, device_id);
+            let bandwidth = bandwidth.clone();
+            handles.push(thread::spawn(move || {
+                while !should_stop.load(Ordering::Relaxed) {
+                    match capture_into(device_index, device_id, &ring, &recording, &bandwidth, &should_stop, &mut watch) {
+                        Ok(()) => break,
+                        Err(e) => {
+                            println!("armed capture failed with error {:?} on device {:?}", e, device_id);
+                            match watch.recover(&e) {
+                                Recovery::Reopen => continue,
+                                Recovery::Replugged | Recovery::GiveUp => break,
+                            }
+                        },
+                    }
+                }
+                ring.stop();
+            }));
+        }
+
+        println!("armed {} devices", rings.len());
+
+        ArmedDevices { rings, recording, should_stop, handles }
+    }
+
+    pub fn disarm(self) {
+        self.should_stop.store(true, Ordering::Relaxed);
+        for handle in self.handles {
+            let _ = handle.join();
+        }
+        if let Some(recording) = self.recording.lock().unwrap().take() {
+            recording.stop();
+        }
+        println!("disarmed");
+    }
+
+    pub fn start_recording(&self) {
+        let mut recording = self.recording.lock().unwrap();
+        if recording.is_some() { return }
+
+        match Recording::start() {
+            Ok(started) => *recording = Some(started),
+            Err(e) => println!("couldn't start recording: {:?}", e),
+        }
+    }
+
+    pub fn stop_recording(&self) {
+        // take it first, so the cameras aren't kept waiting while the rest is written
+        let recording = self.recording.lock().unwrap().take();
+        if let Some(recording) = recording {
+            recording.stop();
+        }
+    }
+
+    pub fn ring(&self, device_id: DeviceId) -> Option<Arc<FrameRing>> {
+        self.rings.iter()
+            .find(|&&(id, _)| id == device_id)
+            .map(|(_, ring)| Arc::clone(ring))
+    }
+
+    pub fn rings(&self) -> impl Iterator<Item=&(DeviceId, Arc<FrameRing>)> + '_ {
+        self.rings.iter()
+    }
+}
+
+fn capture_into(
+    device_index: DeviceFileIndex,
+    device_id: DeviceId,
+    ring: &FrameRing,
+    recording: &Mutex<Option<Recording>>,
+    bandwidth: &BandwidthLedger,
+    should_stop: &AtomicBool,
+    watch: &mut CameraWatch,
+) -> io::Result<()> {
+    let boot_time_utc = boot_time_utc();
+
+    let mut device = CaptureDevice::new(device_index.file_index())?;
+    let (used_format, format, _reservation) = negotiation::negotiate(&mut device, device_index, SNAP_FORMAT, bandwidth)?;
+    *ring.format.lock().unwrap() = Some(format);
+
+    let stream = Stream::with_buffers(&device, ARMED_BUFFER_COUNT)?;
+    let mut stream = stream.start()?;
+
+    while !should_stop.load(Ordering::Relaxed) {
+        let frame = match stream.next() {
+            Ok(frame) => frame,
+            Err(e) if watch.keep_waiting(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        watch.frame_arrived();
+
+        let frame = CapturedFrame::from_frame(&frame, used_format, boot_time_utc, device_id);
+        if let Some(ref recording) = *recording.lock().unwrap() {
+            recording.record(&frame);
+        }
+        ring.push(frame);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use orbit_types::DeviceIdGenerator;
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis(1_600_000_000_000 + millis)
+    }
+
+    /// A frame of `len` bytes, with its capture time in the sequence number to tell them apart
+    fn frame(millis: i64, len: usize) -> CapturedFrame {
+        let device_id = DeviceIdGenerator::new().next();
+        CapturedFrame::new(device_id, 1, 1, *b"MJPG", at(millis), millis as u32, vec![0; len])
+    }
+
+    fn ring(budget_bytes: usize, frames: &[i64]) -> FrameRing {
+        let ring = FrameRing::new(budget_bytes);
+        for &millis in frames {
+            ring.push(frame(millis, 10));
+        }
+        ring
+    }
+
+    fn sequences(frames: &[CapturedFrame]) -> Vec<u32> {
+        frames.iter().map(CapturedFrame::sequence).collect()
+    }
+
+    fn buffered(ring: &FrameRing) -> Vec<u32> {
+        ring.frames.lock().unwrap().frames.iter().map(CapturedFrame::sequence).collect()
+    }
+
+    #[test]
+    fn frames_older_than_the_history_are_dropped() {
+        let ring = ring(1000, &[0, 400, 1000, 3000, 3400]);
+
+        // 3400 - RING_BUFFER_HISTORY_MILLIS is 400, which is just old enough to stay
+        assert_eq!(buffered(&ring), [400, 1000, 3000, 3400]);
+    }
+
+    #[test]
+    fn the_oldest_frames_are_dropped_to_fit_the_budget() {
+        let ring = ring(25, &[0, 10, 20, 30]);
+
+        assert_eq!(buffered(&ring), [20, 30]);
+        assert_eq!(ring.frames.lock().unwrap().total_bytes, 20);
+    }
+
+    #[test]
+    fn the_newest_frame_is_kept_even_over_budget() {
+        let ring = ring(25, &[0]);
+        ring.push(frame(10, 100));
+
+        assert_eq!(buffered(&ring), [10]);
+        assert_eq!(ring.latest().map(|frame| frame.sequence()), Some(10));
+    }
+
+    #[test]
+    fn nearest_frame_to_a_past_time() {
+        let ring = ring(1000, &[0, 100, 200]);
+
+        // the deadline has passed, so it doesn't wait for anything newer
+        assert_eq!(ring.nearest(at(130), at(0)).map(|frame| frame.sequence()), Some(100));
+        assert_eq!(ring.nearest(at(-50), at(0)).map(|frame| frame.sequence()), Some(0));
+        assert_eq!(ring.nearest(at(500), at(0)).map(|frame| frame.sequence()), Some(200));
+    }
+
+    #[test]
+    fn nearest_waits_for_a_frame_after_the_target() {
+        let ring = Arc::new(ring(1000, &[0, 100]));
+        let pushing = {
+            let ring = Arc::clone(&ring);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                ring.push(frame(160, 10));
+            })
+        };
+
+        let nearest = ring.nearest(at(150), Utc::now() + chrono::Duration::seconds(5));
+        pushing.join().unwrap();
+
+        assert_eq!(nearest.map(|frame| frame.sequence()), Some(160));
+    }
+
+    #[test]
+    fn nearest_gives_up_waiting_once_the_camera_stops() {
+        let ring = ring(1000, &[0, 100]);
+        ring.stop();
+
+        let nearest = ring.nearest(at(150), Utc::now() + chrono::Duration::seconds(5));
+
+        assert_eq!(nearest.map(|frame| frame.sequence()), Some(100));
+    }
+
+    #[test]
+    fn window_includes_both_ends() {
+        let ring = ring(1000, &[0, 100, 200, 300, 400]);
+
+        assert_eq!(sequences(&ring.window(at(100), at(300), at(0))), [100, 200, 300]);
+        assert_eq!(sequences(&ring.window(at(150), at(160), at(0))), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,156 @@
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use orbit_types::{Request, StreamResponse, StreamSettings};
+
+use crate::{Helper, SharedHelper, restart};
+use crate::snap::Capture;
+use crate::stream::Previews;
+use crate::preview::PreviewTargets;
+use crate::recording;
+use crate::health;
+
+/// Streams previews over the connection until the station hangs up, while reading more requests
+/// from it. The responses are sent back between the preview frames, so the station never has to
+/// stop the previews or open another connection to take a picture
+pub fn session(connection: TcpStream, helper: SharedHelper, settings: StreamSettings) {
+    let reader = match connection.try_clone() {
+        Ok(reader) => reader,
+        Err(e) => {
+            println!("couldn't start session: {:?}", e);
+            return;
+        },
+    };
+    let mut reader = BufReader::new(reader);
+    let writer = Arc::new(Mutex::new(connection));
+    let targets = PreviewTargets::new(settings.preview);
+    // fetching originals doesn't need the cameras, so it doesn't wait for a snap to finish
+    let originals = helper.lock().unwrap().originals.clone();
+    let watchdog = helper.lock().unwrap().watchdog.clone();
+    let detectors = helper.lock().unwrap().detectors.clone();
+    helper.lock().unwrap().session_count += 1;
+
+    let stop_reporting = Arc::new(AtomicBool::new(false));
+    let reporter = health::spawn_reporter(watchdog.clone(), Arc::clone(&writer), Arc::clone(&stop_reporting));
+
+    let mut session = Session {
+        previews: Previews::start(&helper, &writer, settings, &targets),
+        helper,
+        writer,
+        settings,
+        targets,
+    };
+
+    while let Ok(request) = bincode::deserialize_from(&mut reader) {
+        let response = match request {
+            Request::Stream(_) => None,
+            Request::Snap(target_time) => {
+                Some(StreamResponse::Stills(session.capture().snap(target_time)))
+            },
+            Request::Burst { start, end, max_frames } => {
+                Some(StreamResponse::Burst(session.capture().burst(start, end, max_frames)))
+            },
+            Request::Detect { target_time, search } => {
+                let stills = session.capture().snap(target_time).stills;
+                Some(StreamResponse::Detections(detectors.detect(stills, search)))
+            },
+            Request::Arm => {
+                session.restarting_previews(Helper::arm);
+                None
+            },
+            Request::Disarm => {
+                session.restarting_previews(Helper::disarm);
+                None
+            },
+            Request::StartRecording => {
+                session.restarting_previews(Helper::start_recording);
+                None
+            },
+            Request::StopRecording => {
+                session.restarting_previews(Helper::stop_recording);
+                None
+            },
+            // reading the frames back doesn't need the cameras, so it doesn't hold up anyone else
+            Request::FetchRecording { start, end, max_frames } => {
+                Some(StreamResponse::Recorded(recording::fetch(start, end, max_frames)))
+            },
+            Request::SnapThumbnails { target_time, capture_id, thumbnail } => {
+                Some(StreamResponse::Stills(session.capture().snap_thumbnails(target_time, capture_id, thumbnail, &originals)))
+            },
+            Request::FetchOriginal { capture_id, device_id, offset, max_len } => {
+                Some(StreamResponse::Original(originals.chunk(capture_id, device_id, offset, max_len)))
+            },
+            Request::Status => Some(StreamResponse::Status(health::report(&watchdog))),
+            // there's no response for these in a session, so ask over a connection of their own
+            Request::Log | Request::Devices => None,
+            Request::Restart => {
+                restart(&session.helper);
+                None
+            },
+            Request::Preview(device_id, preview) => {
+                // the camera's listener picks it up with its next frame
+                session.targets.set(device_id, preview);
+                None
+            },
+            Request::Latencies(latencies) => {
+                session.helper.lock().unwrap().set_latencies(latencies);
+                None
+            },
+        };
+
+        if let Some(response) = response {
+            if response.serialize_into(&mut *session.writer.lock().unwrap()).is_err() { break }
+        }
+    }
+
+    stop_reporting.store(true, Ordering::Relaxed);
+    let _ = reporter.join();
+
+    // the cameras stay armed for the other sessions' captures, until the last one hangs up
+    session.previews.stop();
+    let mut helper = session.helper.lock().unwrap();
+    helper.session_count -= 1;
+    if helper.session_count == 0 && helper.armed_for_captures {
+        helper.disarm();
+    }
+    drop(helper);
+
+    println!("session ended");
+}
+
+struct Session {
+    helper: SharedHelper,
+    writer: Arc<Mutex<TcpStream>>,
+    previews: Previews,
+    settings: StreamSettings,
+    targets: PreviewTargets,
+}
+
+impl Session {
+    /// Unarmed cameras are busy streaming previews, so rather than stopping those for every
+    /// capture, the cameras get armed once and the previews come from the ring buffers from then on
+    fn capture(&mut self) -> Capture {
+        let settings = self.settings;
+        let armed = {
+            let helper = self.helper.lock().unwrap();
+            if helper.is_armed() { Some(helper.capture(settings)) } else { None }
+        };
+
+        match armed {
+            Some(capture) => capture,
+            None => self.restarting_previews(|helper| {
+                helper.arm_for_captures();
+                helper.capture(settings)
+            }),
+        }
+    }
+
+    fn restarting_previews<T>(&mut self, f: impl FnOnce(&mut Helper) -> T) -> T {
+        self.previews.stop();
+        let result = f(&mut *self.helper.lock().unwrap());
+        self.previews = Previews::start(&self.helper, &self.writer, self.settings, &self.targets);
+        result
+    }
+}
@@ -1,25 +1,121 @@
 use chrono::{DateTime, Utc};
 use crate::{SNAP_FORMAT};
+use crate::negotiation;
+use crate::bandwidth::BandwidthLedger;
 use v4l::prelude::{CaptureDevice};
-use std::net::TcpStream;
 use std::{io, thread};
 use std::mem::MaybeUninit;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use crate::polling_stream_fork::{Stream};
 use std::thread::JoinHandle;
-use crate::known_devices::KnownDevices;
+use crate::known_devices::DeviceFileIndex;
 use libc::{CLOCK_MONOTONIC, timespec, clock_gettime};
-use orbit_types::{CapturedFrame, SnapResponse};
+use orbit_types::{CapturedFrame, SnapResponse, BurstResponse, DeviceId, CaptureId, PreviewSettings};
+use orbit_metrics::{Metric, Metrics};
+use crate::ring_buffer::FrameRing;
+use crate::originals::OriginalCache;
+use crate::preview;
+use crate::ARMED_FRAME_WAIT_MILLIS;
 
-pub fn snap(target_time: DateTime<Utc>, known_devices: &mut KnownDevices, mut writer: TcpStream) {
+const SNAP_SECONDS: Metric = Metric {
+    name: "orbit_helper_snap_seconds",
+    help: "How long snaps took, from the request to the stills being ready",
+};
+
+/// How late the timestamps of each camera are, as measured by the station. Cameras that aren't in
+/// it aren't late
+pub type Latencies = HashMap<DeviceId, chrono::Duration>;
+
+/// What a capture needs from the helper, taken in one go so that the helper isn't locked while the
+/// cameras capture
+pub struct Capture {
+    cameras: Cameras,
+    buffer_count: u32,
+    latencies: Latencies,
+    metrics: Metrics,
+}
+
+pub enum Cameras {
+    /// The ring buffers of the armed cameras
+    Armed(Vec<(DeviceId, Arc<FrameRing>)>),
+    /// The cameras have to be opened for the capture. Only one capture can have a camera open at a
+    /// time, so they take turns holding `turn`
+    Closed {
+        devices: Vec<(DeviceFileIndex, DeviceId)>,
+        bandwidth: BandwidthLedger,
+        turn: Arc<Mutex<()>>,
+    },
+}
+
+impl Capture {
+    pub fn new(cameras: Cameras, buffer_count: u32, latencies: Latencies, metrics: Metrics) -> Capture {
+        Capture { cameras, buffer_count, latencies, metrics }
+    }
+
+    pub fn snap(&self, target_time: DateTime<Utc>) -> SnapResponse {
+        let started = Instant::now();
+
+        let (response, mode) = match self.cameras {
+            Cameras::Armed(ref rings) => (fire(target_time, rings, &self.latencies), "armed"),
+            Cameras::Closed { ref devices, ref bandwidth, ref turn } => {
+                let _turn = turn.lock().unwrap();
+                (snap(target_time, self.buffer_count, devices, bandwidth, &self.latencies), "unarmed")
+            },
+        };
+
+        self.metrics.observe(&SNAP_SECONDS, &[("mode", mode)], started.elapsed().as_secs_f64());
+        response
+    }
+
+    pub fn burst(&self, start: DateTime<Utc>, end: DateTime<Utc>, max_frames: u32) -> BurstResponse {
+        match self.cameras {
+            Cameras::Armed(ref rings) => fire_burst(start, end, max_frames, rings, &self.latencies),
+            Cameras::Closed { ref devices, ref bandwidth, ref turn } => {
+                let _turn = turn.lock().unwrap();
+                burst(start, end, max_frames, self.buffer_count, devices, bandwidth, &self.latencies)
+            },
+        }
+    }
+
+    /// Keeps the originals in `originals` for `FetchOriginal`, and returns thumbnails of them
+    pub fn snap_thumbnails(
+        &self,
+        target_time: DateTime<Utc>,
+        capture_id: CaptureId,
+        thumbnail: PreviewSettings,
+        originals: &OriginalCache,
+    ) -> SnapResponse {
+        let stills = self.snap(target_time).stills;
+        originals.keep(capture_id, &stills);
+
+        let stills = stills.into_iter()
+            .map(|still| preview::thumbnail(still, thumbnail))
+            .collect();
+
+        SnapResponse { stills }
+    }
+}
+
+fn snap(
+    target_time: DateTime<Utc>,
+    buffer_count: u32,
+    devices: &[(DeviceFileIndex, DeviceId)],
+    bandwidth: &BandwidthLedger,
+    latencies: &Latencies,
+) -> SnapResponse {
     let mut handles = Vec::new();
 
     let boot_time_utc = boot_time_utc();
 
-    for (d, device_id) in known_devices.video_devices() {
+    for &(d, device_id) in devices {
+        let bandwidth = bandwidth.clone();
+        let target_time = target_time + latency(latencies, device_id);
         let handle: JoinHandle<io::Result<CapturedFrame>> = thread::spawn(move || {
             let mut dev = CaptureDevice::new(d.file_index())?;
-            let used_format = dev.set_format(&SNAP_FORMAT)?;
-            let stream = Stream::with_buffers(&mut dev, 1)?;
+            let (used_format, _, _reservation) = negotiation::negotiate(&mut dev, d, SNAP_FORMAT, &bandwidth)?;
+            let stream = Stream::with_buffers(&mut dev, buffer_count)?;
             let mut active = stream.start()?;
 
             let burned_frame = active.next()?;
@@ -49,15 +145,114 @@ pub fn snap(target_time: DateTime<Utc>, known_devices: &mut KnownDevices, mut wr
     let mut stills = Vec::new();
 
     for handle in handles {
-        if let Ok(frame) = handle.join().unwrap() {
-            stills.push(frame);
+        match handle.join().unwrap() {
+            Ok(frame) => stills.push(frame),
+            Err(e) => println!("couldn't snap: {}", e),
         }
     }
 
-    let _ = bincode::serialize_into(
-        &mut writer,
-        &SnapResponse { stills },
-    );
+    SnapResponse { stills }
+}
+
+/// Like `snap`, but the cameras are already streaming so we just pick frames out of the ring buffers
+fn fire(target_time: DateTime<Utc>, rings: &[(DeviceId, Arc<FrameRing>)], latencies: &Latencies) -> SnapResponse {
+    let stills = rings.iter()
+        .filter_map(|&(device_id, ref ring)| {
+            let target_time = target_time + latency(latencies, device_id);
+            let deadline = target_time + chrono::Duration::milliseconds(ARMED_FRAME_WAIT_MILLIS);
+            ring.nearest(target_time, deadline)
+        })
+        .collect();
+
+    SnapResponse { stills }
+}
+
+/// Captures every frame between `start` and `end` from every camera
+fn burst(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    max_frames: u32,
+    buffer_count: u32,
+    devices: &[(DeviceFileIndex, DeviceId)],
+    bandwidth: &BandwidthLedger,
+    latencies: &Latencies,
+) -> BurstResponse {
+    let boot_time_utc = boot_time_utc();
+
+    let handles: Vec<JoinHandle<io::Result<Vec<CapturedFrame>>>> = devices.iter()
+        .map(|&(d, device_id)| (d, device_id, bandwidth.clone(), latency(latencies, device_id)))
+        .map(|(d, device_id, bandwidth, latency)| thread::spawn(move || {
+            let (start, end) = (start + latency, end + latency);
+            let mut dev = CaptureDevice::new(d.file_index())?;
+            let (used_format, _, _reservation) = negotiation::negotiate(&mut dev, d, SNAP_FORMAT, &bandwidth)?;
+            let stream = Stream::with_buffers(&mut dev, buffer_count)?;
+            let mut active = stream.start()?;
+
+            let mut frames = Vec::new();
+
+            loop {
+                let frame = active.next()?;
+                let frame = CapturedFrame::from_frame(&frame, used_format, boot_time_utc, device_id);
+
+                if *frame.captured_at() > end { break }
+                if *frame.captured_at() >= start {
+                    frames.push(frame);
+                }
+            }
+
+            Ok(evenly_spaced(frames, max_frames))
+        }))
+        .collect();
+
+    let bursts = handles.into_iter()
+        .filter_map(|handle| match handle.join().unwrap() {
+            Ok(frames) => Some(frames),
+            Err(e) => {
+                println!("couldn't capture a burst: {}", e);
+                None
+            },
+        })
+        .collect();
+
+    BurstResponse { bursts }
+}
+
+/// Like `burst`, but the frames come out of the ring buffers
+fn fire_burst(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    max_frames: u32,
+    rings: &[(DeviceId, Arc<FrameRing>)],
+    latencies: &Latencies,
+) -> BurstResponse {
+    let bursts = rings.iter()
+        .map(|&(device_id, ref ring)| {
+            let latency = latency(latencies, device_id);
+            let deadline = end + latency + chrono::Duration::milliseconds(ARMED_FRAME_WAIT_MILLIS);
+            evenly_spaced(ring.window(start + latency, end + latency, deadline), max_frames)
+        })
+        .collect();
+
+    BurstResponse { bursts }
+}
+
+/// A late camera's timestamps say its frames were captured later than they were, so to capture
+/// at some time, we look for frames that say they were captured that much later
+fn latency(latencies: &Latencies, device_id: DeviceId) -> chrono::Duration {
+    latencies.get(&device_id).copied().unwrap_or_else(chrono::Duration::zero)
+}
+
+/// Picks at most `max_frames` frames, spread out over all of `frames`
+pub fn evenly_spaced<T>(frames: Vec<T>, max_frames: u32) -> Vec<T> {
+    let max_frames = max_frames as usize;
+    if frames.len() <= max_frames { return frames }
+
+    let len = frames.len();
+    frames.into_iter()
+        .enumerate()
+        .filter(|&(i, _)| (i * max_frames) % len < max_frames)
+        .map(|(_, frame)| frame)
+        .collect()
 }
 
 pub fn duration_abs(duration: chrono::Duration) -> chrono::Duration {
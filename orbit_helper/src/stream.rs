@@ -1,67 +1,271 @@
 use std::{io, sync::Arc, thread};
 use std::net::TcpStream;
+use std::thread::JoinHandle;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Instant;
 use v4l::prelude::CaptureDevice;
-use crate::known_devices::{KnownDevices, DeviceFileIndex};
-use crate::{STREAM_FORMAT, NEW_DEVICE_CHECK};
+use crate::known_devices::DeviceFileIndex;
+use crate::{Helper, SharedHelper, SNAP_FORMAT, STREAM_FORMAT, STOP_CHECK, ARMED_PREVIEW_INTERVAL, DROP_REPORT_INTERVAL};
+use crate::hotplug::DeviceChange;
 use std::sync::Mutex;
 use orbit_types::{CapturedFrame};
 use std::sync::atomic::{AtomicBool, Ordering};
-use orbit_types::{DeviceId, StreamResponse};
+use orbit_types::{DeviceId, StreamResponse, StreamSettings, DropCounts, CameraFormats, DevicesResponse, PreviewSettings};
+use orbit_metrics::{Metric, Metrics};
 use crate::snap::boot_time_utc;
 use crate::polling_stream_fork::Stream;
+use crate::ring_buffer::FrameRing;
+use crate::negotiation;
+use crate::watchdog::{Watchdog, CameraWatch, Recovery};
+use crate::bandwidth::BandwidthLedger;
+use crate::preview::{PreviewShaper, PreviewTargets};
+use crate::negotiation::FormatRequest;
 
-pub fn stream(
-    connection: TcpStream,
-    known_devices: &mut KnownDevices,
-) {
-    let writer = Arc::new(Mutex::new(connection));
-    let should_stop = Arc::new(AtomicBool::new(false));
+const PREVIEW_FRAMES_SENT: Metric = Metric {
+    name: "orbit_helper_preview_frames_sent_total",
+    help: "Preview frames sent to the station",
+};
+const PREVIEW_FRAMES_DROPPED: Metric = Metric {
+    name: "orbit_helper_preview_frames_dropped_total",
+    help: "Frames missed for lack of a free buffer, or discarded for a newer one",
+};
+
+/// Sends a preview from every camera over the connection until stopped, picking up cameras as
+/// they're plugged in and telling the station when they come and go. Armed cameras are already
+/// open, so their previews come from their ring buffers instead
+pub struct Previews {
+    should_stop: Arc<AtomicBool>,
+    watcher: Option<JoinHandle<()>>,
+}
 
-    for (device_index, device_id) in known_devices.video_devices() {
-        let writer = Arc::clone(&writer);
-        let should_stop = Arc::clone(&should_stop);
+impl Previews {
+    pub fn start(
+        helper: &SharedHelper,
+        writer: &Arc<Mutex<TcpStream>>,
+        settings: StreamSettings,
+        targets: &PreviewTargets,
+    ) -> Previews {
+        let should_stop = Arc::new(AtomicBool::new(false));
 
-        spawn_stream_listener(device_index, device_id, writer, should_stop)
+        let watcher = {
+            let helper = Arc::clone(helper);
+            let writer = Arc::clone(writer);
+            let targets = targets.clone();
+            let should_stop = Arc::clone(&should_stop);
+            thread::spawn(move || watch_devices(helper, writer, settings, targets, should_stop))
+        };
+
+        Previews { should_stop, watcher: Some(watcher) }
     }
 
-    while !should_stop.load(Ordering::Relaxed) {
-        for (device_index, device_id) in known_devices.recently_added() {
+    /// Returns once every camera the previews opened has been closed again
+    pub fn stop(&mut self) {
+        self.should_stop.store(true, Ordering::Relaxed);
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+    }
+}
+
+impl Drop for Previews {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn watch_devices(
+    helper: SharedHelper,
+    writer: Arc<Mutex<TcpStream>>,
+    settings: StreamSettings,
+    targets: PreviewTargets,
+    should_stop: Arc<AtomicBool>,
+) {
+    let mut handles = Vec::new();
+
+    // subscribing while we list the devices means we can't miss one being plugged in in between
+    let (device_changes, watchdog, bandwidth, metrics) = {
+        let helper = helper.lock().unwrap();
+        let device_changes = helper.subscribe();
+        let Helper { known_devices, armed, watchdog, bandwidth, metrics, .. } = &*helper;
+
+        for (device_index, device_id) in known_devices.video_devices() {
             let writer = Arc::clone(&writer);
             let should_stop = Arc::clone(&should_stop);
 
-            spawn_stream_listener(device_index, device_id, writer, should_stop)
+            match armed.as_ref().and_then(|armed| armed.ring(device_id)) {
+                Some(ring) => handles.push(spawn_armed_listener(device_id, ring, targets.clone(), metrics.clone(), writer, should_stop)),
+                None => handles.push(spawn_stream_listener(
+                    device_index,
+                    device_id,
+                    settings,
+                    targets.clone(),
+                    watchdog.clone(),
+                    bandwidth.clone(),
+                    metrics.clone(),
+                    writer,
+                    should_stop,
+                )),
+            }
         }
 
-        thread::sleep(NEW_DEVICE_CHECK);
+        (device_changes, watchdog.clone(), bandwidth.clone(), metrics.clone())
+    };
+
+    while !should_stop.load(Ordering::Relaxed) {
+        let change = match device_changes.recv_timeout(STOP_CHECK) {
+            Ok(change) => change,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let response = match change {
+            DeviceChange::Added(device_index, device_id) => {
+                let writer = Arc::clone(&writer);
+                let should_stop = Arc::clone(&should_stop);
+                handles.push(spawn_stream_listener(
+                    device_index,
+                    device_id,
+                    settings,
+                    targets.clone(),
+                    watchdog.clone(),
+                    bandwidth.clone(),
+                    metrics.clone(),
+                    writer,
+                    should_stop,
+                ));
+
+                StreamResponse::DeviceAdded(device_id)
+            },
+            // the camera's listener finds out on its own when it tries to read the next frame
+            DeviceChange::Removed(device_id) => StreamResponse::DeviceRemoved(device_id),
+            DeviceChange::Recovering(device_id, attempt) => StreamResponse::Recovering(device_id, attempt),
+        };
+
+        if response.serialize_into(&mut *writer.lock().unwrap()).is_err() {
+            should_stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    for handle in handles {
+        let _ = handle.join();
     }
 }
 
 fn spawn_stream_listener(
     device_index: DeviceFileIndex,
     device_id: DeviceId,
+    settings: StreamSettings,
+    targets: PreviewTargets,
+    watchdog: Watchdog,
+    bandwidth: BandwidthLedger,
+    metrics: Metrics,
     writer: Arc<Mutex<TcpStream>>,
     should_stop: Arc<AtomicBool>,
-) {
+) -> JoinHandle<()> {
     thread::spawn(move || {
         println!("{:?} {:?}", device_index, device_id);
-        match stream_inner(device_index, device_id, Arc::clone(&writer), Arc::clone(&should_stop)) {
-            Ok(_) => {},
-            Err(OrbitError::TcpStreamFailed(_)) => {
-                println!("tcp stream failed error in device {:?}", device_id);
-                should_stop.store(true, Ordering::Relaxed)
-            }, // can't report lol
-            Err(OrbitError::WebcamFailed(e)) => {
-                println!("webcam failed error {:?} on device {:?}", e, device_id);
-                // ignore error because nothing to do
+        let mut watch = watchdog.watch(device_index, device_id);
+
+        while !should_stop.load(Ordering::Relaxed) {
+            let result = stream_inner(
+                device_index,
+                device_id,
+                settings,
+                &targets,
+                &bandwidth,
+                &metrics,
+                &mut watch,
+                Arc::clone(&writer),
+                Arc::clone(&should_stop),
+            );
+
+            match result {
+                // either we were stopped, which the loop checks, or the camera has to be opened
+                // again for its new preview settings
+                Ok(()) => continue,
+                Err(OrbitError::TcpStreamFailed(_)) => {
+                    println!("tcp stream failed error in device {:?}", device_id);
+                    should_stop.store(true, Ordering::Relaxed)
+                }, // can't report lol
+                Err(OrbitError::WebcamFailed(e)) => {
+                    println!("webcam failed error {:?} on device {:?}", e, device_id);
+                    match watch.recover(&e) {
+                        Recovery::Reopen => continue,
+                        // the camera comes back with a new id, and its own listener
+                        Recovery::Replugged => break,
+                        Recovery::GiveUp => {
+                            // ignore errors because nothing to do
+                            let mut writer = writer.lock().unwrap();
+                            let _ = StreamResponse::Unavailable(device_id, e.to_string()).serialize_into(&mut *writer);
+                            let _ = StreamResponse::Stop(device_id).serialize_into(&mut *writer);
+                            break;
+                        },
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn spawn_armed_listener(
+    device_id: DeviceId,
+    ring: Arc<FrameRing>,
+    targets: PreviewTargets,
+    metrics: Metrics,
+    writer: Arc<Mutex<TcpStream>>,
+    should_stop: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        // armed cameras capture at snap resolution, so there's no need to reopen them for bigger
+        // previews
+        let mut shaper = PreviewShaper::new(targets.get(device_id));
+        let mut last_sent = None;
+        let mut sent_format = false;
+
+        while !should_stop.load(Ordering::Relaxed) {
+            if ring.is_stopped() {
+                println!("armed device {:?} stopped", device_id);
                 let _ = StreamResponse::Stop(device_id).serialize_into(&mut *writer.lock().unwrap());
+                break;
+            }
+
+            if !sent_format {
+                if let Some(format) = ring.format() {
+                    sent_format = true;
+                    let formats = CameraFormats { preview: format, snap: format };
+                    let _ = StreamResponse::Formats(device_id, formats).serialize_into(&mut *writer.lock().unwrap());
+                }
+            }
+
+            if let Some(frame) = ring.latest() {
+                if last_sent != Some(*frame.captured_at()) {
+                    last_sent = Some(*frame.captured_at());
+
+                    shaper.set_settings(targets.get(device_id));
+                    if let Some(frame) = shaper.shape(frame) {
+                        if StreamResponse::Frame(frame).serialize_into(&mut *writer.lock().unwrap()).is_err() {
+                            println!("tcp stream failed error in device {:?}", device_id);
+                            should_stop.store(true, Ordering::Relaxed);
+                        } else {
+                            metrics.count(&PREVIEW_FRAMES_SENT, &[("device", &device_id.to_string())], 1);
+                        }
+                    }
+                }
             }
+
+            thread::sleep(ARMED_PREVIEW_INTERVAL);
         }
-    });
+    })
 }
 
 fn stream_inner(
     device_index: DeviceFileIndex,
     device_id: DeviceId,
+    settings: StreamSettings,
+    targets: &PreviewTargets,
+    bandwidth: &BandwidthLedger,
+    metrics: &Metrics,
+    watch: &mut CameraWatch,
     writer: Arc<Mutex<TcpStream>>,
     should_stop: Arc<AtomicBool>,
 ) -> OrbitResult<()> {
@@ -69,26 +273,145 @@ fn stream_inner(
     let boot_time_utc = boot_time_utc();
 
     let mut device = CaptureDevice::new(device_index.file_index())?;
-    let used_format = device.set_format(&STREAM_FORMAT)?;
+    let preview_settings = targets.get(device_id);
+    let request = capture_request(preview_settings);
+    let (used_format, preview, _reservation) = negotiation::negotiate(&mut device, device_index, request, bandwidth)?;
+    // the previews are closed before snapping, so the snap gets the bus to itself. If several
+    // cameras share it they still have to split it, but that's the best guess we can make here
+    let snap = negotiation::choose(&device, SNAP_FORMAT, bandwidth.idle_allowance(device_index))?;
+    let formats = CameraFormats { preview, snap };
+    StreamResponse::Formats(device_id, formats).serialize_into(&mut *writer.lock().unwrap())?;
 
-    let stream = Stream::with_buffers(&device, 1)?;
+    let stream = Stream::with_buffers(&device, settings.buffer_count)?;
     let mut stream = stream.start()?;
 
+    let mut drops = DropCounter::new();
+    let mut shaper = PreviewShaper::new(preview_settings);
+    let device_label = device_id.to_string();
+
     println!("started streaming device {:?}", device_id);
     loop {
         if should_stop.load(Ordering::Relaxed) { break }
 
-        let frame = &stream.next()?;
+        let preview_settings = targets.get(device_id);
+        if preview_settings != shaper.settings() {
+            if capture_request(preview_settings) != request {
+                println!("reopening {:?} for its new preview settings", device_id);
+                return Ok(());
+            }
+            shaper.set_settings(preview_settings);
+        }
 
-        let frame = CapturedFrame::from_frame(frame, used_format, boot_time_utc, device_id);
+        let next = if settings.latest_frame_wins {
+            stream.latest()
+        } else {
+            stream.next().map(|frame| (frame, 0))
+        };
 
-        StreamResponse::Frame(frame).serialize_into(&mut *writer.lock().unwrap())?;
+        let (frame, skipped) = match next {
+            Ok(next) => next,
+            Err(e) if watch.keep_waiting(&e) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        watch.frame_arrived();
+
+        let frame = CapturedFrame::from_frame(&frame, used_format, boot_time_utc, device_id);
+        // frames skipped to keep to the preview frame rate weren't dropped
+        drops.count(frame.sequence(), skipped);
+
+        let frame = match shaper.shape(frame) {
+            Some(frame) => frame,
+            None => continue,
+        };
+
+        let mut writer = writer.lock().unwrap();
+        StreamResponse::Frame(frame).serialize_into(&mut *writer)?;
+        metrics.count(&PREVIEW_FRAMES_SENT, &[("device", &device_label)], 1);
+
+        if let Some(counts) = drops.report() {
+            StreamResponse::Dropped(device_id, counts).serialize_into(&mut *writer)?;
+            metrics.count(&PREVIEW_FRAMES_DROPPED, &[("device", &device_label), ("reason", "missed")], counts.missed as u64);
+            metrics.count(&PREVIEW_FRAMES_DROPPED, &[("device", &device_label), ("reason", "discarded")], counts.discarded as u64);
+        }
     }
     println!("stop stream {:?} requested", device_id);
 
     Ok(())
 }
 
+/// The formats each camera would stream with the default previews if nothing else on its bus
+/// were open. The cameras are only asked what they can do, so this doesn't get in the way of
+/// anything that's streaming from them
+pub fn list_devices(devices: &[(DeviceFileIndex, DeviceId)], bandwidth: &BandwidthLedger) -> DevicesResponse {
+    let mut cameras: Vec<(DeviceId, Result<CameraFormats, String>)> = devices.iter()
+        .map(|&(device_index, device_id)| {
+            let formats = CaptureDevice::new(device_index.file_index())
+                .and_then(|device| {
+                    let allowance = bandwidth.idle_allowance(device_index);
+                    let preview = negotiation::choose(&device, capture_request(PreviewSettings::default()), allowance)?;
+                    let snap = negotiation::choose(&device, SNAP_FORMAT, allowance)?;
+                    Ok(CameraFormats { preview, snap })
+                })
+                .map_err(|e| e.to_string());
+
+            (device_id, formats)
+        })
+        .collect();
+
+    cameras.sort_by_key(|&(device_id, _)| device_id);
+    DevicesResponse { cameras }
+}
+
+/// Cameras capture previews at `STREAM_FORMAT`, unless the previews should be bigger or faster than
+/// that
+fn capture_request(preview: PreviewSettings) -> FormatRequest {
+    FormatRequest {
+        width: STREAM_FORMAT.width.max(preview.width),
+        height: STREAM_FORMAT.height.max(preview.height),
+        fps: STREAM_FORMAT.fps.max(preview.fps),
+    }
+}
+
+/// Counts the frames a preview dropped since it last reported them
+struct DropCounter {
+    last_sequence: Option<u32>,
+    counts: DropCounts,
+    last_report: Instant,
+}
+
+impl DropCounter {
+    fn new() -> DropCounter {
+        DropCounter {
+            last_sequence: None,
+            counts: DropCounts::default(),
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Every frame the camera captured between the last one we sent and this one was dropped,
+    /// either by us skipping over it, or by the camera for lack of a free buffer
+    fn count(&mut self, sequence: u32, skipped: u32) {
+        if let Some(last_sequence) = self.last_sequence {
+            let gap = sequence.wrapping_sub(last_sequence).saturating_sub(1);
+            self.counts.missed += gap.saturating_sub(skipped);
+        }
+
+        self.counts.discarded += skipped;
+        self.last_sequence = Some(sequence);
+    }
+
+    /// Returns the counts every `DROP_REPORT_INTERVAL`, as long as something was dropped
+    fn report(&mut self) -> Option<DropCounts> {
+        if self.last_report.elapsed() < DROP_REPORT_INTERVAL { return None }
+        self.last_report = Instant::now();
+
+        let counts = std::mem::take(&mut self.counts);
+        if counts.missed == 0 && counts.discarded == 0 { return None }
+
+        Some(counts)
+    }
+}
+
 pub type OrbitResult<T> = Result<T, OrbitError>;
 
 pub enum OrbitError {
@@ -107,3 +430,66 @@ impl From<io::Error> for OrbitError {
         OrbitError::WebcamFailed(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts drops for the given sequence numbers and skips, then reports straight away
+    fn drops(frames: &[(u32, u32)]) -> Option<DropCounts> {
+        let mut drops = DropCounter::new();
+        for &(sequence, skipped) in frames {
+            drops.count(sequence, skipped);
+        }
+
+        drops.last_report -= DROP_REPORT_INTERVAL;
+        drops.report()
+    }
+
+    fn counts(counts: Option<DropCounts>) -> Option<(u32, u32)> {
+        counts.map(|counts| (counts.missed, counts.discarded))
+    }
+
+    #[test]
+    fn consecutive_frames_drop_nothing() {
+        assert_eq!(counts(drops(&[(7, 0), (8, 0), (9, 0)])), None);
+    }
+
+    #[test]
+    fn gaps_in_the_sequence_were_missed() {
+        assert_eq!(counts(drops(&[(7, 0), (10, 0), (11, 0), (15, 0)])), Some((5, 0)));
+    }
+
+    #[test]
+    fn skipped_frames_were_discarded_rather_than_missed() {
+        assert_eq!(counts(drops(&[(7, 0), (10, 2)])), Some((0, 2)));
+        assert_eq!(counts(drops(&[(7, 0), (12, 2)])), Some((2, 2)));
+    }
+
+    #[test]
+    fn the_first_frame_has_nothing_to_compare_to() {
+        assert_eq!(counts(drops(&[(100, 0)])), None);
+        assert_eq!(counts(drops(&[(100, 3)])), Some((0, 3)));
+    }
+
+    #[test]
+    fn gaps_across_wraparound_are_counted() {
+        assert_eq!(counts(drops(&[(u32::MAX - 1, 0), (u32::MAX, 0), (0, 0)])), None);
+        assert_eq!(counts(drops(&[(u32::MAX - 1, 0), (2, 0)])), Some((3, 0)));
+    }
+
+    #[test]
+    fn reports_wait_for_the_interval_and_start_over() {
+        let mut drops = DropCounter::new();
+        drops.count(1, 0);
+        drops.count(5, 0);
+        assert!(drops.report().is_none());
+
+        drops.last_report -= DROP_REPORT_INTERVAL;
+        assert_eq!(counts(drops.report()), Some((3, 0)));
+
+        drops.count(6, 0);
+        drops.last_report -= DROP_REPORT_INTERVAL;
+        assert_eq!(counts(drops.report()), None);
+    }
+}
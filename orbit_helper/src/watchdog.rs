@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::{fs, io, thread};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use orbit_types::{DeviceId, RecoveryAttempt};
+use orbit_metrics::{Metric, Metrics};
+
+use crate::{STALL_TIMEOUT, MAX_REOPENS, RECOVERY_SETTLE, PORT_RESET_COOLDOWN};
+use crate::hotplug::{DeviceChange, DeviceSubscribers};
+use crate::known_devices::DeviceFileIndex;
+use crate::bandwidth;
+
+// give a camera that just failed a moment before opening it again
+const REOPEN_DELAY: Duration = Duration::from_millis(500);
+// a camera that something else has open usually isn't for long, like while a snap opens every camera
+const BUSY_RETRY_DELAY: Duration = Duration::from_secs(1);
+// how long a USB port stays deauthorized or unbound before we bring it back
+const PORT_RESET_DELAY: Duration = Duration::from_millis(500);
+// each camera's frame rate is measured over this long
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+const FRAMES_CAPTURED: Metric = Metric {
+    name: "orbit_helper_frames_captured_total",
+    help: "Frames each camera delivered, for previews and while armed",
+};
+const RECOVERY_ATTEMPTS: Metric = Metric {
+    name: "orbit_helper_recovery_attempts_total",
+    help: "What was tried to get stalled or failed cameras going again",
+};
+
+/// Notices when cameras stop delivering frames, and decides how to get them going again. Cloning
+/// it shares the same history of port resets and frame rates
+#[derive(Clone)]
+pub struct Watchdog {
+    device_subscribers: DeviceSubscribers,
+    port_resets: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+    /// The latest frame rate of every watched camera, and when it was measured
+    frame_rates: Arc<Mutex<HashMap<DeviceId, (f32, Instant)>>>,
+    metrics: Metrics,
+}
+
+/// What a capture loop should do after its camera failed
+pub enum Recovery {
+    /// Open the camera again and keep going
+    Reopen,
+    /// The camera's USB port was reset, so it's about to be unplugged and plugged in again as a
+    /// new device. `hotplug` takes it from here
+    Replugged,
+    GiveUp,
+}
+
+/// Why a camera failed, as far as getting it going again goes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Failure {
+    /// The camera was unplugged, and `hotplug` already told everyone
+    Unplugged,
+    /// The camera didn't stall, there's just no room for it on the bus
+    NoBandwidth,
+    /// Something else has the camera open. Nothing is wrong with it, so it's only tried again
+    Busy,
+    /// Anything else might be the camera being stuck
+    Stalled,
+}
+
+impl Failure {
+    fn of(error: &io::Error) -> Failure {
+        match error.raw_os_error() {
+            Some(libc::ENODEV) => Failure::Unplugged,
+            Some(libc::EBUSY) => Failure::Busy,
+            _ if bandwidth::is_exceeded(error) => Failure::NoBandwidth,
+            _ => Failure::Stalled,
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn new(device_subscribers: DeviceSubscribers, metrics: Metrics) -> Watchdog {
+        Watchdog {
+            device_subscribers,
+            metrics,
+            port_resets: Arc::new(Mutex::new(HashMap::new())),
+            frame_rates: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn watch(&self, device_index: DeviceFileIndex, device_id: DeviceId) -> CameraWatch {
+        CameraWatch {
+            watchdog: self.clone(),
+            device_index,
+            device_id,
+            last_frame: Instant::now(),
+            reopens: 0,
+            last_recovery: None,
+            window_start: Instant::now(),
+            window_frames: 0,
+        }
+    }
+
+    /// How many frames per second each watched camera has been capturing. A camera that stalled
+    /// hasn't finished measuring in a while, so it's at 0
+    pub fn frame_rates(&self) -> Vec<(DeviceId, f32)> {
+        let mut frame_rates: Vec<_> = self.frame_rates.lock().unwrap().iter()
+            .map(|(&device_id, &(fps, measured_at))| {
+                let fps = if measured_at.elapsed() < FRAME_RATE_WINDOW * 2 { fps } else { 0.0 };
+                (device_id, fps)
+            })
+            .collect();
+
+        frame_rates.sort_by_key(|&(device_id, _)| device_id);
+        frame_rates
+    }
+
+    fn reset_port(&self, device_index: DeviceFileIndex) -> io::Result<()> {
+        let port = usb_port(device_index)?;
+
+        {
+            let mut port_resets = self.port_resets.lock().unwrap();
+            if matches!(port_resets.get(&port), Some(reset_at) if reset_at.elapsed() < PORT_RESET_COOLDOWN) {
+                return Err(io::Error::other("the port was reset recently and that didn't help"));
+            }
+            port_resets.insert(port.clone(), Instant::now());
+        }
+
+        reauthorize(&port).or_else(|e| {
+            println!("couldn't deauthorize {:?}, unbinding it instead: {:?}", port, e);
+            rebind(&port)
+        })
+    }
+}
+
+/// Keeps track of one camera for its capture loop
+pub struct CameraWatch {
+    watchdog: Watchdog,
+    device_index: DeviceFileIndex,
+    device_id: DeviceId,
+    last_frame: Instant,
+    reopens: u32,
+    last_recovery: Option<Instant>,
+    window_start: Instant,
+    window_frames: u32,
+}
+
+impl CameraWatch {
+    pub fn frame_arrived(&mut self) {
+        self.last_frame = Instant::now();
+        self.watchdog.metrics.count(&FRAMES_CAPTURED, &[("device", &self.device_id.to_string())], 1);
+
+        self.window_frames += 1;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= FRAME_RATE_WINDOW {
+            let fps = self.window_frames as f32 / elapsed.as_secs_f32();
+            self.watchdog.frame_rates.lock().unwrap().insert(self.device_id, (fps, Instant::now()));
+            self.window_start = Instant::now();
+            self.window_frames = 0;
+        }
+
+        if matches!(self.last_recovery, Some(recovered_at) if recovered_at.elapsed() >= RECOVERY_SETTLE) {
+            println!("{:?} recovered", self.device_id);
+            self.reopens = 0;
+            self.last_recovery = None;
+        }
+    }
+
+    /// Whether the capture loop should keep waiting for the next frame after this error. Polling
+    /// times out every second, but the camera only counts as stalled after `STALL_TIMEOUT`
+    pub fn keep_waiting(&self, error: &io::Error) -> bool {
+        error.kind() == io::ErrorKind::TimedOut && self.last_frame.elapsed() < STALL_TIMEOUT
+    }
+
+    /// Call when the camera couldn't be opened, stalled, or failed while streaming. Tries closing
+    /// and reopening it a few times, then resetting its USB port. A camera that something else
+    /// has open is only tried again a little later, however many times it takes
+    pub fn recover(&mut self, error: &io::Error) -> Recovery {
+        match Failure::of(error) {
+            Failure::Unplugged | Failure::NoBandwidth => return Recovery::GiveUp,
+            Failure::Busy => {
+                println!("{:?} {:?} is busy, trying again", self.device_index, self.device_id);
+                thread::sleep(BUSY_RETRY_DELAY);
+                self.last_frame = Instant::now();
+                return Recovery::Reopen;
+            },
+            Failure::Stalled => {},
+        }
+
+        self.last_recovery = Some(Instant::now());
+        // the reopened camera gets the whole stall timeout to deliver its first frame
+        self.last_frame = Instant::now();
+
+        if self.reopens < MAX_REOPENS {
+            self.reopens += 1;
+            self.report(RecoveryAttempt::Reopen);
+            thread::sleep(REOPEN_DELAY);
+            return Recovery::Reopen;
+        }
+
+        self.report(RecoveryAttempt::ResetPort);
+        match self.watchdog.reset_port(self.device_index) {
+            Ok(()) => Recovery::Replugged,
+            Err(e) => {
+                println!("couldn't reset the port of {:?}: {:?}", self.device_id, e);
+                self.report(RecoveryAttempt::GaveUp);
+                Recovery::GiveUp
+            },
+        }
+    }
+
+    fn report(&self, attempt: RecoveryAttempt) {
+        println!("{:?} {:?} failed, recovery attempt: {:?}", self.device_index, self.device_id, attempt);
+        let device = self.device_id.to_string();
+        let attempt_name = format!("{:?}", attempt);
+        self.watchdog.metrics.count(&RECOVERY_ATTEMPTS, &[("device", &device), ("attempt", &attempt_name)], 1);
+        self.watchdog.device_subscribers.broadcast(DeviceChange::Recovering(self.device_id, attempt));
+    }
+}
+
+impl Drop for CameraWatch {
+    fn drop(&mut self) {
+        // the camera's capture loop is done with it, so it doesn't have a frame rate anymore
+        self.watchdog.frame_rates.lock().unwrap().remove(&self.device_id);
+    }
+}
+
+/// The sysfs directory of the USB device the camera belongs to. It stays the same when the camera
+/// is plugged in again, as long as it's plugged into the same port
+fn usb_port(device_index: DeviceFileIndex) -> io::Result<PathBuf> {
+    let device = format!("/sys/class/video4linux/video{}/device", device_index.file_index());
+
+    // the link points at the camera's USB interface, and its parent is the USB device
+    let interface = fs::canonicalize(device)?;
+
+    interface.parent()
+        .filter(|port| port.join("authorized").exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the camera isn't a USB device"))
+}
+
+fn reauthorize(port: &Path) -> io::Result<()> {
+    let authorized = port.join("authorized");
+
+    fs::write(&authorized, "0")?;
+    thread::sleep(PORT_RESET_DELAY);
+    fs::write(&authorized, "1")
+}
+
+/// Detaches the USB device from its driver and attaches it again, for when deauthorizing it
+/// doesn't work
+fn rebind(port: &Path) -> io::Result<()> {
+    let name = port.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the USB port has no name"))?;
+
+    fs::write("/sys/bus/usb/drivers/usb/unbind", name)?;
+    thread::sleep(PORT_RESET_DELAY);
+    fs::write("/sys/bus/usb/drivers/usb/bind", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orbit_types::CameraFormat;
+    use crate::bandwidth::Allowance;
+
+    #[test]
+    fn unplugged_cameras() {
+        assert_eq!(Failure::of(&io::Error::from_raw_os_error(libc::ENODEV)), Failure::Unplugged);
+    }
+
+    #[test]
+    fn busy_cameras() {
+        assert_eq!(Failure::of(&io::Error::from_raw_os_error(libc::EBUSY)), Failure::Busy);
+    }
+
+    #[test]
+    fn cameras_without_bandwidth() {
+        let format = CameraFormat { width: 1920, height: 1080, fps: 30, fourcc: *b"YUYV" };
+        let error = Allowance { bytes_per_sec: Some(0) }.exceeded_by(format);
+
+        assert_eq!(Failure::of(&error), Failure::NoBandwidth);
+    }
+
+    #[test]
+    fn everything_else_might_be_a_stall() {
+        assert_eq!(Failure::of(&io::Error::from_raw_os_error(libc::EIO)), Failure::Stalled);
+        assert_eq!(Failure::of(&io::Error::from_raw_os_error(libc::EPROTO)), Failure::Stalled);
+        assert_eq!(Failure::of(&io::Error::new(io::ErrorKind::TimedOut, "no frame")), Failure::Stalled);
+        assert_eq!(Failure::of(&io::Error::other("something else")), Failure::Stalled);
+    }
+}
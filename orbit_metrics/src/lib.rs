@@ -0,0 +1,258 @@
+//! Just enough of Prometheus to graph the rig over a day: counters, gauges and summaries with
+//! labels, served as text at `/metrics` by a tiny HTTP server
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// requests are answered one at a time, so a client that stops sending or reading can only hold up
+// the others this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The name and description of a metric, which every binary declares as constants next to the
+/// code that updates it
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+/// Every metric a binary has updated so far. Cloning it shares the same metrics
+#[derive(Clone)]
+pub struct Metrics {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    /// By their labels, already formatted like `{device="camera1"}`
+    series: BTreeMap<String, Series>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    /// Only the sum and count of the observations, so the average can be graphed
+    Summary,
+}
+
+#[derive(Default)]
+struct Series {
+    value: f64,
+    count: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { families: Arc::new(Mutex::new(BTreeMap::new())) }
+    }
+
+    /// Adds to a counter, which only ever goes up
+    pub fn count(&self, metric: &Metric, labels: &[(&str, &str)], amount: u64) {
+        self.update(metric, Kind::Counter, labels, |series| series.value += amount as f64);
+    }
+
+    pub fn set(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, Kind::Gauge, labels, |series| series.value = value);
+    }
+
+    pub fn observe(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, Kind::Summary, labels, |series| {
+            series.value += value;
+            series.count += 1;
+        });
+    }
+
+    fn update(&self, metric: &Metric, kind: Kind, labels: &[(&str, &str)], f: impl FnOnce(&mut Series)) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(metric.name)
+            .or_insert_with(|| Family { help: metric.help, kind, series: BTreeMap::new() });
+
+        // a name can't be two kinds of metric, so the first one wins
+        if family.kind != kind { return }
+
+        f(family.series.entry(format_labels(labels)).or_default());
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        for (name, family) in self.families.lock().unwrap().iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Summary => "summary",
+            };
+            let _ = writeln!(text, "# HELP {} {}", name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+
+            for (labels, series) in family.series.iter() {
+                match family.kind {
+                    Kind::Counter | Kind::Gauge => {
+                        let _ = writeln!(text, "{}{} {}", name, labels, series.value);
+                    },
+                    Kind::Summary => {
+                        let _ = writeln!(text, "{}_sum{} {}", name, labels, series.value);
+                        let _ = writeln!(text, "{}_count{} {}", name, labels, series.count);
+                    },
+                }
+            }
+        }
+
+        text
+    }
+
+    /// Answers `GET /metrics` on `port` from a thread of its own, one request at a time
+    pub fn serve(&self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        let metrics = self.clone();
+
+        thread::spawn(move || {
+            for connection in listener.incoming().filter_map(Result::ok) {
+                if let Err(e) = metrics.respond(connection) {
+                    println!("couldn't answer a metrics request: {:?}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn respond(&self, connection: TcpStream) -> io::Result<()> {
+        connection.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        connection.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+        let mut reader = BufReader::new(connection.try_clone()?);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+
+        // the headers don't matter, but the client might not be happy if we don't read them
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            _ => ("404 Not Found", "try /metrics\n".to_string()),
+        };
+
+        let mut connection = connection;
+        write!(
+            connection,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body,
+        )
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() { return String::new() }
+
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAMES: Metric = Metric { name: "test_frames_total", help: "Frames" };
+    const TEMPERATURE: Metric = Metric { name: "test_temperature_celsius", help: "Temperature" };
+    const SECONDS: Metric = Metric { name: "test_seconds", help: "How long it took" };
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(format_labels(&[]), "");
+        assert_eq!(format_labels(&[("device", "camera1")]), r#"{device="camera1"}"#);
+        assert_eq!(format_labels(&[("a", "1"), ("b", "2")]), r#"{a="1",b="2"}"#);
+        assert_eq!(format_labels(&[("path", r#"C:\ "quoted""#)]), r#"{path="C:\\ \"quoted\""}"#);
+        assert_eq!(format_labels(&[("reason", "two\nlines")]), r#"{reason="two\nlines"}"#);
+    }
+
+    #[test]
+    fn counters_add_up() {
+        let metrics = Metrics::new();
+        metrics.count(&FRAMES, &[("device", "camera1")], 2);
+        metrics.count(&FRAMES, &[("device", "camera1")], 3);
+        metrics.count(&FRAMES, &[("device", "camera2")], 1);
+
+        assert_eq!(metrics.render(), "\
+# HELP test_frames_total Frames
+# TYPE test_frames_total counter
+test_frames_total{device=\"camera1\"} 5
+test_frames_total{device=\"camera2\"} 1
+");
+    }
+
+    #[test]
+    fn gauges_keep_the_last_value() {
+        let metrics = Metrics::default();
+        metrics.set(&TEMPERATURE, &[], 40.0);
+        metrics.set(&TEMPERATURE, &[], 42.5);
+
+        assert_eq!(metrics.render(), "\
+# HELP test_temperature_celsius Temperature
+# TYPE test_temperature_celsius gauge
+test_temperature_celsius 42.5
+");
+    }
+
+    #[test]
+    fn summaries_have_a_sum_and_a_count() {
+        let metrics = Metrics::new();
+        metrics.observe(&SECONDS, &[("mode", "armed")], 0.25);
+        metrics.observe(&SECONDS, &[("mode", "armed")], 0.5);
+
+        assert_eq!(metrics.render(), "\
+# HELP test_seconds How long it took
+# TYPE test_seconds summary
+test_seconds_sum{mode=\"armed\"} 0.75
+test_seconds_count{mode=\"armed\"} 2
+");
+    }
+
+    #[test]
+    fn families_are_sorted_and_keep_their_first_kind() {
+        let metrics = Metrics::new();
+        metrics.set(&TEMPERATURE, &[], 40.0);
+        metrics.count(&FRAMES, &[], 1);
+        // not a gauge, so it's left alone
+        metrics.set(&FRAMES, &[], 100.0);
+
+        let rendered = metrics.render();
+        assert!(rendered.find("test_frames_total").unwrap() < rendered.find("test_temperature_celsius").unwrap());
+        assert!(rendered.contains("test_frames_total 1\n"));
+        assert!(!rendered.contains("100"));
+    }
+
+    #[test]
+    fn clones_share_the_metrics() {
+        let metrics = Metrics::new();
+        metrics.clone().count(&FRAMES, &[], 1);
+
+        assert!(metrics.render().contains("test_frames_total 1\n"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use chrono::Duration;
+
+use orbit_types::{frame_jpeg, CapturedFrame};
+
+use crate::streams::{Streams, StreamSource};
+use crate::sync::select_synchronized;
+
+/// The frames of a burst as a matrix, with a row for each camera in the order of the tiles, and
+/// the frames of each row in the order they were captured
+pub struct BurstCapture {
+    cameras: Vec<(StreamSource, Vec<CapturedFrame>)>,
+}
+
+impl BurstCapture {
+    pub fn new(streams: &Streams, devices: Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)>) -> BurstCapture {
+        let mut cameras: Vec<_> = devices.into_iter()
+            .flat_map(|(addr, bursts)| bursts.into_iter()
+                .filter_map(move |frames| {
+                    let source = StreamSource::new(addr, frames.first()?.device_id());
+                    Some((source, frames))
+                }))
+            .filter(|&(source, _)| streams.get_stream_tile(source).is_some())
+            .collect();
+
+        cameras.sort_by_key(|&(source, _)| streams.get_stream_tile(source));
+
+        BurstCapture { cameras }
+    }
+
+    pub fn cameras(&self) -> impl Iterator<Item=(StreamSource, &[CapturedFrame])> + '_ {
+        self.cameras.iter().map(|(source, frames)| (*source, frames.as_slice()))
+    }
+
+    /// One frame from each camera, picked so that they were captured as close together as
+    /// possible, along with the time between the earliest and latest of them
+    pub fn synchronized(&self) -> Option<(Vec<(StreamSource, CapturedFrame)>, Duration)> {
+        let cameras: Vec<&(StreamSource, Vec<CapturedFrame>)> = self.cameras.iter()
+            .filter(|(_, frames)| !frames.is_empty())
+            .collect();
+
+        let times: Vec<Vec<_>> = cameras.iter()
+            .map(|(_, frames)| frames.iter().map(|frame| *frame.captured_at()).collect())
+            .collect();
+
+        let (indices, spread) = select_synchronized(&times)?;
+
+        let frames = cameras.into_iter().zip(indices)
+            .map(|(camera, index)| (camera.0, camera.1[index].clone()))
+            .collect();
+
+        Some((frames, spread))
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.cameras.iter().map(|(_, frames)| frames.len()).sum()
+    }
+
+    /// Saves every frame as `camXX/seqXXXXXX.jpg`, along with `timestamps.csv` that says when
+    /// each frame was captured
+    pub fn save(&self, dir: &Path, streams: &Streams) -> io::Result<()> {
+        let mut timestamps = io::BufWriter::new(fs::File::create(dir.join("timestamps.csv"))?);
+        writeln!(timestamps, "camera,sequence,captured_at")?;
+
+        for (source, frames) in self.cameras() {
+            let ordinal = match streams.get_stream_tile(source) {
+                Some(ordinal) => ordinal,
+                None => continue,
+            };
+
+            let camera_dir = dir.join(format!("cam{:02}", ordinal.index()));
+            fs::create_dir_all(&camera_dir)?;
+
+            for frame in frames {
+                fs::write(camera_dir.join(format!("seq{:06}.jpg", frame.sequence())), frame_jpeg(frame)?)?;
+                writeln!(timestamps, "{},{},{}", ordinal.index(), frame.sequence(), frame.captured_at().to_rfc3339())?;
+            }
+        }
+
+        timestamps.flush()
+    }
+}
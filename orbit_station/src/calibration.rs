@@ -1,13 +1,13 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
 
-use image::ImageFormat;
+use nalgebra::{Isometry3, Matrix3, Point3, Rotation3};
 
-use apriltag::{ApriltagDetector, EulerAngles};
-use orbit_types::CapturedFrame;
+use apriltag::{ApriltagDetector, ApriltagDetection, EulerAngles};
+use orbit_types::{CapturedFrame, CameraDetections, TagDetection};
 
-use crate::{FOCAL_LENGTH_PIXELS, STREAM_ASPECT_HEIGHT, STREAM_ASPECT_WIDTH, TAG_SIZE_METERS};
-use crate::picture::crop_rotate_scale;
+use crate::{FOCAL_LENGTH_CALIBRATION_WIDTH, FOCAL_LENGTH_PIXELS, STREAM_ASPECT_HEIGHT, STREAM_ASPECT_WIDTH, TAG_SIZE_METERS};
+use crate::picture::{crop_rotate_scale, decode_frame};
 use crate::streams::{Streams, StreamSource};
 
 #[derive(Debug, Copy, Clone)]
@@ -55,6 +55,7 @@ impl Adjustment {
 
 pub struct CalibrationEvent {
     includes_streams: HashMap<StreamSource, EulerAngles>,
+    camera_poses: HashMap<StreamSource, Isometry3<f64>>,
     average_pitch: Averager,
     average_roll: Averager,
 }
@@ -63,56 +64,92 @@ impl CalibrationEvent {
     pub fn new(
         devices: Vec<(SocketAddr, Vec<CapturedFrame>)>,
         apriltag_detector: &mut ApriltagDetector,
-        streams: &Streams,
+        streams: &mut Streams,
     ) -> CalibrationEvent {
-        let mut average_pitch = Averager::new();
-        let mut average_roll = Averager::new();
-        let mut includes_streams = HashMap::new();
+        let mut detections = Vec::new();
 
         for (socket_addr, stills) in devices {
             for still in stills {
                 let source = StreamSource::new(socket_addr, still.device_id());
 
-                let image = image::load_from_memory_with_format(
-                    still.frame_data(),
-                    ImageFormat::Jpeg,
-                );
-
-                if let Ok(image) = image {
-                    if let Some(ordinal) = streams.get_stream_tile(source) {
-                        // ^^ if we're in the condition where we get a frame that we've never seen
-                        // before, the cardinal rotation might be wrong and the whole calibration
-                        // will be messed up. Instead, we choose to ignore the frame
-
-                        let image = streams
-                            .cardinal_transform_image(ordinal, &image)
-                            .into_luma8();
-
-                        let detection = apriltag_detector.search(
-                            image.as_raw(),
-                            image.width(),
-                            image.height(),
-                            TAG_SIZE_METERS,
-                            FOCAL_LENGTH_PIXELS,
-                        );
-
-                        match detection.first() {
-                            Some(detection) => {
-                                let euler_angles = detection.euler_angles();
-
-                                average_pitch.add(euler_angles.pitch);
-                                average_roll.add(euler_angles.roll);
-                                includes_streams.insert(source, euler_angles);
-                            },
-                            None => {}, // TODO: display the ones that fail on the screen
-                        }
-                    }
+                // `from_detections` ignores cameras without a tile, so don't bother searching them
+                if streams.get_stream_tile(source).is_none() { continue }
+
+                if let Ok(image) = decode_frame(&still) {
+                    // search the untransformed image, the orientation of the tag tells us
+                    // the cardinal rotation
+                    let image = image.into_luma8();
+
+                    let detection = apriltag_detector.search(
+                        image.as_raw(),
+                        image.width(),
+                        image.height(),
+                        TAG_SIZE_METERS,
+                        focal_length_pixels(image.width()),
+                    );
+
+                    detections.push((source, detection.into_iter().next()));
                 }
             }
         }
 
-        CalibrationEvent { includes_streams, average_pitch, average_roll }
+        CalibrationEvent::from_detections(detections, streams)
     }
+
+    /// Like `new`, but the helpers already searched their stills for the tag
+    pub fn from_helper_detections(devices: Vec<(SocketAddr, Vec<CameraDetections>)>, streams: &mut Streams) -> CalibrationEvent {
+        let detections = devices.into_iter()
+            .flat_map(|(socket_addr, cameras)| cameras.into_iter().map(move |camera| {
+                let source = StreamSource::new(socket_addr, camera.device_id);
+                if let Some(ref error) = camera.error {
+                    println!("{:?} couldn't look for the tag: {}", source, error);
+                }
+                (source, camera.detections.first().map(from_tag_detection))
+            }))
+            .collect();
+
+        CalibrationEvent::from_detections(detections, streams)
+    }
+
+    /// Works out the calibration from the first tag each camera found, if it found one
+    fn from_detections(detections: Vec<(StreamSource, Option<ApriltagDetection>)>, streams: &mut Streams) -> CalibrationEvent {
+        let mut average_pitch = Averager::new();
+        let mut average_roll = Averager::new();
+        let mut includes_streams = HashMap::new();
+        let mut camera_poses = HashMap::new();
+
+        for (source, detection) in detections {
+            if let Some(ordinal) = streams.get_stream_tile(source) {
+                // ^^ if we get a frame from a stream that we've never seen before, there's
+                // no tile to store its cardinal rotation in, so we choose to ignore the frame
+
+                match detection {
+                    Some(detection) => {
+                        let euler_angles = streams.orient_from_detection(ordinal, &detection);
+
+                        average_pitch.add(euler_angles.pitch);
+                        average_roll.add(euler_angles.roll);
+                        includes_streams.insert(source, euler_angles);
+                        camera_poses.insert(source, detection.camera_pose());
+                    },
+                    None => {}, // TODO: display the ones that fail on the screen
+                }
+            }
+        }
+
+        CalibrationEvent { includes_streams, camera_poses, average_pitch, average_roll }
+    }
+
+    /// The pose of the camera relative to the tag, if the tag was found in its image
+    pub fn camera_pose(&self, stream: StreamSource) -> Option<Isometry3<f64>> {
+        self.camera_poses.get(&stream).copied()
+    }
+}
+
+/// The focal length of a camera whose images are `image_width` pixels wide. The cameras can
+/// negotiate different resolutions, but they all have the same lens
+pub fn focal_length_pixels(image_width: u32) -> f64 {
+    FOCAL_LENGTH_PIXELS * image_width as f64 / FOCAL_LENGTH_CALIBRATION_WIDTH as f64
 }
 
 #[derive(Copy, Clone)]
@@ -139,3 +176,22 @@ impl Averager {
         self.sum / self.measurement_count
     }
 }
+
+fn from_tag_detection(detection: &TagDetection) -> ApriltagDetection {
+    let [row0, row1, row2] = detection.rotation;
+    let rotation = Matrix3::new(
+        row0[0], row0[1], row0[2],
+        row1[0], row1[1], row1[2],
+        row2[0], row2[1], row2[2],
+    );
+    let [x, y, z] = detection.translation;
+
+    ApriltagDetection::from_parts(
+        detection.error,
+        detection.tag_id,
+        detection.image_center,
+        detection.image_tag_corners,
+        Rotation3::from_matrix_unchecked(rotation),
+        Point3::new(x, y, z),
+    )
+}
@@ -0,0 +1,50 @@
+use std::collections::{BTreeMap, HashMap};
+
+use orbit_types::{CameraFormat, CameraFormats};
+
+use crate::streams::StreamSource;
+
+/// The formats every camera ended up with. The cameras in a rig should all capture at the same
+/// resolution, so we warn when one of them couldn't
+pub struct CameraFormatTracker {
+    formats: HashMap<StreamSource, CameraFormats>,
+}
+
+impl CameraFormatTracker {
+    pub fn new() -> CameraFormatTracker {
+        CameraFormatTracker { formats: HashMap::new() }
+    }
+
+    pub fn update(&mut self, source: StreamSource, formats: CameraFormats) {
+        if self.formats.get(&source) == Some(&formats) { return }
+
+        println!("{:?} previews at {} and snaps at {}", source, describe(formats.preview), describe(formats.snap));
+        self.formats.insert(source, formats);
+
+        self.warn_about_mismatches();
+    }
+
+    pub fn remove(&mut self, source: StreamSource) {
+        self.formats.remove(&source);
+    }
+
+    fn warn_about_mismatches(&self) {
+        let mut cameras_by_resolution: BTreeMap<(u32, u32), Vec<StreamSource>> = BTreeMap::new();
+        for (&source, formats) in self.formats.iter() {
+            cameras_by_resolution.entry((formats.snap.width, formats.snap.height))
+                .or_default()
+                .push(source);
+        }
+
+        if cameras_by_resolution.len() < 2 { return }
+
+        println!("warning: the cameras snap at {} different resolutions", cameras_by_resolution.len());
+        for ((width, height), sources) in cameras_by_resolution {
+            println!("    {}x{}: {:?}", width, height, sources);
+        }
+    }
+}
+
+fn describe(format: CameraFormat) -> String {
+    format!("{}x{} {} at {}fps", format.width, format.height, String::from_utf8_lossy(&format.fourcc), format.fps)
+}
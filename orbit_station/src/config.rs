@@ -0,0 +1,158 @@
+use std::fs;
+
+use serde::Deserialize;
+use orbit_types::{StreamSettings, PreviewSettings};
+
+use crate::helper_status::HealthThresholds;
+
+const CONFIG_PATH: &str = "orbit_station.toml";
+
+/// Settings that operators might want to change without rebuilding. Anything missing from
+/// `orbit_station.toml` gets its default value
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Cameras that capture further than this from the requested time are flagged
+    pub sync_tolerance_millis: f64,
+    /// How many more times to ask helpers that left cameras out of a capture
+    pub capture_retries: u32,
+    /// How to fill in cameras that are missing from a video the operator chose to render anyway
+    pub gap_fill: GapFill,
+    /// How many buffers each camera fills while the helper is busy sending the previous frame
+    pub stream_buffers: u32,
+    /// Previews always show the newest frame, dropping older ones if the connection can't keep up
+    pub latest_frame_wins: bool,
+    /// Helpers scale previews down to fit in this size before sending them
+    pub preview_width: u32,
+    pub preview_height: u32,
+    /// How many preview frames each camera sends per second at most
+    pub preview_fps: u32,
+    /// The JPEG quality of previews that had to be scaled down, from 1 to 100
+    pub preview_quality: u8,
+    /// The previews of the camera the operator focuses on with F, for checking framing and focus
+    pub focus_preview_width: u32,
+    pub focus_preview_height: u32,
+    pub focus_preview_fps: u32,
+    pub focus_preview_quality: u8,
+    /// Calibrating has the helpers search their stills for the tag, instead of sending the stills
+    /// over for us to search one at a time
+    pub detect_tags_on_helpers: bool,
+    /// Videos are first made from thumbnails of the stills, so they can be looked at right away,
+    /// and made again from the full resolution stills once those have been fetched
+    pub thumbnail_snaps: bool,
+    pub thumbnail_width: u32,
+    pub thumbnail_height: u32,
+    pub thumbnail_quality: u8,
+    /// Helpers past any of these are flagged in the window title and the fleet view
+    pub alert_cpu_temp_celsius: f32,
+    /// Of the 1 minute load average. The NanoPi has four cores
+    pub alert_load: f32,
+    pub alert_memory_used_percent: f32,
+    pub alert_swap_used_mb: f32,
+    /// Flagged below these instead
+    pub alert_disk_free_mb: f32,
+    pub alert_camera_fps: f32,
+    /// Serve Prometheus metrics at `/metrics` on this port
+    pub metrics_port: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum GapFill {
+    /// Repeat the frame from the camera before the missing one
+    Hold,
+    /// Blend the frames from the cameras on either side of the missing one
+    Interpolate,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            sync_tolerance_millis: 20.0,
+            capture_retries: 1,
+            gap_fill: GapFill::Hold,
+            stream_buffers: 3,
+            latest_frame_wins: true,
+            preview_width: 640,
+            preview_height: 360,
+            preview_fps: 30,
+            preview_quality: 80,
+            focus_preview_width: 1280,
+            focus_preview_height: 720,
+            focus_preview_fps: 30,
+            focus_preview_quality: 90,
+            detect_tags_on_helpers: false,
+            thumbnail_snaps: false,
+            thumbnail_width: 320,
+            thumbnail_height: 180,
+            thumbnail_quality: 70,
+            alert_cpu_temp_celsius: 75.0,
+            alert_load: 4.0,
+            alert_memory_used_percent: 90.0,
+            alert_swap_used_mb: 64.0,
+            alert_disk_free_mb: 1024.0,
+            alert_camera_fps: 20.0,
+            metrics_port: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Config {
+        match fs::read_to_string(CONFIG_PATH) {
+            Ok(contents) => match toml::from_str(&contents) {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("couldn't parse {}, using the default config: {}", CONFIG_PATH, e);
+                    Config::default()
+                },
+            },
+            Err(_) => Config::default(),
+        }
+    }
+
+    pub fn stream_settings(&self) -> StreamSettings {
+        StreamSettings {
+            buffer_count: self.stream_buffers.max(1),
+            latest_frame_wins: self.latest_frame_wins,
+            preview: preview_settings(self.preview_width, self.preview_height, self.preview_fps, self.preview_quality),
+        }
+    }
+
+    pub fn focus_preview_settings(&self) -> PreviewSettings {
+        preview_settings(
+            self.focus_preview_width,
+            self.focus_preview_height,
+            self.focus_preview_fps,
+            self.focus_preview_quality,
+        )
+    }
+
+    /// How the helpers scale down the stills of a video, or `None` if they send them as they are
+    pub fn thumbnail_settings(&self) -> Option<PreviewSettings> {
+        if !self.thumbnail_snaps { return None }
+
+        // thumbnails aren't a stream, so there's no frame rate
+        Some(preview_settings(self.thumbnail_width, self.thumbnail_height, 1, self.thumbnail_quality))
+    }
+
+    pub fn health_thresholds(&self) -> HealthThresholds {
+        HealthThresholds {
+            cpu_temp_celsius: self.alert_cpu_temp_celsius,
+            load: self.alert_load,
+            memory_used_percent: self.alert_memory_used_percent,
+            swap_used_mb: self.alert_swap_used_mb,
+            disk_free_mb: self.alert_disk_free_mb,
+            camera_fps: self.alert_camera_fps,
+        }
+    }
+}
+
+fn preview_settings(width: u32, height: u32, fps: u32, quality: u8) -> PreviewSettings {
+    PreviewSettings {
+        width: width.max(1),
+        height: height.max(1),
+        fps: fps.max(1),
+        quality: quality.clamp(1, 100),
+    }
+}
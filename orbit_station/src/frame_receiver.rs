@@ -1,110 +1,892 @@
-use image::{RgbImage, ImageFormat};
-use std::net::{SocketAddr, TcpStream};
-use orbit_types::{CapturedFrame, Request, StreamResponse, SnapResponse};
-use std::sync::mpsc::Sender;
+use image::RgbImage;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use orbit_types::{CapturedFrame, DeviceId, Request, StreamResponse, StreamSettings, CameraFormats, PreviewSettings};
+use orbit_types::{CameraDetections, TagSearch, CaptureId, HelperHealth};
+use orbit_metrics::{Metric, Metrics};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::{thread, io};
 use std::io::BufReader;
-use chrono::Utc;
-use crate::STILL_CAPTURE_DELAY_MILLIS;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use crate::{STILL_CAPTURE_DELAY_MILLIS, RETROACTIVE_CAPTURE_MILLIS, BURST_DURATION_MILLIS, BURST_MAX_FRAMES, SYNC_SEARCH_MILLIS, SYNC_SEARCH_MAX_FRAMES};
+use crate::{TAG_SIZE_METERS, FOCAL_LENGTH_PIXELS, FOCAL_LENGTH_CALIBRATION_WIDTH, RECORDING_FETCH_MAX_FRAMES};
 use crate::streams::StreamSource;
-use crate::state::{PictureEventState, PictureEvent};
+use crate::state::{PictureEventState, PictureEvent, CaptureKind};
+use crate::sync::{synchronize_bursts, spread, format_spread, LatencyCorrections};
+use crate::helper_status::HelperStatus;
+use crate::picture::decode_frame;
+
+// how often the capture loop checks for new picture events
+const CAPTURE_LOOP_POLL: Duration = Duration::from_millis(10);
+// how long we wait for the helpers to answer a snap or a burst before giving up on them
+const HELPER_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+// connecting happens in the background, but don't tie up a thread for a helper that isn't there
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// after a helper fails, we wait this long before reconnecting, doubling every time it fails again
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+// originals are fetched between captures, so each piece should be quick to send
+const ORIGINAL_CHUNK_BYTES: u32 = 256 * 1024;
+
+const FRAMES_RECEIVED: Metric = Metric {
+    name: "orbit_station_frames_received_total",
+    help: "Preview frames received from each camera",
+};
+const DECODE_FAILURES: Metric = Metric {
+    name: "orbit_station_decode_failures_total",
+    help: "Preview frames that couldn't be decoded",
+};
+const FRAMES_DROPPED: Metric = Metric {
+    name: "orbit_station_frames_dropped_total",
+    help: "Preview frames the helpers reported as missed for lack of a buffer, or discarded for a newer one",
+};
+const RECONNECTS: Metric = Metric {
+    name: "orbit_station_reconnects_total",
+    help: "Times a helper was connected to again after losing the connection",
+};
+const CAPTURE_SECONDS: Metric = Metric {
+    name: "orbit_station_capture_seconds",
+    help: "How long captures took, from the request to every helper's answer",
+};
+const SYNC_SPREAD_SECONDS: Metric = Metric {
+    name: "orbit_station_sync_spread_seconds",
+    help: "How far apart the first and last camera captured their stills",
+};
+const CAPTURE_OFFSET_SECONDS: Metric = Metric {
+    name: "orbit_station_capture_offset_seconds",
+    help: "How far from the requested time each camera captured its latest still",
+};
 
 pub enum Message {
     StreamDeregistered(StreamSource),
     NewImage(StreamSource, RgbImage),
-    Stills(PictureEvent, Vec<(SocketAddr, Vec<CapturedFrame>)>),
+    /// The stills from each helper, along with the time they were requested for
+    Stills(PictureEvent, DateTime<Utc>, Vec<(SocketAddr, Vec<CapturedFrame>)>),
+    /// The full resolution stills from each helper, for a capture that first sent thumbnails
+    Originals(PictureEvent, Vec<(SocketAddr, Vec<CapturedFrame>)>),
+    /// For each helper, the frames of each of its cameras
+    Burst(PictureEvent, Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)>),
+    /// The tags each helper found in the stills of its cameras
+    Detections(PictureEvent, Vec<(SocketAddr, Vec<CameraDetections>)>),
+    /// For each helper, the recorded frames of each of its cameras
+    Recording(Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)>),
+    HelperStatus(SocketAddr, HelperStatus),
+    /// How a helper is holding up, which it reports every few seconds
+    HelperHealth(SocketAddr, HelperHealth),
+    CameraFormats(StreamSource, CameraFormats),
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    ConnectFailed(io::Error),
+    RequestFailed(bincode::Error),
+    ConnectionLost(bincode::Error),
+    Disconnected,
+    TimedOut,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::ConnectFailed(e) => write!(f, "couldn't connect: {}", e),
+            SessionError::RequestFailed(e) => write!(f, "couldn't send request: {}", e),
+            SessionError::ConnectionLost(e) => write!(f, "lost connection: {}", e),
+            SessionError::Disconnected => write!(f, "connection closed"),
+            SessionError::TimedOut => write!(f, "didn't respond in time"),
+        }
+    }
 }
 
-pub fn spawn_capture_loop(addrs: Vec<SocketAddr>, message_sender: Sender<Message>, picture_event_state: PictureEventState) {
+pub fn spawn_capture_loop(
+    addrs: Vec<SocketAddr>,
+    message_sender: Sender<Message>,
+    picture_event_state: PictureEventState,
+    latency_corrections: LatencyCorrections,
+    capture_retries: u32,
+    stream_settings: StreamSettings,
+    focus_preview: PreviewSettings,
+    metrics: Metrics,
+) {
     thread::spawn(move || {
+        let mut sessions: Vec<HelperSession> = addrs.iter()
+            .map(|&socket_addr| HelperSession::new(socket_addr, stream_settings, focus_preview, message_sender.clone(), metrics.clone()))
+            .collect();
+
+        let mut armed = false;
+        let mut last_event = picture_event_state.current_event();
+        let mut originals = OriginalTransfers::new();
+
         loop {
-            // streaming mode
-            let last_event = picture_event_state.current_event();
-
-            let stream_handles: Vec<_> = addrs.iter()
-                .map(|&socket_addr| {
-                    let message_sender = message_sender.clone();
-                    let picture_event_state = picture_event_state.clone();
-                    thread::spawn(move || stream(socket_addr, &message_sender, last_event, picture_event_state))
-                })
-                .collect();
-
-            for handle in stream_handles {
-                handle.join().unwrap().unwrap(); // TODO: handle properly
+            thread::sleep(CAPTURE_LOOP_POLL);
+
+            let focused = picture_event_state.focused();
+            let recording = picture_event_state.is_recording();
+            for session in sessions.iter_mut() {
+                session.maintain(armed);
+                session.focus(focused);
+                session.record(recording);
+                session.correct_latencies(latency_corrections.for_helper(session.socket_addr));
             }
 
-            // still frame mode
-            let shutter_handles: Vec<_> = addrs.iter()
-                .map(|&socket_addr| thread::spawn(move || shutter(socket_addr)))
-                .collect();
+            if picture_event_state.is_armed() != armed {
+                armed = !armed;
+                let request = if armed { Request::Arm } else { Request::Disarm };
+                for session in sessions.iter_mut() {
+                    session.send(&request);
+                }
+            }
 
-            let mut stills = Vec::new();
-            for (handle, &socket_addr) in shutter_handles.into_iter().zip(addrs.iter()) {
-                if let Ok(snap_response) = handle.join().unwrap() {
-                    stills.push((socket_addr, snap_response.stills));
+            if !picture_event_state.has_been_new_event_since(last_event) {
+                // nothing to capture, so carry on fetching originals
+                if let Some(message) = originals.fetch_next(&mut sessions, &latency_corrections) {
+                    if message_sender.send(message).is_err() { break }
                 }
+                continue;
+            }
+
+            let event = last_event;
+            last_event = picture_event_state.current_event();
+
+            let request = picture_event_state.latest_request();
+
+            let requested_capture_time = if armed {
+                // the helpers have been buffering, so we can go back to when the key was pressed
+                request.requested_at - chrono::Duration::milliseconds(RETROACTIVE_CAPTURE_MILLIS)
+            } else {
+                Utc::now() + chrono::Duration::milliseconds(STILL_CAPTURE_DELAY_MILLIS)
+            };
+            println!("requested a {:?} at {:?}", request.kind, requested_capture_time);
+            let started = Instant::now();
+
+            let message = match request.kind {
+                CaptureKind::Still => {
+                    let stills = if armed {
+                        // the cameras are already running, so we can look at all of the frames
+                        // around the requested time and pick the ones that line up best
+                        let window = chrono::Duration::milliseconds(SYNC_SEARCH_MILLIS);
+                        let start = requested_capture_time - window;
+                        let end = requested_capture_time + window;
+                        let bursts = capture_bursts(&mut sessions, armed, start, end, SYNC_SEARCH_MAX_FRAMES, capture_retries, &latency_corrections);
+                        synchronize_bursts(bursts)
+                    } else {
+                        capture_stills(&mut sessions, armed, requested_capture_time, capture_retries, &latency_corrections, Request::Snap)
+                    };
+
+                    let all_stills = stills.iter().flat_map(|(_, stills)| stills.iter());
+                    println!("captured stills with a spread of {}", format_spread(spread(all_stills)));
+
+                    Message::Stills(event, requested_capture_time, stills)
+                },
+                CaptureKind::Thumbnails(thumbnail) => {
+                    // unique across restarts of the station, since the helpers keep their originals
+                    let capture_id = CaptureId(Utc::now().timestamp_nanos() as u64);
+                    let snap = |target_time| Request::SnapThumbnails { target_time, capture_id, thumbnail };
+                    let stills = capture_stills(&mut sessions, armed, requested_capture_time, capture_retries, &latency_corrections, snap);
+
+                    originals.expect(event, capture_id, &stills);
+                    Message::Stills(event, requested_capture_time, stills)
+                },
+                CaptureKind::Burst => {
+                    let end = requested_capture_time + chrono::Duration::milliseconds(BURST_DURATION_MILLIS);
+                    let bursts = capture_bursts(&mut sessions, armed, requested_capture_time, end, BURST_MAX_FRAMES, capture_retries, &latency_corrections);
+                    Message::Burst(event, bursts)
+                },
+                CaptureKind::Detection => {
+                    let detections = capture_detections(&mut sessions, armed, requested_capture_time, capture_retries);
+                    Message::Detections(event, detections)
+                },
+                CaptureKind::Recording { start, end } => {
+                    Message::Recording(fetch_recordings(&mut sessions, armed, start, end, capture_retries, &latency_corrections))
+                },
+            };
+
+            metrics.observe(&CAPTURE_SECONDS, &[("kind", request.kind.name())], started.elapsed().as_secs_f64());
+            if let Message::Stills(_, requested_at, ref stills) = message {
+                record_timing(&metrics, requested_at, stills);
             }
-            message_sender.send(Message::Stills(last_event, stills)).unwrap();
+
+            // the window has been closed
+            if message_sender.send(message).is_err() { break }
         }
     });
 }
 
-fn stream(
+fn record_timing(metrics: &Metrics, requested_at: DateTime<Utc>, stills: &[(SocketAddr, Vec<CapturedFrame>)]) {
+    let all_stills = stills.iter().flat_map(|(_, stills)| stills.iter());
+    if let Some(spread) = spread(all_stills) {
+        metrics.observe(&SYNC_SPREAD_SECONDS, &[], seconds(spread));
+    }
+
+    for (socket_addr, stills) in stills {
+        let helper = socket_addr.to_string();
+        for still in stills {
+            let offset = seconds(*still.captured_at() - requested_at);
+            metrics.set(&CAPTURE_OFFSET_SECONDS, &[("helper", &helper), ("device", &still.device_id().to_string())], offset);
+        }
+    }
+}
+
+fn seconds(duration: chrono::Duration) -> f64 {
+    duration.num_microseconds().unwrap_or(i64::MAX) as f64 / 1_000_000.0
+}
+
+/// Our side of a session with one helper. When the connection fails, we keep trying to reconnect in
+/// the background, waiting longer after every failure, so the rest of the rig keeps working
+struct HelperSession {
     socket_addr: SocketAddr,
-    message_sender: &Sender<Message>,
-    last_event: PictureEvent,
-    picture_event_state: PictureEventState,
-) -> io::Result<()> {
-    let mut connection = TcpStream::connect(socket_addr)?;
+    stream_settings: StreamSettings,
+    /// The previews of the camera the operator is focusing on
+    focus_preview: PreviewSettings,
+    /// The camera the operator is focusing on, if it's one of ours
+    focused: Option<DeviceId>,
+    /// Whether the helper should be writing what its cameras capture to disk
+    recording: bool,
+    /// The latencies the helper was last told about, in microseconds
+    latencies: Vec<(DeviceId, i64)>,
+    message_sender: Sender<Message>,
+    metrics: Metrics,
+    connection: Option<Connection>,
+    /// The connection being opened in the background, so a helper that's slow to answer doesn't
+    /// hold up the captures of the others
+    connecting: Option<Receiver<Result<Connection, SessionError>>>,
+    /// Whether we've been connected before, so the next connection is a reconnect
+    has_connected: bool,
+    backoff: Duration,
+    reconnect_at: Instant,
+}
 
-    bincode::serialize_into(
-        &mut connection,
-        &Request::Stream,
-    ).unwrap();
+/// A connection that carries the previews from the helper, with our snap and burst requests sent
+/// over it in between
+struct Connection {
+    writer: TcpStream,
+    /// The responses to our requests, separated out from the previews
+    responses: Receiver<Result<StreamResponse, SessionError>>,
+    /// The cameras the helper is sending previews of, which should all be in every capture
+    devices: Arc<Mutex<HashSet<DeviceId>>>,
+}
 
-    let mut connection = BufReader::new(connection);
+impl HelperSession {
+    fn new(
+        socket_addr: SocketAddr,
+        stream_settings: StreamSettings,
+        focus_preview: PreviewSettings,
+        message_sender: Sender<Message>,
+        metrics: Metrics,
+    ) -> HelperSession {
+        HelperSession {
+            socket_addr,
+            stream_settings,
+            focus_preview,
+            focused: None,
+            recording: false,
+            latencies: Vec::new(),
+            message_sender,
+            metrics,
+            connection: None,
+            connecting: None,
+            has_connected: false,
+            backoff: INITIAL_RECONNECT_BACKOFF,
+            reconnect_at: Instant::now(),
+        }
+    }
 
-    loop {
-        if picture_event_state.has_been_new_event_since(last_event) { break Ok(()) }
+    /// Notices when the connection has been lost, and reconnects once we've waited long enough
+    fn maintain(&mut self, armed: bool) {
+        self.check_connection();
 
-        let response = StreamResponse::deserialize_from(&mut connection).unwrap();
+        if self.connection.is_some() { return }
 
-        match response {
-            StreamResponse::Stop(device_id) => {
-                let stream_id = StreamSource::new(socket_addr, device_id);
-                message_sender.send(Message::StreamDeregistered(stream_id)).unwrap();
+        let opened = match self.connecting {
+            Some(ref connecting) => match connecting.try_recv() {
+                Ok(opened) => opened,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Err(SessionError::Disconnected),
             },
-            StreamResponse::Frame(frame) => {
-                let stream_id = StreamSource::new(socket_addr, frame.device_id());
+            None => {
+                if Instant::now() >= self.reconnect_at { self.connect() }
+                return;
+            },
+        };
+        self.connecting = None;
 
-                let image = image::load_from_memory_with_format(
-                    frame.frame_data(),
-                    ImageFormat::Jpeg,
-                );
+        match opened {
+            Ok(connection) => {
+                if self.has_connected {
+                    self.metrics.count(&RECONNECTS, &[("helper", &self.socket_addr.to_string())], 1);
+                }
+                self.has_connected = true;
+                self.connection = Some(connection);
+                self.backoff = INITIAL_RECONNECT_BACKOFF;
+                self.report(HelperStatus::Streaming);
 
-                if let Ok(image) = image {
-                    let image = image.into_rgb8();
-                    message_sender.send(Message::NewImage(stream_id, image)).unwrap();
+                // the helper might have restarted since it was last armed or focused on
+                if armed { self.send(&Request::Arm) }
+                if let Some(device_id) = self.focused {
+                    self.send(&Request::Preview(device_id, Some(self.focus_preview)));
                 }
+                if self.recording { self.send(&Request::StartRecording) }
+                if !self.latencies.is_empty() { self.send(&Request::Latencies(self.latencies.clone())) }
+                // rather than waiting for the first report
+                self.send(&Request::Status);
             },
+            Err(e) => self.fail(e),
         }
     }
+
+    fn connect(&mut self) {
+        self.report(HelperStatus::Connecting);
+
+        let (sender, connecting) = mpsc::channel();
+        let (socket_addr, stream_settings) = (self.socket_addr, self.stream_settings);
+        let (message_sender, metrics) = (self.message_sender.clone(), self.metrics.clone());
+        thread::spawn(move || {
+            let _ = sender.send(Connection::open(socket_addr, stream_settings, message_sender, metrics));
+        });
+
+        self.connecting = Some(connecting);
+    }
+
+    /// Puts the previously focused camera back to the normal previews, and boosts the newly
+    /// focused one, for whichever of them are on this helper
+    fn focus(&mut self, focused: Option<StreamSource>) {
+        let focused = focused
+            .filter(|source| source.socket_addr() == self.socket_addr)
+            .map(|source| source.device_id());
+
+        if focused == self.focused { return }
+
+        if let Some(device_id) = self.focused {
+            self.send(&Request::Preview(device_id, None));
+        }
+        if let Some(device_id) = focused {
+            self.send(&Request::Preview(device_id, Some(self.focus_preview)));
+        }
+
+        self.focused = focused;
+    }
+
+    fn record(&mut self, recording: bool) {
+        if recording == self.recording { return }
+
+        self.send(if recording { &Request::StartRecording } else { &Request::StopRecording });
+        self.recording = recording;
+    }
+
+    /// Tells the helper when the latencies of its cameras have been measured again
+    fn correct_latencies(&mut self, latencies: Vec<(DeviceId, i64)>) {
+        if latencies == self.latencies { return }
+
+        self.send(&Request::Latencies(latencies.clone()));
+        self.latencies = latencies;
+    }
+
+    /// Nothing should arrive between captures except the late answer to a request we already gave
+    /// up on, which we throw away, or the reason the connection broke
+    fn check_connection(&mut self) {
+        loop {
+            let received = match self.connection {
+                Some(ref connection) => connection.responses.try_recv(),
+                None => return,
+            };
+
+            match received {
+                Ok(Ok(_)) => {},
+                Ok(Err(e)) => return self.fail(e),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => return self.fail(SessionError::Disconnected),
+            }
+        }
+    }
+
+    fn send(&mut self, request: &Request) {
+        self.check_connection();
+
+        let result = match self.connection {
+            Some(ref mut connection) => bincode::serialize_into(&mut connection.writer, request),
+            None => return,
+        };
+
+        if let Err(e) = result {
+            self.fail(SessionError::RequestFailed(e));
+        }
+    }
+
+    /// Gives up on the helper if it hasn't answered by `deadline`
+    fn wait_for_response(&mut self, deadline: Instant) -> Option<StreamResponse> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let result = match self.connection {
+            Some(ref connection) => connection.responses.recv_timeout(timeout),
+            None => return None,
+        };
+
+        match result {
+            Ok(Ok(response)) => Some(response),
+            Ok(Err(e)) => {
+                self.fail(e);
+                None
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                self.fail(SessionError::TimedOut);
+                None
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                self.fail(SessionError::Disconnected);
+                None
+            },
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    fn fail(&mut self, error: SessionError) {
+        self.report(HelperStatus::Failed(error.to_string()));
+
+        if let Some(connection) = self.connection.take() {
+            // wakes up the thread receiving the previews, so it can clean up
+            let _ = connection.writer.shutdown(Shutdown::Both);
+        }
+
+        self.reconnect_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+
+    fn streaming_devices(&self) -> HashSet<DeviceId> {
+        match self.connection {
+            Some(ref connection) => connection.devices.lock().unwrap().clone(),
+            None => HashSet::new(),
+        }
+    }
+
+    fn report(&self, status: HelperStatus) {
+        let _ = self.message_sender.send(Message::HelperStatus(self.socket_addr, status));
+    }
 }
 
-fn shutter(socket_addr: SocketAddr) -> io::Result<SnapResponse> {
-    let mut connection = TcpStream::connect(socket_addr)?;
+impl Connection {
+    fn open(
+        socket_addr: SocketAddr,
+        stream_settings: StreamSettings,
+        message_sender: Sender<Message>,
+        metrics: Metrics,
+    ) -> Result<Connection, SessionError> {
+        let mut writer = TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT)
+            .map_err(SessionError::ConnectFailed)?;
+
+        bincode::serialize_into(&mut writer, &Request::Stream(stream_settings))
+            .map_err(SessionError::RequestFailed)?;
 
-    let requested_capture_time = Utc::now() + chrono::Duration::milliseconds(STILL_CAPTURE_DELAY_MILLIS);
-    println!("requested a frame at {:?}", requested_capture_time);
+        let reader = writer.try_clone().map_err(SessionError::ConnectFailed)?;
+        let (response_sender, responses) = mpsc::channel();
+        let devices = Arc::new(Mutex::new(HashSet::new()));
+
+        {
+            let devices = Arc::clone(&devices);
+            thread::spawn(move || receive(socket_addr, BufReader::new(reader), &devices, &message_sender, &response_sender, &metrics));
+        }
+
+        Ok(Connection { writer, responses, devices })
+    }
+}
 
-    bincode::serialize_into(
-        &mut connection,
-        &Request::Snap(requested_capture_time),
-    ).unwrap();
+/// `snap` makes the request for the stills at a given time
+fn capture_stills(
+    sessions: &mut [HelperSession],
+    armed: bool,
+    requested_capture_time: DateTime<Utc>,
+    retries: u32,
+    latency_corrections: &LatencyCorrections,
+    snap: impl Fn(DateTime<Utc>) -> Request,
+) -> Vec<(SocketAddr, Vec<CapturedFrame>)> {
+    let request = |attempt| if attempt == 0 || armed {
+        // armed helpers still have the requested time in their ring buffers
+        snap(requested_capture_time)
+    } else {
+        // the requested time has passed, and the cameras were closed, so these stills won't
+        // line up with the others
+        snap(Utc::now() + chrono::Duration::milliseconds(STILL_CAPTURE_DELAY_MILLIS))
+    };
 
-    let mut connection = BufReader::new(connection);
+    let parse = |response| match response {
+        StreamResponse::Stills(snap_response) => Some(snap_response.stills),
+        _ => None,
+    };
+
+    let cameras = |stills: &Vec<CapturedFrame>| stills.iter().map(CapturedFrame::device_id).collect();
+
+    let mut stills = capture_from_helpers(sessions, armed, retries, request, parse, cameras);
+
+    for (socket_addr, stills) in stills.iter_mut() {
+        latency_corrections.apply(*socket_addr, stills);
+    }
+    stills
+}
+
+fn capture_bursts(
+    sessions: &mut [HelperSession],
+    armed: bool,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    max_frames: u32,
+    retries: u32,
+    latency_corrections: &LatencyCorrections,
+) -> Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)> {
+    let request = |attempt| if attempt == 0 || armed {
+        // armed helpers still have the window in their ring buffers
+        Request::Burst { start, end, max_frames }
+    } else {
+        let retry_start = Utc::now() + chrono::Duration::milliseconds(STILL_CAPTURE_DELAY_MILLIS);
+        Request::Burst { start: retry_start, end: retry_start + (end - start), max_frames }
+    };
+
+    let parse = |response| match response {
+        StreamResponse::Burst(burst_response) => Some(burst_response.bursts),
+        _ => None,
+    };
+
+    let cameras = |bursts: &Vec<Vec<CapturedFrame>>| bursts.iter()
+        .filter_map(|frames| frames.first())
+        .map(CapturedFrame::device_id)
+        .collect();
+
+    let mut bursts = capture_from_helpers(sessions, armed, retries, request, parse, cameras);
+
+    for (socket_addr, bursts) in bursts.iter_mut() {
+        for frames in bursts.iter_mut() {
+            latency_corrections.apply(*socket_addr, frames);
+        }
+    }
+    bursts
+}
+
+/// Like `capture_stills`, but the helpers search the stills for the tag and only send back what
+/// they found. Calibration doesn't care when exactly the stills were taken, so there's nothing to
+/// correct for latency
+fn capture_detections(
+    sessions: &mut [HelperSession],
+    armed: bool,
+    requested_capture_time: DateTime<Utc>,
+    retries: u32,
+) -> Vec<(SocketAddr, Vec<CameraDetections>)> {
+    let search = TagSearch {
+        tag_size_meters: TAG_SIZE_METERS,
+        focal_length_pixels: FOCAL_LENGTH_PIXELS,
+        focal_length_width: FOCAL_LENGTH_CALIBRATION_WIDTH,
+        include_stills: false,
+    };
+
+    let request = |attempt| {
+        let target_time = if attempt == 0 || armed {
+            requested_capture_time
+        } else {
+            Utc::now() + chrono::Duration::milliseconds(STILL_CAPTURE_DELAY_MILLIS)
+        };
+        Request::Detect { target_time, search }
+    };
+
+    let parse = |response| match response {
+        StreamResponse::Detections(detect_response) => Some(detect_response.cameras),
+        _ => None,
+    };
+
+    let cameras = |cameras: &Vec<CameraDetections>| cameras.iter().map(|camera| camera.device_id).collect();
+
+    capture_from_helpers(sessions, armed, retries, request, parse, cameras)
+}
+
+/// The frames the helpers recorded between `start` and `end`
+fn fetch_recordings(
+    sessions: &mut [HelperSession],
+    armed: bool,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    retries: u32,
+    latency_corrections: &LatencyCorrections,
+) -> Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)> {
+    let request = |_| Request::FetchRecording { start, end, max_frames: RECORDING_FETCH_MAX_FRAMES };
+
+    let parse = |response| match response {
+        StreamResponse::Recorded(recorded) => Some(recorded.bursts),
+        _ => None,
+    };
+
+    let cameras = |recorded: &Vec<Vec<CapturedFrame>>| recorded.iter()
+        .filter_map(|frames| frames.first())
+        .map(CapturedFrame::device_id)
+        .collect();
+
+    let mut recorded = capture_from_helpers(sessions, armed, retries, request, parse, cameras);
+
+    for (socket_addr, recorded) in recorded.iter_mut() {
+        for frames in recorded.iter_mut() {
+            latency_corrections.apply(*socket_addr, frames);
+        }
+    }
+    recorded
+}
+
+/// Sends a request to every helper and collects what they send back. Helpers that don't answer,
+/// or that leave out some of the cameras they're streaming, are asked again up to `retries` times.
+/// `request` makes the request for each attempt, `parse` pulls the answer out of the response,
+/// and `cameras` says which cameras are in an answer
+fn capture_from_helpers<T>(
+    sessions: &mut [HelperSession],
+    armed: bool,
+    retries: u32,
+    request: impl Fn(u32) -> Request,
+    parse: impl Fn(StreamResponse) -> Option<T>,
+    cameras: impl Fn(&T) -> HashSet<DeviceId>,
+) -> Vec<(SocketAddr, T)> {
+    let mut answers: Vec<Option<(T, usize)>> = sessions.iter().map(|_| None).collect();
+    let mut incomplete: Vec<usize> = (0..sessions.len()).collect();
+
+    for attempt in 0..=retries {
+        if incomplete.is_empty() { break }
+
+        if attempt > 0 {
+            println!("asking {} helpers again, attempt {} of {}", incomplete.len(), attempt, retries);
+            for &i in incomplete.iter() {
+                sessions[i].maintain(armed);
+            }
+        }
+
+        // send every request before waiting on any of them, so the helpers all work at the same time
+        let request = request(attempt);
+        for &i in incomplete.iter() {
+            sessions[i].send(&request);
+        }
+        // the helpers work at the same time, so they all get the same time to answer
+        let deadline = Instant::now() + HELPER_RESPONSE_TIMEOUT;
+
+        let mut still_incomplete = Vec::new();
+        for &i in incomplete.iter() {
+            let session = &mut sessions[i];
+            let streaming = session.streaming_devices();
+
+            let answer = match session.wait_for_response(deadline).and_then(&parse) {
+                Some(answer) => answer,
+                None => {
+                    println!("{} didn't answer", session.socket_addr);
+                    still_incomplete.push(i);
+                    continue;
+                },
+            };
+
+            let answered = cameras(&answer);
+            let missing = streaming.difference(&answered).count();
+            if missing > 0 {
+                println!("{} left out {} of its cameras", session.socket_addr, missing);
+                still_incomplete.push(i);
+            }
+
+            // keep whichever attempt got the most cameras
+            let is_most = match answers[i] {
+                Some((_, count)) => answered.len() > count,
+                None => true,
+            };
+            if is_most {
+                answers[i] = Some((answer, answered.len()));
+            }
+        }
+
+        incomplete = still_incomplete;
+    }
+
+    sessions.iter().zip(answers)
+        .filter_map(|(session, answer)| Some((session.socket_addr, answer?.0)))
+        .collect()
+}
+
+/// The originals of thumbnail captures that are still on the helpers. They're fetched a chunk at a
+/// time whenever the capture loop has nothing else to do, and a transfer that's cut off by a lost
+/// connection carries on from where it was once the helper is back
+struct OriginalTransfers {
+    captures: VecDeque<PendingCapture>,
+}
+
+struct PendingCapture {
+    event: PictureEvent,
+    capture_id: CaptureId,
+    transfers: Vec<Transfer>,
+    /// The originals that have arrived so far
+    received: Vec<(SocketAddr, CapturedFrame)>,
+}
+
+struct Transfer {
+    socket_addr: SocketAddr,
+    device_id: DeviceId,
+    /// The original as serialized by the helper, as far as it's arrived
+    data: Vec<u8>,
+}
+
+impl OriginalTransfers {
+    fn new() -> OriginalTransfers {
+        OriginalTransfers { captures: VecDeque::new() }
+    }
+
+    fn expect(&mut self, event: PictureEvent, capture_id: CaptureId, thumbnails: &[(SocketAddr, Vec<CapturedFrame>)]) {
+        let transfers = thumbnails.iter()
+            .flat_map(|(socket_addr, thumbnails)| thumbnails.iter().map(move |thumbnail| Transfer {
+                socket_addr: *socket_addr,
+                device_id: thumbnail.device_id(),
+                data: Vec::new(),
+            }))
+            .collect();
+
+        self.captures.push_back(PendingCapture { event, capture_id, transfers, received: Vec::new() });
+    }
+
+    /// Asks for the next chunk of the oldest original whose helper is connected. Returns the
+    /// originals of a capture once the last of them has arrived
+    fn fetch_next(&mut self, sessions: &mut [HelperSession], latency_corrections: &LatencyCorrections) -> Option<Message> {
+        // a helper that's gone doesn't hold up the captures of the others
+        let (capture_index, transfer_index, session) = self.captures.iter().enumerate()
+            .flat_map(|(capture_index, capture)| capture.transfers.iter().enumerate()
+                .map(move |(transfer_index, transfer)| (capture_index, transfer_index, transfer.socket_addr)))
+            .find_map(|(capture_index, transfer_index, socket_addr)| {
+                let session = sessions.iter().position(|session| session.socket_addr == socket_addr && session.is_connected())?;
+                Some((capture_index, transfer_index, session))
+            })?;
+
+        let capture = &mut self.captures[capture_index];
+        let transfer = &mut capture.transfers[transfer_index];
+        let session = &mut sessions[session];
+
+        let offset = transfer.data.len() as u64;
+        session.send(&Request::FetchOriginal {
+            capture_id: capture.capture_id,
+            device_id: transfer.device_id,
+            offset,
+            max_len: ORIGINAL_CHUNK_BYTES,
+        });
+
+        let chunk = match session.wait_for_response(Instant::now() + HELPER_RESPONSE_TIMEOUT) {
+            Some(StreamResponse::Original(chunk)) if chunk.capture_id == capture.capture_id
+                && chunk.device_id == transfer.device_id
+                && chunk.offset == offset => chunk,
+            // the session has already given up on the connection if it failed, and the transfer
+            // carries on from here once it's back
+            _ => return None,
+        };
+
+        match chunk.total_len {
+            Some(total_len) => {
+                transfer.data.extend_from_slice(&chunk.data);
+
+                if transfer.data.len() as u64 >= total_len {
+                    let transfer = capture.transfers.remove(transfer_index);
+                    match bincode::deserialize::<CapturedFrame>(&transfer.data) {
+                        Ok(original) => capture.received.push((transfer.socket_addr, original)),
+                        Err(e) => println!("couldn't read the original from {:?} on {}: {}", transfer.device_id, transfer.socket_addr, e),
+                    }
+                }
+            },
+            None => {
+                println!("{} no longer has the original from {:?}", transfer.socket_addr, transfer.device_id);
+                capture.transfers.remove(transfer_index);
+            },
+        }
+
+        if !capture.transfers.is_empty() { return None }
+
+        let capture = self.captures.remove(capture_index)?;
+        let mut originals: Vec<(SocketAddr, Vec<CapturedFrame>)> = Vec::new();
+        for (socket_addr, original) in capture.received {
+            match originals.iter_mut().find(|(addr, _)| *addr == socket_addr) {
+                Some((_, stills)) => stills.push(original),
+                None => originals.push((socket_addr, vec![original])),
+            }
+        }
+
+        for (socket_addr, stills) in originals.iter_mut() {
+            latency_corrections.apply(*socket_addr, stills);
+        }
+
+        Some(Message::Originals(capture.event, originals))
+    }
+}
+
+/// Passes the previews on to the window, and the responses to our requests on to the capture loop.
+/// When the connection is lost, the helper's tiles stay on screen, so the operator can tell which
+/// cameras are missing from a capture and the tiles keep their place for when it reconnects
+fn receive(
+    socket_addr: SocketAddr,
+    mut connection: BufReader<TcpStream>,
+    devices: &Mutex<HashSet<DeviceId>>,
+    message_sender: &Sender<Message>,
+    response_sender: &Sender<Result<StreamResponse, SessionError>>,
+    metrics: &Metrics,
+) {
+    let helper = socket_addr.to_string();
+
+    let error = loop {
+        let response = match StreamResponse::deserialize_from(&mut connection) {
+            Ok(response) => response,
+            Err(e) => break SessionError::ConnectionLost(e),
+        };
+
+        let message = match response {
+            StreamResponse::Stop(device_id) | StreamResponse::DeviceRemoved(device_id) => {
+                devices.lock().unwrap().remove(&device_id);
+                Message::StreamDeregistered(StreamSource::new(socket_addr, device_id))
+            },
+            StreamResponse::DeviceAdded(device_id) => {
+                println!("{:?} was plugged into {}", device_id, socket_addr);
+                devices.lock().unwrap().insert(device_id);
+                continue;
+            },
+            StreamResponse::Recovering(device_id, attempt) => {
+                println!("{:?} on {} stalled, recovery attempt: {:?}", device_id, socket_addr, attempt);
+                continue;
+            },
+            StreamResponse::Formats(device_id, formats) => {
+                Message::CameraFormats(StreamSource::new(socket_addr, device_id), formats)
+            },
+            StreamResponse::Unavailable(device_id, reason) => {
+                println!("{:?} on {} can't stream: {}", device_id, socket_addr, reason);
+                continue;
+            },
+            StreamResponse::Dropped(device_id, counts) => {
+                println!("{:?} on {} missed {} and discarded {} preview frames", device_id, socket_addr, counts.missed, counts.discarded);
+                let device = device_id.to_string();
+                metrics.count(&FRAMES_DROPPED, &[("helper", &helper), ("device", &device), ("reason", "missed")], counts.missed as u64);
+                metrics.count(&FRAMES_DROPPED, &[("helper", &helper), ("device", &device), ("reason", "discarded")], counts.discarded as u64);
+                continue;
+            },
+            StreamResponse::Status(health) => Message::HelperHealth(socket_addr, health),
+            StreamResponse::Frame(frame) => {
+                devices.lock().unwrap().insert(frame.device_id());
+                let stream_id = StreamSource::new(socket_addr, frame.device_id());
+                let device = frame.device_id().to_string();
+                metrics.count(&FRAMES_RECEIVED, &[("helper", &helper), ("device", &device)], 1);
+
+                let image = decode_frame(&frame);
+
+                match image {
+                    Ok(image) => Message::NewImage(stream_id, image.into_rgb8()),
+                    Err(_) => {
+                        metrics.count(&DECODE_FAILURES, &[("helper", &helper), ("device", &device)], 1);
+                        continue;
+                    },
+                }
+            },
+            response @ StreamResponse::Stills(_)
+            | response @ StreamResponse::Burst(_)
+            | response @ StreamResponse::Detections(_)
+            | response @ StreamResponse::Recorded(_)
+            | response @ StreamResponse::Original(_) => {
+                let _ = response_sender.send(Ok(response));
+                continue;
+            },
+        };
 
-    let snap_response: SnapResponse = bincode::deserialize_from(&mut connection).unwrap();
+        // the window has been closed
+        if message_sender.send(message).is_err() { return }
+    };
 
-    Ok(snap_response)
+    let _ = response_sender.send(Err(error));
 }
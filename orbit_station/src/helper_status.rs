@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use glium::{Display, Rect, Surface};
+use glium::index::PrimitiveType;
+use orbit_types::HelperHealth;
+
+use crate::overlay::{Overlay, OverlayVertex};
+
+const INDICATOR_SIZE: u32 = 16;
+const INDICATOR_MARGIN: u32 = 8;
+/// Gauges go this far past their threshold before they're full
+const GAUGE_EXTENT: f32 = 1.5;
+const GAUGE_MARGIN: f32 = 0.2;
+/// How much of each row of the fleet view goes to the swatch that says what the gauge measures
+const LABEL_WIDTH: f32 = 0.1;
+
+const BORDER_COLOR: [f32; 3] = [0.3, 0.3, 0.3];
+const THRESHOLD_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+const HEALTHY_COLOR: [f32; 3] = [0.0, 1.0, 0.0];
+const ALERT_COLOR: [f32; 3] = [1.0, 0.5, 0.0];
+
+const TEMPERATURE_LABEL: [f32; 3] = [1.0, 0.0, 0.0];
+const LOAD_LABEL: [f32; 3] = [1.0, 1.0, 0.0];
+const MEMORY_LABEL: [f32; 3] = [0.0, 0.5, 1.0];
+const SWAP_LABEL: [f32; 3] = [0.7, 0.0, 1.0];
+const DISK_LABEL: [f32; 3] = [0.6, 0.4, 0.2];
+const CAMERA_LABEL: [f32; 3] = [0.0, 1.0, 1.0];
+/// What the colors of the swatches mean, since we can't draw text
+pub const FLEET_VIEW_LEGEND: &str = "fleet view: red is temperature, yellow is load, blue is memory, \
+    purple is swap, brown is free disk, and cyan is the frame rate of each camera";
+
+#[derive(Clone, Debug)]
+pub enum HelperStatus {
+    Connecting,
+    Streaming,
+    /// The reason the connection failed. We'll keep retrying in the background
+    Failed(String),
+}
+
+/// Past these, a helper is in trouble. `disk_free_mb` and `camera_fps` are the lowest healthy
+/// values, the rest are the highest
+#[derive(Copy, Clone, Debug)]
+pub struct HealthThresholds {
+    pub cpu_temp_celsius: f32,
+    pub load: f32,
+    pub memory_used_percent: f32,
+    pub swap_used_mb: f32,
+    pub disk_free_mb: f32,
+    pub camera_fps: f32,
+}
+
+/// One thing about a helper, measured against its threshold
+struct Gauge {
+    name: String,
+    /// The color of the swatch beside its bar in the fleet view
+    label: [f32; 3],
+    /// 1.0 is right at the threshold, and anything past it is an alert. `None` if the helper
+    /// couldn't measure it, which leaves its row of the fleet view empty
+    ratio: Option<f32>,
+    /// What the alert says
+    description: String,
+}
+
+impl Gauge {
+    fn is_alert(&self) -> bool {
+        matches!(self.ratio, Some(ratio) if ratio >= 1.0)
+    }
+}
+
+impl HealthThresholds {
+    /// In the same order for every helper, so the fleet view lines up, with a gauge for each camera
+    /// at the end
+    fn gauges(&self, health: &HelperHealth) -> Vec<Gauge> {
+        let mb = |bytes: u64| bytes as f32 / (1024.0 * 1024.0);
+        let memory_used_percent = match (health.memory_available_bytes, health.memory_total_bytes) {
+            (Some(available), Some(total)) if total > 0 => Some(100.0 * (1.0 - available as f32 / total as f32)),
+            _ => None,
+        };
+        let swap_used_mb = match (health.swap_total_bytes, health.swap_free_bytes) {
+            (Some(total), Some(free)) => Some(mb(total.saturating_sub(free))),
+            _ => None,
+        };
+        let disk_free_mb = health.disk_free_bytes.map(mb);
+
+        let mut gauges = vec![
+            Gauge {
+                name: "temperature".to_string(),
+                label: TEMPERATURE_LABEL,
+                ratio: health.cpu_temp_celsius.map(|temp| temp / self.cpu_temp_celsius),
+                description: format!("CPU at {:.0}°C", health.cpu_temp_celsius.unwrap_or(0.0)),
+            },
+            Gauge {
+                name: "load".to_string(),
+                label: LOAD_LABEL,
+                ratio: Some(health.load[0] / self.load),
+                description: format!("load of {:.1}", health.load[0]),
+            },
+            Gauge {
+                name: "memory".to_string(),
+                label: MEMORY_LABEL,
+                ratio: memory_used_percent.map(|memory_used_percent| memory_used_percent / self.memory_used_percent),
+                description: format!("{:.0}% of memory used", memory_used_percent.unwrap_or(0.0)),
+            },
+            Gauge {
+                name: "swap".to_string(),
+                label: SWAP_LABEL,
+                ratio: swap_used_mb.map(|swap_used_mb| swap_used_mb / self.swap_used_mb),
+                description: format!("{:.0}MB swapped out", swap_used_mb.unwrap_or(0.0)),
+            },
+            Gauge {
+                name: "disk".to_string(),
+                label: DISK_LABEL,
+                ratio: disk_free_mb.map(|disk_free_mb| self.disk_free_mb / disk_free_mb),
+                description: format!("{:.0}MB of disk left", disk_free_mb.unwrap_or(0.0)),
+            },
+        ];
+
+        gauges.extend(health.camera_fps.iter().map(|&(device_id, fps)| Gauge {
+            name: device_id.to_string(),
+            label: CAMERA_LABEL,
+            ratio: Some(self.camera_fps / fps),
+            description: format!("{} at {:.1}fps", device_id, fps),
+        }));
+
+        gauges
+    }
+}
+
+impl HelperStatus {
+    fn color(&self) -> [f32; 3] {
+        match self {
+            HelperStatus::Connecting => [1.0, 1.0, 0.0],
+            HelperStatus::Streaming => [0.0, 1.0, 0.0],
+            HelperStatus::Failed(_) => [1.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// The connection state of every helper we've heard about, and how the connected ones are holding up
+pub struct HelperStatuses {
+    statuses: BTreeMap<SocketAddr, HelperStatus>,
+    health: BTreeMap<SocketAddr, HelperHealth>,
+    thresholds: HealthThresholds,
+}
+
+impl HelperStatuses {
+    pub fn new(thresholds: HealthThresholds) -> HelperStatuses {
+        HelperStatuses { statuses: BTreeMap::new(), health: BTreeMap::new(), thresholds }
+    }
+
+    pub fn update(&mut self, socket_addr: SocketAddr, status: HelperStatus) {
+        match status {
+            HelperStatus::Failed(ref reason) => println!("{} failed: {}", socket_addr, reason),
+            _ => println!("{} is {:?}", socket_addr, status),
+        }
+
+        // whatever the helper said last doesn't hold while it's not connected
+        if let HelperStatus::Failed(_) = status {
+            self.health.remove(&socket_addr);
+        }
+
+        self.statuses.insert(socket_addr, status);
+    }
+
+    /// Prints the alerts that weren't there in the helper's last report
+    pub fn update_health(&mut self, socket_addr: SocketAddr, health: HelperHealth) {
+        let previous_alerts: Vec<String> = self.health.get(&socket_addr)
+            .map(|previous| self.thresholds.gauges(previous).into_iter()
+                .filter(Gauge::is_alert)
+                .map(|gauge| gauge.name)
+                .collect())
+            .unwrap_or_default();
+
+        for gauge in self.thresholds.gauges(&health) {
+            if gauge.is_alert() && !previous_alerts.contains(&gauge.name) {
+                println!("{} (running orbit_helper {}, up {}s): {}", socket_addr, health.version, health.uptime_secs, gauge.description);
+            }
+        }
+
+        self.health.insert(socket_addr, health);
+    }
+
+    fn alerts(&self, socket_addr: SocketAddr) -> Vec<String> {
+        match self.health.get(&socket_addr) {
+            Some(health) => self.thresholds.gauges(health).into_iter()
+                .filter(Gauge::is_alert)
+                .map(|gauge| gauge.description)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// A window title that says which helpers aren't streaming or are in trouble, since we can't
+    /// draw text
+    pub fn title(&self) -> String {
+        let problems: Vec<String> = self.statuses.iter()
+            .filter_map(|(socket_addr, status)| match status {
+                HelperStatus::Streaming => {
+                    let alerts = self.alerts(*socket_addr);
+                    if alerts.is_empty() { None } else { Some(format!("{} {}", socket_addr, alerts.join(", "))) }
+                },
+                HelperStatus::Connecting => Some(format!("{} connecting", socket_addr)),
+                HelperStatus::Failed(reason) => Some(format!("{} failed: {}", socket_addr, reason)),
+            })
+            .collect();
+
+        if problems.is_empty() {
+            "Orbit Station".to_string()
+        } else {
+            format!("Orbit Station - {}", problems.join("; "))
+        }
+    }
+
+    /// A colored square for each helper along the top left of the window
+    pub fn draw(&self, overlay: &Overlay, display: &Display, target: &mut glium::Frame) {
+        let (_, height) = target.get_dimensions();
+
+        let square = [
+            OverlayVertex::new(-1.0, -1.0),
+            OverlayVertex::new(-1.0, 1.0),
+            OverlayVertex::new(1.0, 1.0),
+            OverlayVertex::new(-1.0, -1.0),
+            OverlayVertex::new(1.0, 1.0),
+            OverlayVertex::new(1.0, -1.0),
+        ];
+
+        for (i, (&socket_addr, status)) in self.statuses.iter().enumerate() {
+            let color = match status {
+                HelperStatus::Streaming if !self.alerts(socket_addr).is_empty() => ALERT_COLOR,
+                _ => status.color(),
+            };
+
+            let viewport = Rect {
+                left: INDICATOR_MARGIN + i as u32 * (INDICATOR_SIZE + INDICATOR_MARGIN),
+                bottom: height.saturating_sub(INDICATOR_MARGIN + INDICATOR_SIZE),
+                width: INDICATOR_SIZE,
+                height: INDICATOR_SIZE,
+            };
+
+            overlay.draw(display, target, viewport, &square, PrimitiveType::TrianglesList, color);
+        }
+    }
+
+    /// A column for each helper in the order of the indicators, with a bar for each of its gauges:
+    /// temperature, load, memory, swap, disk, then the frame rate of each camera. A swatch beside
+    /// each bar says which it is, see `FLEET_VIEW_LEGEND`. The line across each bar is its
+    /// threshold, and bars past it turn orange
+    pub fn draw_fleet_view(&self, overlay: &Overlay, display: &Display, target: &mut glium::Frame) {
+        let (width, height) = target.get_dimensions();
+        let columns = self.health.len().max(1) as u32;
+        let column_width = width / columns;
+
+        for (column, health) in self.health.values().enumerate() {
+            let viewport = Rect { left: column as u32 * column_width, bottom: 0, width: column_width, height };
+            overlay.draw_border(display, target, viewport, BORDER_COLOR);
+
+            let gauges = self.thresholds.gauges(health);
+            let row_height = 2.0 / gauges.len().max(1) as f32;
+            // the bars start after the swatches
+            let left = -1.0 + 2.0 * LABEL_WIDTH;
+            let to_x = |ratio: f32| left + (1.0 - left) * ratio.clamp(0.0, GAUGE_EXTENT) / GAUGE_EXTENT;
+
+            for (row, gauge) in gauges.iter().enumerate() {
+                let top = 1.0 - row_height * (row as f32 + GAUGE_MARGIN);
+                let bottom = 1.0 - row_height * (row as f32 + 1.0 - GAUGE_MARGIN);
+
+                let swatch = rectangle(-1.0, left - GAUGE_MARGIN * LABEL_WIDTH, bottom, top);
+                overlay.draw(display, target, viewport, &swatch, PrimitiveType::TrianglesList, gauge.label);
+
+                if let Some(ratio) = gauge.ratio {
+                    let bar = rectangle(left, to_x(ratio), bottom, top);
+                    let color = if gauge.is_alert() { ALERT_COLOR } else { HEALTHY_COLOR };
+                    overlay.draw(display, target, viewport, &bar, PrimitiveType::TrianglesList, color);
+                }
+
+                let threshold = [OverlayVertex::new(to_x(1.0), bottom), OverlayVertex::new(to_x(1.0), top)];
+                overlay.draw(display, target, viewport, &threshold, PrimitiveType::LinesList, THRESHOLD_COLOR);
+            }
+        }
+    }
+}
+
+/// Two triangles covering the rectangle
+fn rectangle(left: f32, right: f32, bottom: f32, top: f32) -> [OverlayVertex; 6] {
+    [
+        OverlayVertex::new(left, bottom),
+        OverlayVertex::new(left, top),
+        OverlayVertex::new(right, top),
+        OverlayVertex::new(left, bottom),
+        OverlayVertex::new(right, top),
+        OverlayVertex::new(right, bottom),
+    ]
+}
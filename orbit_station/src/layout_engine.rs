@@ -82,5 +82,5 @@ fn integer_square_root(n: u32) -> u32 {
 }
 
 fn ceiling_div(a: u32, b: u32) -> u32 {
-    a/b + if a%b == 0 { 0 } else { 1 }
+    a.div_ceil(b)
 }
@@ -8,6 +8,16 @@ mod layout_engine;
 mod frame_receiver;
 mod find_tags;
 mod calibration;
+mod overlay;
+mod rig_view;
+mod reconstruction;
+mod burst;
+mod sync;
+mod config;
+mod timing_view;
+mod timecode;
+mod helper_status;
+mod camera_formats;
 
 use std::net::SocketAddr;
 use glium::{glutin};
@@ -15,6 +25,9 @@ use glutin::event_loop::EventLoop;
 use crate::state::{State, PictureEventState};
 use std::sync::{mpsc};
 use crate::frame_receiver::spawn_capture_loop;
+use crate::config::Config;
+use crate::sync::LatencyCorrections;
+use orbit_metrics::Metrics;
 
 const TAG_SIZE_METERS: f64 = 162.0 / 1000.0;
 const INITIAL_WINDOW_WIDTH: u32 = STREAM_ASPECT_WIDTH*400;
@@ -22,7 +35,20 @@ const INITIAL_WINDOW_HEIGHT: u32 = STREAM_ASPECT_HEIGHT*400;
 const STREAM_ASPECT_WIDTH: u32 = 9;
 const STREAM_ASPECT_HEIGHT: u32 = 16;
 const STILL_CAPTURE_DELAY_MILLIS: i64 = 2000;
+// armed helpers remember the last few seconds, so we capture from just before the key was pressed
+// to make up for the operator's reaction time
+const RETROACTIVE_CAPTURE_MILLIS: i64 = 150;
+const BURST_DURATION_MILLIS: i64 = 1000;
+const BURST_MAX_FRAMES: u32 = 30;
+// armed helpers send every frame this close to the requested time, and we pick the frames that
+// were captured closest together
+const SYNC_SEARCH_MILLIS: i64 = 100;
+const SYNC_SEARCH_MAX_FRAMES: u32 = 16;
 const FOCAL_LENGTH_PIXELS: f64 = 1484.0;
+// the focal length was measured on stills this wide
+const FOCAL_LENGTH_CALIBRATION_WIDTH: u32 = 1280;
+// recordings are fetched from the helpers thinned out to this many frames per camera
+const RECORDING_FETCH_MAX_FRAMES: u32 = 100;
 const VIDEO_FRAMERATE: (usize, usize) = (1, 1); // 1 frame / second
 
 fn main() {
@@ -32,13 +58,39 @@ fn main() {
         "192.168.2.102:2000".parse().unwrap(),
     ];
 
+    let config = Config::load();
     let picture_event_state = PictureEventState::new();
+    let latency_corrections = LatencyCorrections::new();
     let (message_sender, message_receiver) = mpsc::channel();
 
-    spawn_capture_loop(addrs, message_sender, picture_event_state.clone());
+    let metrics = Metrics::new();
+    if let Some(port) = config.metrics_port {
+        match metrics.serve(port) {
+            Ok(()) => println!("serving metrics on port {}", port),
+            Err(e) => println!("couldn't serve metrics on port {}: {:?}", port, e),
+        }
+    }
+
+    spawn_capture_loop(
+        addrs,
+        message_sender,
+        picture_event_state.clone(),
+        latency_corrections.clone(),
+        config.capture_retries,
+        config.stream_settings(),
+        config.focus_preview_settings(),
+        metrics,
+    );
 
     let event_loop = EventLoop::new();
-    let mut state = State::new(INITIAL_WINDOW_WIDTH, INITIAL_WINDOW_HEIGHT, &event_loop, picture_event_state.clone());
+    let mut state = State::new(
+        INITIAL_WINDOW_WIDTH,
+        INITIAL_WINDOW_HEIGHT,
+        &event_loop,
+        picture_event_state.clone(),
+        latency_corrections,
+        config,
+    );
 
     event_loop.run(move |event, _, control_flow| {
         state.event_handler(event, control_flow, &message_receiver)
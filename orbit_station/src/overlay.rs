@@ -0,0 +1,100 @@
+use glium::{Display, DrawParameters, implement_vertex, program, Program, Rect, Surface, uniform, VertexBuffer};
+use glium::index::{NoIndices, PrimitiveType};
+
+#[derive(Copy, Clone)]
+pub struct OverlayVertex {
+    position: [f32; 2],
+}
+implement_vertex!(OverlayVertex, position);
+
+impl OverlayVertex {
+    pub fn new(x: f32, y: f32) -> OverlayVertex {
+        OverlayVertex { position: [x, y] }
+    }
+
+    pub fn x(self) -> f32 {
+        self.position[0]
+    }
+
+    pub fn y(self) -> f32 {
+        self.position[1]
+    }
+}
+
+/// Draws flat colored lines and shapes, for the views that aren't just camera images
+pub struct Overlay {
+    shaders: Program,
+}
+
+impl Overlay {
+    pub fn new(display: &Display) -> Overlay {
+        let shaders = program!(display,
+            140 => {
+                vertex: include_str!("shaders/overlay_vertex.glsl"),
+                fragment: include_str!("shaders/overlay_fragment.glsl"),
+            },
+        ).unwrap();
+
+        Overlay { shaders }
+    }
+
+    /// `vertices` are in normalized device coordinates of `viewport`
+    pub fn draw(
+        &self,
+        display: &Display,
+        target: &mut glium::Frame,
+        viewport: Rect,
+        vertices: &[OverlayVertex],
+        primitive: PrimitiveType,
+        color: [f32; 3],
+    ) {
+        if vertices.is_empty() { return }
+
+        let vertex_buffer = VertexBuffer::new(display, vertices).unwrap();
+
+        let uniforms = uniform! {
+            color: color,
+        };
+
+        let draw_parameters: DrawParameters = DrawParameters {
+            viewport: Some(viewport),
+            line_width: Some(2.0),
+            ..Default::default()
+        };
+
+        target.draw(
+            &vertex_buffer,
+            NoIndices(primitive),
+            &self.shaders,
+            &uniforms,
+            &draw_parameters,
+        ).unwrap();
+    }
+
+    pub fn draw_border(&self, display: &Display, target: &mut glium::Frame, viewport: Rect, color: [f32; 3]) {
+        let corners = [
+            OverlayVertex::new(-1.0, -1.0),
+            OverlayVertex::new(-1.0, 1.0),
+            OverlayVertex::new(1.0, 1.0),
+            OverlayVertex::new(1.0, -1.0),
+        ];
+
+        self.draw(display, target, viewport, &corners, PrimitiveType::LineLoop, color);
+    }
+}
+
+/// The line segments of a small square centered on `center`, for use with `PrimitiveType::LinesList`
+pub fn square_marker(center: OverlayVertex, half_size: f32) -> [OverlayVertex; 8] {
+    let (x, y, s) = (center.x(), center.y(), half_size);
+    let bottom_left = OverlayVertex::new(x - s, y - s);
+    let top_left = OverlayVertex::new(x - s, y + s);
+    let top_right = OverlayVertex::new(x + s, y + s);
+    let bottom_right = OverlayVertex::new(x + s, y - s);
+
+    [
+        bottom_left, top_left,
+        top_left, top_right,
+        top_right, bottom_right,
+        bottom_right, bottom_left,
+    ]
+}
@@ -1,4 +1,8 @@
-use image::{ImageBuffer, DynamicImage, Pixel};
+use image::{ImageBuffer, DynamicImage, Pixel, RgbImage, ImageFormat, ImageError, ImageResult};
+use image::error::{ParameterError, ParameterErrorKind};
+use orbit_types::CapturedFrame;
+
+use crate::config::GapFill;
 
 pub trait ImageTransformExt {
     fn crop_rotate(&self, radians: f32, crop_factor: f32) -> Self;
@@ -73,3 +77,58 @@ pub fn rotation_matrix(t: f32) -> [f32; 4] {
         t.sin(), t.cos(),
     ]
 }
+
+/// Fills in the missing frames of a sequence. Holding repeats the frame before the gap (or the one
+/// after it, for a gap at the start). Interpolating blends the frames on either side of the gap,
+/// weighted by how close they are, and holds when there's only a frame on one side or the frames
+/// on either side aren't the same size
+pub fn fill_gaps(frames: &mut [Option<RgbImage>], gap_fill: GapFill) {
+    let present: Vec<usize> = (0..frames.len()).filter(|&i| frames[i].is_some()).collect();
+    if present.is_empty() { return }
+
+    for i in 0..frames.len() {
+        if frames[i].is_some() { continue }
+
+        let before = present.iter().rev().copied().find(|&p| p < i);
+        let after = present.iter().copied().find(|&p| p > i);
+
+        let filled = match (gap_fill, before, after) {
+            (GapFill::Interpolate, Some(before), Some(after)) => {
+                let weight = (i - before) as f32 / (after - before) as f32;
+                blend(frames[before].as_ref().unwrap(), frames[after].as_ref().unwrap(), weight)
+                    .or_else(|| frames[before].clone())
+            },
+            (_, Some(before), _) => frames[before].clone(),
+            (_, None, Some(after)) => frames[after].clone(),
+            (_, None, None) => None,
+        };
+
+        frames[i] = filled;
+    }
+}
+
+/// `weight` is how much of `b` to use
+fn blend(a: &RgbImage, b: &RgbImage, weight: f32) -> Option<RgbImage> {
+    if a.dimensions() != b.dimensions() { return None }
+
+    Some(RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (a, b) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let mut blended = *a;
+        for channel in 0..3 {
+            blended[channel] = (a[channel] as f32*(1.0 - weight) + b[channel] as f32*weight).round() as u8;
+        }
+        blended
+    }))
+}
+
+/// Decodes a frame from a helper, which is JPEG unless the camera couldn't do it
+pub fn decode_frame(frame: &CapturedFrame) -> ImageResult<DynamicImage> {
+    if !frame.is_yuyv() {
+        return image::load_from_memory_with_format(frame.frame_data(), ImageFormat::Jpeg);
+    }
+
+    orbit_types::yuyv_to_rgb(frame.frame_data(), frame.width(), frame.height())
+        .and_then(|rgb| RgbImage::from_raw(frame.width(), frame.height(), rgb))
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)))
+}
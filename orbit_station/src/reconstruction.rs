@@ -0,0 +1,288 @@
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use nalgebra::{Isometry3, Matrix4, Vector4};
+use serde::Serialize;
+
+use orbit_types::{frame_jpeg, CapturedFrame};
+
+use crate::calibration::focal_length_pixels;
+use crate::streams::{Streams, StreamSource, StreamOrdinal};
+use crate::state::output_dir;
+
+/// Writes the stills along with the intrinsics and poses of the cameras that took them, so that
+/// photogrammetry and NeRF tools can skip their own pose estimation. The output directory has
+/// the layout that COLMAP and Nerfstudio expect:
+///
+/// ```text
+/// images/frameXX.jpg
+/// sparse/0/{cameras.txt, images.txt, points3D.txt}
+/// transforms.json
+/// ```
+///
+/// Only the cameras that found the tag in the latest calibration are exported
+pub fn export_reconstruction(streams: &Streams, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>) {
+    let dir = output_dir();
+
+    let mut views: Vec<View> = devices.into_iter()
+        .flat_map(|(addr, stills)| stills.into_iter().map(move |still| (StreamSource::new(addr, still.device_id()), still)))
+        .filter_map(|(source, still)| {
+            let ordinal = streams.get_stream_tile(source)?;
+            let camera_pose = streams.camera_pose(ordinal)?;
+            Some(View { ordinal, camera_pose, still })
+        })
+        .collect();
+
+    views.sort_by_key(|view| view.ordinal);
+
+    match write_reconstruction(&dir, &views) {
+        Ok(()) => println!("exported {} cameras for reconstruction to {:?}", views.len(), dir),
+        Err(e) => println!("failed to export reconstruction to {:?}: {:?}", dir, e),
+    }
+}
+
+struct View {
+    ordinal: StreamOrdinal,
+    /// Takes points in the frame of the camera to the frame of the tag
+    camera_pose: Isometry3<f64>,
+    still: CapturedFrame,
+}
+
+impl View {
+    fn image_name(&self) -> String {
+        format!("frame{:02}.jpg", self.ordinal.index())
+    }
+
+    /// Matches the principal point that we give to the apriltag pose estimation
+    fn principal_point(&self) -> (f64, f64) {
+        (self.still.width() as f64 / 2.0, self.still.height() as f64 / 2.0)
+    }
+
+    fn focal_length(&self) -> f64 {
+        focal_length_pixels(self.still.width())
+    }
+}
+
+fn write_reconstruction(dir: &Path, views: &[View]) -> io::Result<()> {
+    let images_dir = dir.join("images");
+    let sparse_dir = dir.join("sparse").join("0");
+    fs::create_dir_all(&images_dir)?;
+    fs::create_dir_all(&sparse_dir)?;
+
+    for view in views {
+        fs::write(images_dir.join(view.image_name()), frame_jpeg(&view.still)?)?;
+    }
+
+    write_colmap_cameras(&sparse_dir.join("cameras.txt"), views)?;
+    write_colmap_images(&sparse_dir.join("images.txt"), views)?;
+    // we don't have any points, but COLMAP refuses to read the model without the file
+    fs::write(sparse_dir.join("points3D.txt"), "")?;
+
+    write_nerfstudio_transforms(&dir.join("transforms.json"), views)
+}
+
+/// Each camera gets its own COLMAP camera because their resolutions don't have to match
+fn write_colmap_cameras(path: &Path, views: &[View]) -> io::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+
+    writeln!(file, "# Camera list with one line of data per camera:")?;
+    writeln!(file, "#   CAMERA_ID, MODEL, WIDTH, HEIGHT, PARAMS[]")?;
+    writeln!(file, "# Number of cameras: {}", views.len())?;
+
+    for (camera_id, view) in (1..).zip(views) {
+        let (cx, cy) = view.principal_point();
+        writeln!(
+            file,
+            "{} PINHOLE {} {} {} {} {} {}",
+            camera_id,
+            view.still.width(),
+            view.still.height(),
+            view.focal_length(),
+            view.focal_length(),
+            cx,
+            cy,
+        )?;
+    }
+
+    file.flush()
+}
+
+fn write_colmap_images(path: &Path, views: &[View]) -> io::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+
+    writeln!(file, "# Image list with two lines of data per image:")?;
+    writeln!(file, "#   IMAGE_ID, QW, QX, QY, QZ, TX, TY, TZ, CAMERA_ID, NAME")?;
+    writeln!(file, "#   POINTS2D[] as (X, Y, POINT3D_ID)")?;
+    writeln!(file, "# Number of images: {}", views.len())?;
+
+    for (image_id, view) in (1..).zip(views) {
+        // COLMAP wants the transformation from the world (the tag) to the camera
+        let world_to_camera = view.camera_pose.inverse();
+        let q = world_to_camera.rotation;
+        let t = world_to_camera.translation.vector;
+
+        writeln!(
+            file,
+            "{} {} {} {} {} {} {} {} {} {}",
+            image_id, q.w, q.i, q.j, q.k, t.x, t.y, t.z, image_id, view.image_name(),
+        )?;
+        // no 2D points
+        writeln!(file)?;
+    }
+
+    file.flush()
+}
+
+#[derive(Serialize)]
+struct NerfstudioTransforms {
+    camera_model: &'static str,
+    frames: Vec<NerfstudioFrame>,
+}
+
+#[derive(Serialize)]
+struct NerfstudioFrame {
+    file_path: String,
+    fl_x: f64,
+    fl_y: f64,
+    cx: f64,
+    cy: f64,
+    w: u32,
+    h: u32,
+    transform_matrix: [[f64; 4]; 4],
+}
+
+fn write_nerfstudio_transforms(path: &Path, views: &[View]) -> io::Result<()> {
+    let frames = views.iter()
+        .map(|view| {
+            let (cx, cy) = view.principal_point();
+
+            NerfstudioFrame {
+                file_path: format!("images/{}", view.image_name()),
+                fl_x: view.focal_length(),
+                fl_y: view.focal_length(),
+                cx,
+                cy,
+                w: view.still.width(),
+                h: view.still.height(),
+                transform_matrix: opengl_camera_to_world(view.camera_pose),
+            }
+        })
+        .collect();
+
+    let transforms = NerfstudioTransforms { camera_model: "PINHOLE", frames };
+
+    let file = io::BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer_pretty(file, &transforms)?;
+    Ok(())
+}
+
+/// Nerfstudio uses the OpenGL convention, where the camera looks down its negative z axis with
+/// positive y pointing up in the image. Our cameras look down positive z with y pointing down
+fn opengl_camera_to_world(camera_pose: Isometry3<f64>) -> [[f64; 4]; 4] {
+    let flip_y_z = Matrix4::from_diagonal(&Vector4::new(1.0, -1.0, -1.0, 1.0));
+    let matrix = camera_pose.to_homogeneous() * flip_y_z;
+
+    let mut rows = [[0.0; 4]; 4];
+    for (r, row) in rows.iter_mut().enumerate() {
+        for (c, element) in row.iter_mut().enumerate() {
+            *element = matrix[(r, c)];
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::TAU;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::process;
+    use chrono::{TimeZone, Utc};
+    use image::RgbImage;
+    use nalgebra::{Translation3, UnitQuaternion, Vector3};
+    use orbit_types::DeviceIdGenerator;
+
+    /// A 640x480 camera at (1, 2, 3) in the frame of the tag, turned a quarter counterclockwise
+    /// around the tag's z axis
+    fn view() -> View {
+        let source = StreamSource::new(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000)), DeviceIdGenerator::new().next());
+        let mut streams = Streams::new();
+        streams.register_frame(source, RgbImage::new(640, 480));
+
+        View {
+            ordinal: streams.get_stream_tile(source).unwrap(),
+            camera_pose: Isometry3::from_parts(
+                Translation3::new(1.0, 2.0, 3.0),
+                UnitQuaternion::from_axis_angle(&Vector3::z_axis(), TAU/4.0),
+            ),
+            still: CapturedFrame::new(source.device_id(), 640, 480, *b"MJPG", Utc.timestamp_millis(0), 0, Vec::new()),
+        }
+    }
+
+    /// What `write` wrote
+    fn written(name: &str, write: fn(&Path, &[View]) -> io::Result<()>) -> String {
+        let path = std::env::temp_dir().join(format!("orbit_reconstruction_{}_{}", process::id(), name));
+        write(&path, &[view()]).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        contents
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn colmap_cameras() {
+        let cameras = written("cameras.txt", write_colmap_cameras);
+        let lines: Vec<&str> = cameras.lines().filter(|line| !line.starts_with('#')).collect();
+
+        // the focal length is scaled down from the 1280 pixel wide calibration
+        assert_eq!(lines, ["1 PINHOLE 640 480 742 742 320 240"]);
+    }
+
+    #[test]
+    fn colmap_images_take_the_tag_to_the_camera() {
+        let images = written("images.txt", write_colmap_images);
+        let lines: Vec<&str> = images.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "", "there are no 2D points");
+
+        let words: Vec<&str> = lines[0].split(' ').collect();
+        assert_eq!((words[0], words[8], words[9]), ("1", "1", "frame00.jpg"));
+        let numbers: Vec<f64> = words[1..8].iter().map(|word| word.parse().unwrap()).collect();
+
+        // turned back a quarter around z, and where the tag's origin is in the frame of the camera
+        let half_sqrt_2 = 0.5_f64.sqrt();
+        assert_close(&numbers, &[half_sqrt_2, 0.0, 0.0, -half_sqrt_2, -2.0, 1.0, -3.0]);
+    }
+
+    #[test]
+    fn nerfstudio_cameras_look_down_their_negative_z_axis() {
+        let transforms: serde_json::Value = serde_json::from_str(&written("transforms.json", write_nerfstudio_transforms)).unwrap();
+        assert_eq!(transforms["camera_model"], "PINHOLE");
+
+        let frame = &transforms["frames"][0];
+        assert_eq!(frame["file_path"], "images/frame00.jpg");
+        assert_eq!((frame["w"].as_u64(), frame["h"].as_u64()), (Some(640), Some(480)));
+        assert_close(
+            &["fl_x", "fl_y", "cx", "cy"].iter().map(|key| frame[*key].as_f64().unwrap()).collect::<Vec<_>>(),
+            &[742.0, 742.0, 320.0, 240.0],
+        );
+
+        let matrix: Vec<f64> = frame["transform_matrix"].as_array().unwrap().iter()
+            .flat_map(|row| row.as_array().unwrap().iter().map(|element| element.as_f64().unwrap()))
+            .collect();
+        assert_close(&matrix, &[
+            0.0, 1.0, 0.0, 1.0,
+            1.0, 0.0, 0.0, 2.0,
+            0.0, 0.0, -1.0, 3.0,
+            0.0, 0.0, 0.0, 1.0,
+        ]);
+    }
+}
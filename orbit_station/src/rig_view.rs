@@ -0,0 +1,95 @@
+use std::f64::consts::TAU;
+
+use glium::{Display, Rect, Surface};
+use glium::index::PrimitiveType;
+use nalgebra::{Point3, Vector3};
+
+use crate::overlay::{Overlay, OverlayVertex, square_marker};
+use crate::streams::Streams;
+
+/// Cameras pointing further than this from the target are drawn as misaimed
+const MISAIM_TOLERANCE_RADIANS: f64 = 5.0 * TAU / 360.0;
+/// The views always show at least this many meters around the target
+const MINIMUM_EXTENT_METERS: f64 = 0.5;
+const MARKER_HALF_SIZE: f32 = 0.02;
+
+const BORDER_COLOR: [f32; 3] = [0.3, 0.3, 0.3];
+const TARGET_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+const AIMED_COLOR: [f32; 3] = [0.0, 1.0, 0.0];
+const MISAIMED_COLOR: [f32; 3] = [1.0, 0.0, 0.0];
+
+/// Draws where the cameras think they are relative to the tag: a top-down view on the top half of
+/// the window and a side view on the bottom half. Each camera gets a line along its viewing
+/// direction that is as long as its distance to the target, so a camera that's aimed well has a
+/// line that ends on the target
+pub fn draw_rig_view(streams: &Streams, overlay: &Overlay, display: &Display, target: &mut glium::Frame) {
+    let (width, height) = target.get_dimensions();
+
+    let top_down = Rect { left: 0, bottom: height / 2, width, height: height / 2 };
+    let side = Rect { left: 0, bottom: 0, width, height: height / 2 };
+
+    let cameras: Vec<(Point3<f64>, Vector3<f64>)> = streams.camera_poses()
+        .map(|(_, pose)| (
+            Point3::from(pose.translation.vector),
+            pose.rotation * Vector3::z(),
+        ))
+        .collect();
+
+    // both views use the same scale so that distances can be compared between them
+    let extent = cameras.iter()
+        .map(|(position, _)| position.coords.amax())
+        .fold(MINIMUM_EXTENT_METERS, f64::max) * 1.2;
+
+    for &(projection, viewport) in &[(Projection::TopDown, top_down), (Projection::Side, side)] {
+        overlay.draw_border(display, target, viewport, BORDER_COLOR);
+
+        // keep the same number of meters per pixel horizontally and vertically
+        let shorter_side = viewport.width.min(viewport.height) as f64;
+        let scale_x = shorter_side / (viewport.width as f64 * extent);
+        let scale_y = shorter_side / (viewport.height as f64 * extent);
+
+        let to_screen = |point: Point3<f64>| {
+            let (x, y) = projection.project(point);
+            OverlayVertex::new((x*scale_x) as f32, (y*scale_y) as f32)
+        };
+
+        let target_marker = square_marker(to_screen(Point3::origin()), MARKER_HALF_SIZE);
+        overlay.draw(display, target, viewport, &target_marker, PrimitiveType::LinesList, TARGET_COLOR);
+
+        for &(position, direction) in cameras.iter() {
+            let distance = position.coords.norm();
+            let aimed_at = position + direction*distance;
+
+            let color = if direction.angle(&-position.coords) <= MISAIM_TOLERANCE_RADIANS {
+                AIMED_COLOR
+            } else {
+                MISAIMED_COLOR
+            };
+
+            let mut vertices = square_marker(to_screen(position), MARKER_HALF_SIZE).to_vec();
+            vertices.push(to_screen(position));
+            vertices.push(to_screen(aimed_at));
+
+            overlay.draw(display, target, viewport, &vertices, PrimitiveType::LinesList, color);
+        }
+    }
+}
+
+/// The coordinates are in the frame of the tag, which has x to the right, y down, and z pointing
+/// away from the cameras
+#[derive(Copy, Clone)]
+enum Projection {
+    /// Looking down from above, with the cameras at the bottom of the view
+    TopDown,
+    /// Looking from the right of the target, with the cameras on the left of the view
+    Side,
+}
+
+impl Projection {
+    fn project(self, point: Point3<f64>) -> (f64, f64) {
+        match self {
+            Projection::TopDown => (point.x, point.z),
+            Projection::Side => (point.z, -point.y),
+        }
+    }
+}
@@ -0,0 +1,6 @@
+#version 140
+uniform vec3 color;
+out vec4 out_color;
+void main() {
+    out_color = vec4(color, 1.0);
+}
@@ -1,10 +1,10 @@
 use std::fs;
 use std::collections::{HashMap};
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc::Receiver;
-use chrono::Local;
+use chrono::{DateTime, Local, Utc};
 use glium::{Display, DrawParameters, glutin, implement_vertex, IndexBuffer, program, Program, Rect, Surface, uniform, VertexBuffer};
 use glium::backend::glutin::glutin::dpi::PhysicalPosition;
 use glium::backend::glutin::glutin::event_loop::EventLoop;
@@ -13,17 +13,27 @@ use glium::glutin::event::{ElementState, Event, KeyboardInput, MouseButton, Virt
 use glium::glutin::event_loop::ControlFlow;
 use glium::index::PrimitiveType;
 use glium::texture::{RawImage2d};
-use image::{ImageFormat, RgbImage};
+use image::RgbImage;
 use apriltag::{ApriltagDetector, TagFamily};
 
 use crate::{INITIAL_WINDOW_HEIGHT, INITIAL_WINDOW_WIDTH, STREAM_ASPECT_HEIGHT, STREAM_ASPECT_WIDTH, VIDEO_FRAMERATE};
 use crate::frame_receiver::Message;
 use crate::mpeg_encoder::MpegEncoder;
-use crate::picture::{rotation_matrix};
+use crate::picture::{rotation_matrix, fill_gaps, decode_frame};
 use crate::streams::{Streams, StreamOrdinal, StreamSource};
 use crate::layout_engine::LayoutEngine;
+use crate::overlay::Overlay;
+use crate::rig_view::draw_rig_view;
+use crate::reconstruction::export_reconstruction;
+use crate::burst::BurstCapture;
+use crate::sync::{format_spread, LatencyCorrections};
+use crate::timecode::{self, TimecodeDisplay};
+use crate::helper_status::{HelperStatuses, FLEET_VIEW_LEGEND};
+use crate::camera_formats::CameraFormatTracker;
+use crate::config::{Config, GapFill};
+use crate::timing_view::{TimingHistory, draw_timing_view};
 use std::net::SocketAddr;
-use orbit_types::CapturedFrame;
+use orbit_types::{CapturedFrame, PreviewSettings};
 use glutin::window::WindowBuilder;
 use glutin::ContextBuilder;
 
@@ -34,9 +44,26 @@ pub struct State {
     apriltag_detector: ApriltagDetector,
 
     selected: Option<(StreamOrdinal, f64, f64)>,
+    showing_rig_view: bool,
+    showing_timing_view: bool,
+    showing_fleet_view: bool,
+    timing_history: TimingHistory,
+    config: Config,
+    showing_timecode: bool,
+    timecode_display: TimecodeDisplay,
+    latency_corrections: LatencyCorrections,
+    helper_statuses: HelperStatuses,
+    camera_formats: CameraFormatTracker,
 
     picture_event_state: PictureEventState,
     still_purpose: HashMap<PictureEvent, StillPurpose>,
+    last_burst: Option<BurstCapture>,
+    /// When the recording in progress started
+    recording_since: Option<DateTime<Utc>>,
+    /// When the last recording started and stopped, for fetching it from the helpers
+    last_recording: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// A video capture that's missing cameras, waiting for the operator to render or discard it
+    incomplete_video: Option<Vec<(SocketAddr, Vec<CapturedFrame>)>>,
 
     cursor_position: PhysicalPosition<f64>,
 
@@ -49,10 +76,19 @@ pub struct State {
     selection_box_vertex_buffer: VertexBuffer<SelectionBoxVertex>,
     selection_box_index_buffer: IndexBuffer<u16>,
     selection_box_shaders: Program,
+
+    overlay: Overlay,
 }
 
 impl State {
-    pub fn new(window_width: u32, window_height: u32, event_loop: &EventLoop<()>, picture_event_state: PictureEventState) -> State {
+    pub fn new(
+        window_width: u32,
+        window_height: u32,
+        event_loop: &EventLoop<()>,
+        picture_event_state: PictureEventState,
+        latency_corrections: LatencyCorrections,
+        config: Config,
+    ) -> State {
         let wb = WindowBuilder::new()
             .with_inner_size(LogicalSize::new(INITIAL_WINDOW_WIDTH as f32, INITIAL_WINDOW_HEIGHT as f32))
             .with_title("Orbit Station");
@@ -103,14 +139,31 @@ impl State {
             },
         ).unwrap();
 
+        let overlay = Overlay::new(&display);
+        let helper_statuses = HelperStatuses::new(config.health_thresholds());
+
         State {
             apriltag_detector: ApriltagDetector::new(TagFamily::Tag36h11),
             layout: LayoutEngine::new(window_width, window_height, 0),
             streams: Streams::new(),
             selected: None,
+            showing_rig_view: false,
+            showing_timing_view: false,
+            showing_fleet_view: false,
+            timing_history: TimingHistory::new(),
+            config,
+            showing_timecode: false,
+            timecode_display: TimecodeDisplay::new(),
+            latency_corrections,
+            helper_statuses,
+            camera_formats: CameraFormatTracker::new(),
 
             picture_event_state,
             still_purpose: HashMap::new(),
+            last_burst: None,
+            recording_since: None,
+            last_recording: None,
+            incomplete_video: None,
             cursor_position: PhysicalPosition::new(0.0, 0.0),
 
             display,
@@ -120,6 +173,8 @@ impl State {
             selection_box_vertex_buffer,
             selection_box_index_buffer,
             selection_box_shaders,
+
+            overlay,
         }
     }
     
@@ -154,6 +209,55 @@ impl State {
                     KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::Return), .. } => {
                         self.request_still(StillPurpose::Calibration);
                     },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::B), .. } => {
+                        self.request_still(StillPurpose::Burst);
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::A), .. } => {
+                        let armed = self.picture_event_state.toggle_armed();
+                        println!("{}", if armed { "arming" } else { "disarming" });
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::E), .. } => {
+                        self.request_still(StillPurpose::Reconstruction);
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::Tab), .. } => {
+                        self.showing_rig_view = !self.showing_rig_view;
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::T), .. } => {
+                        self.showing_timing_view = !self.showing_timing_view;
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::H), .. } => {
+                        self.showing_fleet_view = !self.showing_fleet_view;
+                        if self.showing_fleet_view { println!("{}", FLEET_VIEW_LEGEND) }
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::S), .. } => {
+                        self.showing_timecode = !self.showing_timecode;
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::L), .. } => {
+                        self.request_still(StillPurpose::Latency);
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::R), .. } => {
+                        self.toggle_recording();
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::G), .. } => {
+                        self.fetch_recording();
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::F), .. } => {
+                        self.toggle_focus();
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::O), .. } => {
+                        self.streams.forget_manual_orientation();
+                        println!("the next calibration will rotate every camera, even the ones flipped by hand");
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::Y), .. } => {
+                        if let Some(devices) = self.incomplete_video.take() {
+                            save_video(&self.streams, devices, Some(self.config.gap_fill));
+                        }
+                    },
+                    KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(VirtualKeyCode::N), .. } => {
+                        if self.incomplete_video.take().is_some() {
+                            println!("discarded the incomplete capture");
+                        }
+                    },
                     _ => {},
                 }
                 _ => {}
@@ -170,22 +274,183 @@ impl State {
 
     fn message_handler(&mut self, message: Message) {
         match message {
-            Message::StreamDeregistered(stream_id) => self.streams.deregister_stream(stream_id),
+            Message::StreamDeregistered(stream_id) => {
+                self.picture_event_state.unfocus(stream_id);
+                self.camera_formats.remove(stream_id);
+                self.streams.deregister_stream(stream_id);
+            },
             Message::NewImage(stream_id, image) => self.register_frame(stream_id, image),
-            Message::Stills(pictures_taken_start, devices) => match self.still_purpose.get(&pictures_taken_start) {
-                Some(StillPurpose::Video) => save_video(&self.streams, devices),
-                Some(StillPurpose::Calibration) => self.streams.calibrate(devices, &mut self.apriltag_detector),
-                None => println!("received unknown picture"),
+            Message::Stills(pictures_taken_start, requested_at, devices) => {
+                self.timing_history.record(requested_at, &devices, &self.streams, self.config.sync_tolerance_millis);
+                self.handle_stills(pictures_taken_start, devices);
+            },
+            Message::Originals(pictures_taken_start, devices) => {
+                println!("received the full resolution stills");
+                self.handle_stills(pictures_taken_start, devices);
+            },
+            Message::Burst(pictures_taken_start, devices) => match self.still_purpose.get(&pictures_taken_start) {
+                Some(StillPurpose::Burst) => self.save_burst(devices),
+                _ => println!("received unknown burst"),
+            },
+            Message::Recording(devices) => self.save_recording(devices),
+            Message::Detections(pictures_taken_start, devices) => match self.still_purpose.get(&pictures_taken_start) {
+                Some(StillPurpose::Calibration) => self.streams.calibrate_from_detections(devices),
+                _ => println!("received unknown detections"),
+            },
+            Message::HelperStatus(socket_addr, status) => {
+                self.helper_statuses.update(socket_addr, status);
+                self.display.gl_window().window().set_title(&self.helper_statuses.title());
+            },
+            Message::HelperHealth(socket_addr, health) => {
+                self.helper_statuses.update_health(socket_addr, health);
+                self.display.gl_window().window().set_title(&self.helper_statuses.title());
+            },
+            Message::CameraFormats(stream_id, formats) => self.camera_formats.update(stream_id, formats),
+        }
+    }
+
+    fn handle_stills(&mut self, pictures_taken_start: PictureEvent, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>) {
+        let missing = self.streams.missing_from(&devices);
+        if !missing.is_empty() {
+            println!("capture is missing {} cameras: {:?}", missing.len(), missing);
+        }
+
+        match self.still_purpose.get(&pictures_taken_start) {
+            Some(StillPurpose::Video) if !missing.is_empty() => {
+                println!("press Y to render it anyway, filling in the gaps with {:?}, or N to discard it", self.config.gap_fill);
+                self.incomplete_video = Some(devices);
+            },
+            Some(StillPurpose::Video) => save_video(&self.streams, devices, None),
+            Some(StillPurpose::Calibration) => self.streams.calibrate(devices, &mut self.apriltag_detector),
+            Some(StillPurpose::Reconstruction) => export_reconstruction(&self.streams, devices),
+            Some(StillPurpose::Latency) => self.measure_latency(devices),
+            Some(StillPurpose::Burst) | None => println!("received unknown picture"),
+        }
+    }
+
+    /// Reads the timecode each camera photographed to find out how late its timestamps are. The
+    /// screen takes a while to show each frame, but that's the same for every camera, so we only
+    /// correct for how far each camera is from the average
+    fn measure_latency(&mut self, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>) {
+        let mut latencies = Vec::new();
+
+        for (socket_addr, stills) in devices {
+            for still in stills {
+                let source = StreamSource::new(socket_addr, still.device_id());
+
+                let image = decode_frame(&still);
+
+                let shown_at = image.ok()
+                    .and_then(|image| timecode::decode(&image.into_luma8()))
+                    .and_then(|code| self.timecode_display.shown_at(code));
+
+                match shown_at {
+                    Some(shown_at) => latencies.push((source, *still.captured_at() - shown_at)),
+                    None => println!("couldn't read the timecode from {:?}", source),
+                }
             }
         }
+
+        if latencies.is_empty() { return }
+
+        let total_micros: i64 = latencies.iter()
+            .map(|(_, latency)| latency.num_microseconds().unwrap_or(0))
+            .sum();
+        let average = chrono::Duration::microseconds(total_micros / latencies.len() as i64);
+
+        for (source, latency) in latencies {
+            let correction = self.latency_corrections.add(source, latency - average);
+            println!("{:?} is {} from the average, correcting by {}", source, format_spread(Some(latency - average)), format_spread(Some(correction)));
+        }
+
+        self.showing_timecode = false;
+    }
+
+    fn save_burst(&mut self, devices: Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)>) {
+        let burst = BurstCapture::new(&self.streams, devices);
+
+        let dir = output_dir();
+        fs::create_dir(&dir).unwrap();
+
+        match burst.save(&dir, &self.streams) {
+            Ok(()) => println!("saved {} burst frames to {:?}", burst.frame_count(), dir),
+            Err(e) => println!("failed to save burst to {:?}: {:?}", dir, e),
+        }
+
+        let synchronized = burst.synchronized();
+        println!("best synchronized burst frames have a spread of {}", format_spread(synchronized.as_ref().map(|&(_, spread)| spread)));
+        if let Some((frames, _)) = synchronized {
+            write_video(&dir, &self.streams, frames, Some(self.config.gap_fill));
+        }
+
+        self.last_burst = Some(burst);
     }
 
-    fn draw(&self) {
+    fn save_recording(&mut self, devices: Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)>) {
+        let recording = BurstCapture::new(&self.streams, devices);
+
+        let dir = output_dir();
+        fs::create_dir(&dir).unwrap();
+
+        match recording.save(&dir, &self.streams) {
+            Ok(()) => println!("saved {} recorded frames to {:?}", recording.frame_count(), dir),
+            Err(e) => println!("failed to save recording to {:?}: {:?}", dir, e),
+        }
+    }
+
+    fn toggle_recording(&mut self) {
+        let recording = self.picture_event_state.toggle_recording();
+
+        if recording {
+            println!("recording");
+            self.recording_since = Some(Utc::now());
+        } else if let Some(since) = self.recording_since.take() {
+            println!("stopped recording, press G to fetch it");
+            self.last_recording = Some((since, Utc::now()));
+        }
+    }
+
+    fn fetch_recording(&mut self) {
+        match self.last_recording {
+            Some((start, end)) => {
+                println!("fetching the recording from {:?} to {:?}", start, end);
+                self.picture_event_state.request(CaptureKind::Recording { start, end });
+            },
+            None => println!("nothing has been recorded yet"),
+        }
+    }
+
+    fn draw(&mut self) {
         let crop_factor = self.streams.crop_factor() as f32;
         let mut target = self.display.draw();
 
         target.clear_color(0.0, 0.0, 0.0, 0.0);
 
+        if self.showing_timecode {
+            self.timecode_display.draw(&self.overlay, &self.display, &mut target);
+            target.finish().unwrap();
+            self.timecode_display.presented();
+            return;
+        }
+
+        if self.showing_rig_view {
+            draw_rig_view(&self.streams, &self.overlay, &self.display, &mut target);
+            target.finish().unwrap();
+            return;
+        }
+
+        if self.showing_fleet_view {
+            self.helper_statuses.draw_fleet_view(&self.overlay, &self.display, &mut target);
+            target.finish().unwrap();
+            return;
+        }
+
+        if self.showing_timing_view {
+            draw_timing_view(&self.timing_history, self.config.sync_tolerance_millis, &self.overlay, &self.display, &mut target);
+            target.finish().unwrap();
+            return;
+        }
+
         // display all the tiles and save the selected one
         let mut last = None;
         for (tile_index, image, rotation_angle) in self.streams.iter() {
@@ -215,6 +480,8 @@ impl State {
             }
         }
 
+        self.helper_statuses.draw(&self.overlay, &self.display, &mut target);
+
         target.finish().unwrap();
     }
 
@@ -233,7 +500,7 @@ impl State {
 
     fn request_still(&mut self, purpose: StillPurpose) {
         println!("requested frame for {:?}", purpose);
-        let event = self.picture_event_state.request();
+        let event = self.picture_event_state.request(purpose.capture_kind(&self.config));
         self.still_purpose.insert(event, purpose);
 
     }
@@ -262,30 +529,131 @@ impl State {
             self.streams.flip(tile);
         }
     }
+
+    fn toggle_focus(&mut self) {
+        if let Some(tile) = self.hovering_over() {
+            let source = self.streams.source(tile);
+            let focused = self.picture_event_state.toggle_focus(source);
+            println!("{} {:?}", if focused { "focusing on" } else { "unfocusing" }, source);
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone)]
 pub struct PictureEvent(u32);
 
 #[derive(Clone)]
-pub struct PictureEventState(Arc<AtomicU32>);
+pub struct PictureEventState {
+    event: Arc<AtomicU32>,
+    latest_request: Arc<Mutex<CaptureRequest>>,
+    /// Whether the helpers should keep their cameras open at snap resolution
+    armed: Arc<AtomicBool>,
+    /// The camera whose previews are bigger and faster than the others
+    focused: Arc<Mutex<Option<StreamSource>>>,
+    /// Whether the helpers should write what their cameras capture to disk
+    recording: Arc<AtomicBool>,
+}
 
 impl PictureEventState {
     pub fn new() -> PictureEventState {
-        PictureEventState(Arc::new(AtomicU32::new(0)))
+        PictureEventState {
+            event: Arc::new(AtomicU32::new(0)),
+            latest_request: Arc::new(Mutex::new(CaptureRequest { requested_at: Utc::now(), kind: CaptureKind::Still })),
+            armed: Arc::new(AtomicBool::new(false)),
+            focused: Arc::new(Mutex::new(None)),
+            recording: Arc::new(AtomicBool::new(false)),
+        }
     }
 
-    fn request(&self) -> PictureEvent {
-        let index = self.0.fetch_add(1, Ordering::SeqCst);
+    fn request(&self, kind: CaptureKind) -> PictureEvent {
+        *self.latest_request.lock().unwrap() = CaptureRequest { requested_at: Utc::now(), kind };
+        let index = self.event.fetch_add(1, Ordering::SeqCst);
         PictureEvent(index)
     }
 
+    pub fn latest_request(&self) -> CaptureRequest {
+        *self.latest_request.lock().unwrap()
+    }
+
     pub fn current_event(&self) -> PictureEvent {
-        PictureEvent(self.0.load(Ordering::Relaxed))
+        PictureEvent(self.event.load(Ordering::Relaxed))
     }
 
     pub fn has_been_new_event_since(&self, last_event: PictureEvent) -> bool {
-        last_event.0 < self.0.load(Ordering::Relaxed)
+        last_event.0 < self.event.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether we are now armed
+    fn toggle_armed(&self) -> bool {
+        !self.armed.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed.load(Ordering::Relaxed)
+    }
+
+    /// Focuses on `source`, or stops focusing if it already was. Returns whether it's now focused
+    fn toggle_focus(&self, source: StreamSource) -> bool {
+        let mut focused = self.focused.lock().unwrap();
+        *focused = if *focused == Some(source) { None } else { Some(source) };
+        focused.is_some()
+    }
+
+    fn unfocus(&self, source: StreamSource) {
+        let mut focused = self.focused.lock().unwrap();
+        if *focused == Some(source) {
+            *focused = None;
+        }
+    }
+
+    pub fn focused(&self) -> Option<StreamSource> {
+        *self.focused.lock().unwrap()
+    }
+
+    /// Returns whether we are now recording
+    fn toggle_recording(&self) -> bool {
+        !self.recording.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct CaptureRequest {
+    pub requested_at: DateTime<Utc>,
+    pub kind: CaptureKind,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum CaptureKind {
+    /// One frame from every camera
+    Still,
+    /// Every frame from every camera over `BURST_DURATION_MILLIS`
+    Burst,
+    /// One frame from every camera, scaled down to the settings. The originals follow once the
+    /// helpers have sent them
+    Thumbnails(PreviewSettings),
+    /// One frame from every camera, searched for the tag by the helpers
+    Detection,
+    /// The frames the helpers recorded between `start` and `end`
+    Recording {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+}
+
+impl CaptureKind {
+    /// For labelling metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaptureKind::Still => "still",
+            CaptureKind::Thumbnails(_) => "thumbnails",
+            CaptureKind::Burst => "burst",
+            CaptureKind::Detection => "detection",
+            CaptureKind::Recording { .. } => "recording",
+        }
     }
 }
 
@@ -375,10 +743,35 @@ fn draw_panel(
 enum StillPurpose {
     Calibration,
     Video,
+    /// Export the stills with the calibrated camera poses for 3D reconstruction
+    Reconstruction,
+    Burst,
+    /// Photograph the timecode to measure how late each camera's timestamps are
+    Latency,
+}
+
+impl StillPurpose {
+    fn capture_kind(&self, config: &Config) -> CaptureKind {
+        match (self, config.thumbnail_settings()) {
+            (StillPurpose::Burst, _) => CaptureKind::Burst,
+            (StillPurpose::Calibration, _) if config.detect_tags_on_helpers => CaptureKind::Detection,
+            (StillPurpose::Video, Some(thumbnail)) => CaptureKind::Thumbnails(thumbnail),
+            (StillPurpose::Calibration, _)
+            | (StillPurpose::Video, None)
+            | (StillPurpose::Reconstruction, _)
+            | (StillPurpose::Latency, _) => CaptureKind::Still,
+        }
+    }
 }
 
-fn save_video(streams: &Streams, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>) {
-    let mut devices: Vec<_> = devices.into_iter()
+/// A new directory name under `outputs` for each capture. The timestamp has no spaces or colons,
+/// which some filesystems and tools don't accept
+pub fn output_dir() -> PathBuf {
+    PathBuf::from("outputs").join(Local::now().format("%Y-%m-%d_%H-%M-%S%.3f").to_string())
+}
+
+fn save_video(streams: &Streams, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>, gap_fill: Option<GapFill>) {
+    let devices: Vec<_> = devices.into_iter()
         .map(|(addr, stills)|
             stills.into_iter().map(move |still| {
                 let source = StreamSource::new(addr, still.device_id());
@@ -387,11 +780,31 @@ fn save_video(streams: &Streams, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>)
         .flatten()
         .collect();
 
-    devices.sort_by_key(|&(s, _)| streams.get_stream_tile(s));
-
-    let dir = PathBuf::from(format!("outputs/{}", Local::now()));
+    let dir = output_dir();
     fs::create_dir(&dir).unwrap();
 
+    write_video(&dir, streams, devices, gap_fill);
+}
+
+/// Saves the frames in tile order as a video and as a sequence of photos. Tiles without a frame are
+/// filled in with `gap_fill`, or left out if there isn't one
+fn write_video(dir: &Path, streams: &Streams, devices: Vec<(StreamSource, CapturedFrame)>, gap_fill: Option<GapFill>) {
+    let mut frames: Vec<Option<RgbImage>> = vec![None; streams.stream_count()];
+
+    for (source, image) in devices.into_iter() {
+        let image = decode_frame(&image);
+
+        if let Ok(image) = image {
+            if let Some(ordinal) = streams.get_stream_tile(source) {
+                frames[ordinal.index()] = Some(streams.transform_image(ordinal, &image).into_rgb8());
+            }
+        }
+    }
+
+    if let Some(gap_fill) = gap_fill {
+        fill_gaps(&mut frames, gap_fill);
+    }
+
     let mut video = MpegEncoder::new_with_params(
         dir.join("video.mp4"),
         1080,
@@ -403,23 +816,12 @@ fn save_video(streams: &Streams, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>)
         None,
     );
 
-    for (source, image) in devices.into_iter() {
-        println!("found frame at {:?}", image.captured_at());
-
-        let image = image::load_from_memory_with_format(
-            image.frame_data(),
-            ImageFormat::Jpeg,
-        );
-
-        if let Ok(image) = image {
-            if let Some(ordinal) = streams.get_stream_tile(source) {
-                let image = streams.transform_image(ordinal, &image);
-
-                // save to photo sequence
-                image.save(dir.join(format!("frame{:02}.png", ordinal.index()))).unwrap();
-                // save to video
-                video.encode_image(image.as_rgb8().unwrap());
-            }
+    for (index, image) in frames.iter().enumerate() {
+        if let Some(image) = image {
+            // save to photo sequence
+            image.save(dir.join(format!("frame{:02}.png", index))).unwrap();
+            // save to video
+            video.encode_image(image);
         }
     }
-}
\ No newline at end of file
+}
@@ -4,9 +4,10 @@ use std::net::SocketAddr;
 
 use glium::texture::{ClientFormat, RawImage2d};
 use image::{DynamicImage, RgbImage};
+use nalgebra::Isometry3;
 
-use apriltag::{ApriltagDetector};
-use orbit_types::CapturedFrame;
+use apriltag::{ApriltagDetector, ApriltagDetection, EulerAngles};
+use orbit_types::{CapturedFrame, CameraDetections};
 use orbit_types::DeviceId;
 
 use crate::calibration::{Adjustment, CalibrationEvent};
@@ -37,20 +38,42 @@ impl Streams {
             None => self.streams.push(StreamInfo {
                 source,
                 flip_flop: FlipFlop::new(image.width(), image.height()),
+                manually_oriented: false,
                 adjustment: None,
+                camera_pose: None,
                 image,
             }),
         }
     }
 
     pub fn calibrate(&mut self, devices: Vec<(SocketAddr, Vec<CapturedFrame>)>, detector: &mut ApriltagDetector) {
-        self.calibration_events.push(CalibrationEvent::new(devices, detector, self));
+        let calibration_event = CalibrationEvent::new(devices, detector, self);
+        self.add_calibration_event(calibration_event);
+    }
+
+    /// Like `calibrate`, but the helpers already searched their stills for the tag
+    pub fn calibrate_from_detections(&mut self, devices: Vec<(SocketAddr, Vec<CameraDetections>)>) {
+        let calibration_event = CalibrationEvent::from_helper_detections(devices, self);
+        self.add_calibration_event(calibration_event);
+    }
+
+    fn add_calibration_event(&mut self, calibration_event: CalibrationEvent) {
+        self.calibration_events.push(calibration_event);
 
         self.crop_factor = 1.0;
         for stream_info in self.streams.iter_mut() {
             let adjustment = Adjustment::new(stream_info.source, &self.calibration_events);
             stream_info.adjustment = Some(adjustment);
             self.crop_factor = self.crop_factor.min(adjustment.get_crop_factor());
+
+            // the tag might have moved between calibration events, so poses from older ones
+            // can't be compared with the latest. Cameras that didn't find it this time have none
+            stream_info.camera_pose = self.calibration_events.last()
+                .and_then(|event| event.camera_pose(stream_info.source));
+
+            if let Some(pose) = stream_info.camera_pose {
+                println!("{:?} is {:.2}m from the target", stream_info.source, pose.translation.vector.norm());
+            }
         }
 
         println!("calibrated");
@@ -62,12 +85,24 @@ impl Streams {
         }
     }
 
-    pub fn transform_image(&self, ordinal: StreamOrdinal, image: &DynamicImage) -> DynamicImage {
-        self.streams[ordinal.index].transform_image(image, self.crop_factor)
+    /// Sets the cardinal rotation of the stream so that the tag in `detection` ends up upright,
+    /// and returns the euler angles of the tag as seen in the cardinally rotated image. Streams
+    /// the operator flipped by hand keep their rotation
+    pub fn orient_from_detection(&mut self, ordinal: StreamOrdinal, detection: &ApriltagDetection) -> EulerAngles {
+        let stream_info = &mut self.streams[ordinal.index];
+        stream_info.orient(detection.image_rotation());
+        detection.euler_angles_in_rotated_image(stream_info.flip_flop.clockwise_quarter_turns())
+    }
+
+    /// Lets the next calibration rotate the streams the operator flipped by hand
+    pub fn forget_manual_orientation(&mut self) {
+        for stream_info in self.streams.iter_mut() {
+            stream_info.manually_oriented = false;
+        }
     }
 
-    pub fn cardinal_transform_image(&self, ordinal: StreamOrdinal, image: &DynamicImage) -> DynamicImage {
-        self.streams[ordinal.index].cardinal_transform_image(image)
+    pub fn transform_image(&self, ordinal: StreamOrdinal, image: &DynamicImage) -> DynamicImage {
+        self.streams[ordinal.index].transform_image(image, self.crop_factor)
     }
 
     pub fn remove_and_insert(&mut self, old: StreamOrdinal, new: StreamOrdinal) {
@@ -87,6 +122,10 @@ impl Streams {
         }
     }
 
+    pub fn source(&self, ordinal: StreamOrdinal) -> StreamSource {
+        self.streams[ordinal.index].source
+    }
+
     pub fn deregister_stream(&mut self, source: StreamSource) {
         if let Some(place) = self.streams.iter().position(|s| s.source == source) {
             self.streams.remove(place);
@@ -98,6 +137,27 @@ impl Streams {
             .map(move |(index, stream_info)| (StreamOrdinal { index }, stream_info.glium_image(), stream_info.total_rotation_angle()))
     }
 
+    /// Where the camera is relative to the tag, if it found the tag in the latest calibration
+    pub fn camera_pose(&self, ordinal: StreamOrdinal) -> Option<Isometry3<f64>> {
+        self.streams[ordinal.index].camera_pose
+    }
+
+    pub fn camera_poses(&self) -> impl Iterator<Item=(StreamOrdinal, Isometry3<f64>)> + '_ {
+        self.streams.iter().enumerate()
+            .filter_map(|(index, stream_info)| Some((StreamOrdinal { index }, stream_info.camera_pose?)))
+    }
+
+    /// The cameras on screen that don't have a frame in `devices`, in tile order
+    pub fn missing_from(&self, devices: &[(SocketAddr, Vec<CapturedFrame>)]) -> Vec<StreamSource> {
+        self.streams.iter()
+            .map(|stream_info| stream_info.source)
+            .filter(|source| !devices.iter().any(|(socket_addr, stills)| {
+                *socket_addr == source.socket_addr
+                    && stills.iter().any(|still| still.device_id() == source.device_id)
+            }))
+            .collect()
+    }
+
     pub fn get_stream_tile(&self, source: StreamSource) -> Option<StreamOrdinal> {
         let inner = self.streams.iter().position(|s| s.source == source)?;
         Some(StreamOrdinal { index: inner })
@@ -114,6 +174,14 @@ impl StreamSource {
     pub fn new(socket_addr: SocketAddr, device_id: DeviceId) -> StreamSource {
         StreamSource { socket_addr, device_id }
     }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.socket_addr
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -130,13 +198,25 @@ impl StreamOrdinal {
 struct StreamInfo {
     source: StreamSource,
     flip_flop: FlipFlop,
+    /// The operator flipped the stream, so calibration leaves its rotation alone
+    manually_oriented: bool,
     adjustment: Option<Adjustment>,
+    /// Where the camera is relative to the tag, from the latest calibration that found the tag
+    camera_pose: Option<Isometry3<f64>>,
     image: RgbImage,
 }
 
 impl StreamInfo {
     fn flip(&mut self) {
         self.flip_flop.flip();
+        self.manually_oriented = true;
+    }
+
+    /// Undoes a tag that appears rotated clockwise by `image_rotation` radians
+    fn orient(&mut self, image_rotation: f64) {
+        if !self.manually_oriented {
+            self.flip_flop = FlipFlop::from_image_rotation(image_rotation);
+        }
     }
 
     fn glium_image(&self) -> RawImage2d<u8> {
@@ -158,10 +238,6 @@ impl StreamInfo {
         image.crop_rotate(radians, crop_factor as f32)
     }
 
-    fn cardinal_transform_image(&self, image: &DynamicImage) -> DynamicImage {
-        self.flip_flop.rotate_image(image)
-    }
-
     fn total_rotation_angle(&self) -> f64 {
         self.flip_flop.get_angle() + self.adjustment.map_or(0.0, |a| a.roll())
     }
@@ -181,10 +257,33 @@ impl FlipFlop {
         FlipFlop { flop: is_horizontal, flip: false }
     }
 
+    /// Picks the rotation that undoes a tag that appears rotated clockwise by `image_rotation` radians
+    fn from_image_rotation(image_rotation: f64) -> FlipFlop {
+        let quarter_turns = (image_rotation / (TAU/4.0)).round().rem_euclid(4.0) as u32;
+
+        // turning the image clockwise the rest of the way around puts the tag upright
+        match (4 - quarter_turns) % 4 {
+            0 => FlipFlop { flop: false, flip: false },
+            1 => FlipFlop { flop: true, flip: true },
+            2 => FlipFlop { flop: false, flip: true },
+            _ => FlipFlop { flop: true, flip: false },
+        }
+    }
+
     fn flip(&mut self) {
         self.flip = !self.flip;
     }
 
+    /// How many times `rotate_image` turns the image clockwise by 90 degrees
+    fn clockwise_quarter_turns(self) -> u32 {
+        match self {
+            FlipFlop { flop: false, flip: false } => 0,
+            FlipFlop { flop: true, flip: true } => 1,
+            FlipFlop { flop: false, flip: true } => 2,
+            FlipFlop { flop: true, flip: false } => 3,
+        }
+    }
+
     fn rotate_image(self, image: &DynamicImage) -> DynamicImage {
         // N.B.: `image` likes to rotate images clockwise, and the convention with math
         // is that we do rotations counter-clockwise. So that's why the angles here don't match
@@ -206,3 +305,196 @@ impl FlipFlop {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use image::Rgb;
+    use chrono::{TimeZone, Utc};
+    use nalgebra::{Point3, Rotation3, Vector3};
+    use orbit_types::{DeviceIdGenerator, TagDetection};
+
+    fn quarter_turns(turns: f64) -> f64 {
+        turns * TAU/4.0
+    }
+
+    fn stream_info(width: u32, height: u32) -> StreamInfo {
+        let socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000));
+        StreamInfo {
+            source: StreamSource::new(socket_addr, DeviceIdGenerator::new().next()),
+            flip_flop: FlipFlop::new(width, height),
+            manually_oriented: false,
+            adjustment: None,
+            camera_pose: None,
+            image: RgbImage::new(width, height),
+        }
+    }
+
+    /// Turns `image` clockwise by 90 degrees `turns` times
+    fn rotated(image: &DynamicImage, turns: u32) -> DynamicImage {
+        (0..turns).fold(image.clone(), |image, _| image.rotate90())
+    }
+
+    /// Where the only pixel of `color` is
+    fn find(image: &RgbImage, color: Rgb<u8>) -> (u32, u32) {
+        image.enumerate_pixels()
+            .find(|&(_, _, &pixel)| pixel == color)
+            .map(|(x, y, _)| (x, y))
+            .unwrap()
+    }
+
+    #[test]
+    fn every_quarter_turn_is_undone() {
+        for turns in 0..4 {
+            let flip_flop = FlipFlop::from_image_rotation(quarter_turns(turns as f64));
+            assert_eq!((turns + flip_flop.clockwise_quarter_turns()) % 4, 0, "{} quarter turns", turns);
+        }
+    }
+
+    #[test]
+    fn rotations_round_to_the_nearest_quarter_turn() {
+        assert_eq!(FlipFlop::from_image_rotation(quarter_turns(0.4)).clockwise_quarter_turns(), 0);
+        assert_eq!(FlipFlop::from_image_rotation(quarter_turns(1.3)).clockwise_quarter_turns(), 3);
+        assert_eq!(FlipFlop::from_image_rotation(quarter_turns(-1.0)).clockwise_quarter_turns(), 1);
+        assert_eq!(FlipFlop::from_image_rotation(quarter_turns(-2.2)).clockwise_quarter_turns(), 2);
+        assert_eq!(FlipFlop::from_image_rotation(quarter_turns(3.8)).clockwise_quarter_turns(), 0);
+    }
+
+    #[test]
+    fn quarter_turns_match_the_rotated_image() {
+        // a single marked pixel in the top left corner shows which way the image turned
+        let mut image = RgbImage::new(3, 2);
+        image.put_pixel(0, 0, Rgb([255, 0, 0]));
+        let image = DynamicImage::ImageRgb8(image);
+
+        for turns in 0..4 {
+            let flip_flop = FlipFlop::from_image_rotation(quarter_turns(turns as f64));
+            let expected = rotated(&image, flip_flop.clockwise_quarter_turns());
+
+            assert_eq!(flip_flop.rotate_image(&image).to_rgb8(), expected.to_rgb8(), "{} quarter turns", turns);
+        }
+    }
+
+    #[test]
+    fn tilted_tags_end_up_upright() {
+        // the bottom edge of an upright tag, from its left corner to its right one, measured the
+        // way `ApriltagDetection::image_rotation` does
+        let (left, right) = (Rgb([255, 0, 0]), Rgb([0, 255, 0]));
+        let mut upright = RgbImage::new(5, 5);
+        upright.put_pixel(1, 3, left);
+        upright.put_pixel(3, 3, right);
+        let upright = DynamicImage::ImageRgb8(upright);
+
+        for turns in 0..4 {
+            let tilted = rotated(&upright, turns).to_rgb8();
+            let ((x0, y0), (x1, y1)) = (find(&tilted, left), find(&tilted, right));
+            let image_rotation = (y1 as f64 - y0 as f64).atan2(x1 as f64 - x0 as f64);
+
+            let oriented = FlipFlop::from_image_rotation(image_rotation)
+                .rotate_image(&DynamicImage::ImageRgb8(tilted))
+                .to_rgb8();
+
+            assert_eq!(oriented, upright.to_rgb8(), "tilted {} quarter turns clockwise", turns);
+        }
+    }
+
+    #[test]
+    fn euler_angles_in_rotated_image() {
+        let upright = Rotation3::from_euler_angles(0.2, -0.1, 0.05);
+        let expected = ApriltagDetection::from_parts(0.0, 0, (20, 20), [(10, 30), (30, 30), (30, 10), (10, 10)], upright, Point3::new(0.0, 0.0, 1.0))
+            .euler_angles();
+        // turns the camera so the tag appears to turn a quarter clockwise in the image
+        let quarter_turn = Rotation3::from_axis_angle(&Vector3::z_axis(), TAU/4.0);
+
+        for turns in 0..4 {
+            let mut rotation = upright;
+            let mut corners = [(10, 30), (30, 30), (30, 10), (10, 10)];
+            for _ in 0..turns {
+                rotation = rotation * quarter_turn.inverse();
+                for corner in corners.iter_mut() {
+                    *corner = (40 - corner.1, corner.0);
+                }
+            }
+            let detection = ApriltagDetection::from_parts(0.0, 0, (20, 20), corners, rotation, Point3::new(0.0, 0.0, 1.0));
+
+            let mut stream_info = stream_info(640, 640);
+            stream_info.orient(detection.image_rotation());
+            let angles = detection.euler_angles_in_rotated_image(stream_info.flip_flop.clockwise_quarter_turns());
+
+            for &(angle, expected) in &[(angles.pitch, expected.pitch), (angles.roll, expected.roll), (angles.yaw, expected.yaw)] {
+                assert!((angle - expected).abs() < 1e-9, "tilted {} quarter turns clockwise", turns);
+            }
+        }
+    }
+
+    #[test]
+    fn calibration_orients_streams_nobody_flipped() {
+        let mut stream_info = stream_info(640, 480);
+
+        stream_info.orient(quarter_turns(2.0));
+
+        assert_eq!(stream_info.flip_flop.clockwise_quarter_turns(), 2);
+    }
+
+    #[test]
+    fn calibration_keeps_a_manual_flip() {
+        let mut stream_info = stream_info(640, 480);
+        stream_info.flip();
+        let flipped = stream_info.flip_flop.clockwise_quarter_turns();
+
+        stream_info.orient(quarter_turns(0.0));
+
+        assert_eq!(stream_info.flip_flop.clockwise_quarter_turns(), flipped);
+    }
+
+    #[test]
+    fn calibration_overrides_a_manual_flip_when_asked() {
+        let mut streams = Streams::new();
+        streams.streams.push(stream_info(640, 480));
+        streams.streams[0].flip();
+
+        streams.forget_manual_orientation();
+        streams.streams[0].orient(quarter_turns(0.0));
+
+        assert_eq!(streams.streams[0].flip_flop.clockwise_quarter_turns(), 0);
+    }
+
+    /// What a helper reports for a camera that's `distance` meters straight in front of the tag,
+    /// or that didn't find it
+    fn detections(device_id: DeviceId, distance: Option<f64>) -> CameraDetections {
+        let detections = distance.into_iter()
+            .map(|distance| TagDetection {
+                tag_id: 0,
+                error: 0.0,
+                image_center: (20, 20),
+                image_tag_corners: [(10, 30), (30, 30), (30, 10), (10, 10)],
+                rotation: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+                translation: [0.0, 0.0, distance],
+            })
+            .collect();
+
+        CameraDetections { device_id, captured_at: Utc.timestamp_millis(0), detections, still: None, error: None }
+    }
+
+    #[test]
+    fn camera_poses_all_come_from_the_latest_calibration() {
+        let socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2000));
+        let mut device_ids = DeviceIdGenerator::new();
+        let (moved, lost) = (device_ids.next(), device_ids.next());
+        let mut streams = Streams::new();
+        for &device_id in &[moved, lost] {
+            streams.register_frame(StreamSource::new(socket_addr, device_id), RgbImage::new(640, 480));
+        }
+        let pose = |streams: &Streams, device_id| {
+            streams.camera_pose(streams.get_stream_tile(StreamSource::new(socket_addr, device_id)).unwrap())
+        };
+
+        streams.calibrate_from_detections(vec![(socket_addr, vec![detections(moved, Some(1.0)), detections(lost, Some(1.0))])]);
+        streams.calibrate_from_detections(vec![(socket_addr, vec![detections(moved, Some(2.0)), detections(lost, None)])]);
+
+        assert_eq!(pose(&streams, moved).unwrap().translation.vector, Vector3::new(0.0, 0.0, -2.0));
+        assert!(pose(&streams, lost).is_none(), "the tag might have moved since it was found");
+        assert_eq!(streams.camera_poses().count(), 1);
+    }
+}
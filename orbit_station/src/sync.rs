@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use orbit_types::{CapturedFrame, DeviceId};
+
+use crate::streams::StreamSource;
+
+/// Picks one time from each list so that the difference between the earliest and latest picked
+/// times is as small as possible. Returns the index of the picked time in each list along with
+/// that difference, or `None` if any of the lists are empty
+pub fn select_synchronized(times: &[Vec<DateTime<Utc>>]) -> Option<(Vec<usize>, Duration)> {
+    if times.is_empty() || times.iter().any(Vec::is_empty) { return None }
+
+    // every time, labeled with which list it came from, in chronological order
+    let mut merged: Vec<(DateTime<Utc>, usize, usize)> = times.iter().enumerate()
+        .flat_map(|(list, times)| times.iter().enumerate().map(move |(index, &time)| (time, list, index)))
+        .collect();
+    merged.sort();
+
+    // slide a window over the merged times, shrinking it from the left whenever it still covers
+    // every list. The narrowest window that covers every list is the answer
+    let mut counts = vec![0usize; times.len()];
+    let mut lists_covered = 0;
+    let mut left = 0;
+    let mut best: Option<(usize, usize)> = None;
+
+    for right in 0..merged.len() {
+        let (_, list, _) = merged[right];
+        if counts[list] == 0 { lists_covered += 1 }
+        counts[list] += 1;
+
+        while lists_covered == times.len() {
+            let width = merged[right].0 - merged[left].0;
+            let is_narrowest = match best {
+                Some((l, r)) => width < merged[r].0 - merged[l].0,
+                None => true,
+            };
+            if is_narrowest {
+                best = Some((left, right));
+            }
+
+            let (_, list, _) = merged[left];
+            counts[list] -= 1;
+            if counts[list] == 0 { lists_covered -= 1 }
+            left += 1;
+        }
+    }
+
+    let (left, right) = best?;
+
+    // any time from each list inside the window will do
+    let mut indices = vec![0; times.len()];
+    for &(_, list, index) in &merged[left..=right] {
+        indices[list] = index;
+    }
+
+    Some((indices, merged[right].0 - merged[left].0))
+}
+
+/// The time between the earliest and the latest frame
+pub fn spread<'a>(frames: impl Iterator<Item=&'a CapturedFrame>) -> Option<Duration> {
+    let mut earliest: Option<DateTime<Utc>> = None;
+    let mut latest: Option<DateTime<Utc>> = None;
+
+    for frame in frames {
+        let captured_at = *frame.captured_at();
+        earliest = Some(earliest.map_or(captured_at, |t| t.min(captured_at)));
+        latest = Some(latest.map_or(captured_at, |t| t.max(captured_at)));
+    }
+
+    Some(latest? - earliest?)
+}
+
+/// Turns a burst from every camera into a single still per camera, picking the stills that were
+/// captured closest together. Cameras that didn't return any frames are left out
+pub fn synchronize_bursts(bursts: Vec<(SocketAddr, Vec<Vec<CapturedFrame>>)>) -> Vec<(SocketAddr, Vec<CapturedFrame>)> {
+    let cameras: Vec<(SocketAddr, Vec<CapturedFrame>)> = bursts.into_iter()
+        .flat_map(|(addr, bursts)| bursts.into_iter().map(move |frames| (addr, frames)))
+        .filter(|(_, frames)| !frames.is_empty())
+        .collect();
+
+    let times: Vec<Vec<DateTime<Utc>>> = cameras.iter()
+        .map(|(_, frames)| frames.iter().map(|frame| *frame.captured_at()).collect())
+        .collect();
+
+    let indices = match select_synchronized(&times) {
+        Some((indices, _)) => indices,
+        None => return Vec::new(),
+    };
+
+    let mut stills: Vec<(SocketAddr, Vec<CapturedFrame>)> = Vec::new();
+    for ((addr, mut frames), index) in cameras.into_iter().zip(indices) {
+        let frame = frames.swap_remove(index);
+        match stills.iter_mut().find(|(a, _)| *a == addr) {
+            Some((_, helper_stills)) => helper_stills.push(frame),
+            None => stills.push((addr, vec![frame])),
+        }
+    }
+
+    stills
+}
+
+pub fn format_spread(spread: Option<Duration>) -> String {
+    match spread {
+        Some(spread) => format!("{:.1}ms", spread.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0),
+        None => "unknown".to_string(),
+    }
+}
+
+/// How late each camera's timestamps are compared to the others, as measured by photographing the
+/// timecode. Shared with the capture loop so every frame gets corrected as soon as it arrives, and
+/// so the helpers can be told to aim their captures that much later
+#[derive(Clone)]
+pub struct LatencyCorrections {
+    corrections: Arc<Mutex<HashMap<StreamSource, Duration>>>,
+}
+
+impl LatencyCorrections {
+    pub fn new() -> LatencyCorrections {
+        LatencyCorrections { corrections: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Frames have already been corrected by the time we measure, so a measurement is added on to
+    /// whatever correction the camera already had
+    pub fn add(&self, source: StreamSource, latency: Duration) -> Duration {
+        let mut corrections = self.corrections.lock().unwrap();
+        let correction = corrections.entry(source).or_insert_with(Duration::zero);
+        *correction = *correction + latency;
+        *correction
+    }
+
+    /// The corrections of the cameras of one helper in microseconds, for `Request::Latencies`
+    pub fn for_helper(&self, socket_addr: SocketAddr) -> Vec<(DeviceId, i64)> {
+        let corrections = self.corrections.lock().unwrap();
+        let mut latencies: Vec<(DeviceId, i64)> = corrections.iter()
+            .filter(|(source, _)| source.socket_addr() == socket_addr)
+            .map(|(source, latency)| (source.device_id(), latency.num_microseconds().unwrap_or(0)))
+            .collect();
+
+        // so the same corrections always come out the same
+        latencies.sort();
+        latencies
+    }
+
+    pub fn apply(&self, socket_addr: SocketAddr, frames: &mut [CapturedFrame]) {
+        let corrections = self.corrections.lock().unwrap();
+        for frame in frames {
+            if let Some(&latency) = corrections.get(&StreamSource::new(socket_addr, frame.device_id())) {
+                frame.correct_captured_at(latency);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use orbit_types::DeviceIdGenerator;
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis(1_600_000_000_000 + millis)
+    }
+
+    fn times(lists: &[&[i64]]) -> Vec<Vec<DateTime<Utc>>> {
+        lists.iter().map(|list| list.iter().map(|&millis| at(millis)).collect()).collect()
+    }
+
+    fn frame(device_id: DeviceId, millis: i64) -> CapturedFrame {
+        CapturedFrame::new(device_id, 4, 4, *b"MJPG", at(millis), 0, Vec::new())
+    }
+
+    fn captured_millis(frame: &CapturedFrame) -> i64 {
+        (*frame.captured_at() - at(0)).num_milliseconds()
+    }
+
+    #[test]
+    fn finds_the_narrowest_window() {
+        let (indices, spread) = select_synchronized(&times(&[&[0, 10, 20], &[12, 30], &[19, 40]])).unwrap();
+
+        assert_eq!(indices, vec![2, 0, 0]);
+        assert_eq!(spread, Duration::milliseconds(8));
+    }
+
+    #[test]
+    fn finds_the_narrowest_window_in_unsorted_lists() {
+        let (indices, spread) = select_synchronized(&times(&[&[50, 0, 100], &[98, 3, 60]])).unwrap();
+
+        assert_eq!(indices, vec![2, 0]);
+        assert_eq!(spread, Duration::milliseconds(2));
+    }
+
+    #[test]
+    fn ties_go_to_the_earliest_window() {
+        let (indices, spread) = select_synchronized(&times(&[&[0, 10], &[2, 12]])).unwrap();
+
+        assert_eq!(indices, vec![0, 0]);
+        assert_eq!(spread, Duration::milliseconds(2));
+    }
+
+    #[test]
+    fn identical_times_have_no_spread() {
+        let (indices, spread) = select_synchronized(&times(&[&[5, 7], &[7], &[1, 7, 9]])).unwrap();
+
+        assert_eq!(indices, vec![1, 0, 1]);
+        assert_eq!(spread, Duration::zero());
+    }
+
+    #[test]
+    fn nothing_to_pick_from() {
+        assert!(select_synchronized(&[]).is_none());
+        assert!(select_synchronized(&times(&[&[]])).is_none());
+        assert!(select_synchronized(&times(&[&[0, 1], &[]])).is_none());
+    }
+
+    #[test]
+    fn a_single_camera_picks_any_of_its_times() {
+        let (indices, spread) = select_synchronized(&times(&[&[5, 3, 9]])).unwrap();
+
+        assert_eq!(indices.len(), 1);
+        assert!(indices[0] < 3);
+        assert_eq!(spread, Duration::zero());
+    }
+
+    #[test]
+    fn spread_of_frames() {
+        let mut devices = DeviceIdGenerator::new();
+        let frames = [frame(devices.next(), 30), frame(devices.next(), 10), frame(devices.next(), 25)];
+
+        assert_eq!(spread(frames.iter()), Some(Duration::milliseconds(20)));
+        assert_eq!(spread(frames[..1].iter()), Some(Duration::zero()));
+        assert_eq!(spread(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn bursts_of_different_lengths() {
+        let first_helper: SocketAddr = "192.168.2.100:2000".parse().unwrap();
+        let second_helper: SocketAddr = "192.168.2.101:2000".parse().unwrap();
+        let mut devices = DeviceIdGenerator::new();
+        let (a, b, d) = (devices.next(), devices.next(), devices.next());
+
+        let bursts = vec![
+            (first_helper, vec![
+                vec![frame(a, 0), frame(a, 33), frame(a, 66), frame(a, 100)],
+                vec![frame(b, 70)],
+            ]),
+            (second_helper, vec![
+                // a camera that didn't return anything is left out instead of spoiling the rest
+                Vec::new(),
+                vec![frame(d, 10), frame(d, 40), frame(d, 68), frame(d, 90), frame(d, 120)],
+            ]),
+        ];
+
+        let stills = synchronize_bursts(bursts);
+
+        assert_eq!(stills.len(), 2);
+        let (addr, first_stills) = &stills[0];
+        assert_eq!(*addr, first_helper);
+        assert_eq!(first_stills.iter().map(|still| (still.device_id(), captured_millis(still))).collect::<Vec<_>>(), vec![(a, 66), (b, 70)]);
+        let (addr, second_stills) = &stills[1];
+        assert_eq!(*addr, second_helper);
+        assert_eq!(second_stills.iter().map(|still| (still.device_id(), captured_millis(still))).collect::<Vec<_>>(), vec![(d, 68)]);
+    }
+
+    #[test]
+    fn no_bursts() {
+        assert!(synchronize_bursts(Vec::new()).is_empty());
+        assert!(synchronize_bursts(vec![("192.168.2.100:2000".parse().unwrap(), vec![Vec::new()])]).is_empty());
+    }
+
+    #[test]
+    fn latencies_of_one_helper() {
+        let first_helper: SocketAddr = "192.168.2.100:2000".parse().unwrap();
+        let second_helper: SocketAddr = "192.168.2.101:2000".parse().unwrap();
+        let mut devices = DeviceIdGenerator::new();
+        let (a, b, c) = (devices.next(), devices.next(), devices.next());
+
+        let corrections = LatencyCorrections::new();
+        corrections.add(StreamSource::new(first_helper, b), Duration::milliseconds(-4));
+        corrections.add(StreamSource::new(second_helper, c), Duration::milliseconds(9));
+        corrections.add(StreamSource::new(first_helper, a), Duration::milliseconds(3));
+        corrections.add(StreamSource::new(first_helper, a), Duration::microseconds(500));
+
+        assert_eq!(corrections.for_helper(first_helper), vec![(a, 3500), (b, -4000)]);
+        assert_eq!(corrections.for_helper(second_helper), vec![(c, 9000)]);
+        assert!(corrections.for_helper("192.168.2.102:2000".parse().unwrap()).is_empty());
+    }
+}
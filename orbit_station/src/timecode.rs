@@ -0,0 +1,309 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, Utc};
+use glium::{Display, Rect, Surface};
+use glium::index::PrimitiveType;
+use image::GrayImage;
+
+use crate::overlay::{Overlay, OverlayVertex};
+
+/// How many bits of the frame counter are shown. At 60 frames per second this wraps around after
+/// about 18 minutes, which is plenty because we only look up timecodes from the last few seconds
+const TIMECODE_BITS: usize = 16;
+/// White, black, white, black cells in front of the bits, so the decoder can find the pattern and
+/// measure how wide a cell is
+const PREAMBLE: [bool; 4] = [true, false, true, false];
+/// The preamble, the bits, and a parity cell
+const CELL_COUNT: usize = PREAMBLE.len() + TIMECODE_BITS + 1;
+/// Empty black cells on either side of the pattern
+const QUIET_CELLS: usize = 1;
+/// How long we remember when each timecode was on the screen
+const DISPLAY_HISTORY_MILLIS: i64 = 10_000;
+/// Preamble cells can be this much wider or narrower than their average and still count
+const CELL_WIDTH_TOLERANCE: f64 = 0.35;
+/// Scanlines are averaged over this many rows or columns on either side of the center
+const SCANLINE_HALF_THICKNESS: u32 = 3;
+/// A scanline without at least this much difference between its darkest and brightest pixels
+/// can't be showing the pattern
+const MIN_CONTRAST: f32 = 40.0;
+
+const WHITE: [f32; 3] = [1.0, 1.0, 1.0];
+
+/// Shows a frame counter as vertical stripes over the whole window, one new value every time the
+/// window is redrawn, and remembers when each value was put on the screen
+pub struct TimecodeDisplay {
+    counter: u32,
+    shown: VecDeque<(u32, DateTime<Utc>)>,
+}
+
+impl TimecodeDisplay {
+    pub fn new() -> TimecodeDisplay {
+        TimecodeDisplay { counter: 0, shown: VecDeque::new() }
+    }
+
+    pub fn draw(&self, overlay: &Overlay, display: &Display, target: &mut glium::Frame) {
+        let (width, height) = target.get_dimensions();
+        let viewport = Rect { left: 0, bottom: 0, width, height };
+
+        let cell_width = 2.0 / (CELL_COUNT + 2*QUIET_CELLS) as f32;
+
+        let mut vertices = Vec::new();
+        for (cell, white) in encode(self.counter).into_iter().enumerate() {
+            if !white { continue }
+
+            let left = -1.0 + cell_width*(cell + QUIET_CELLS) as f32;
+            let right = left + cell_width;
+
+            vertices.extend_from_slice(&[
+                OverlayVertex::new(left, -1.0),
+                OverlayVertex::new(left, 1.0),
+                OverlayVertex::new(right, 1.0),
+                OverlayVertex::new(left, -1.0),
+                OverlayVertex::new(right, 1.0),
+                OverlayVertex::new(right, -1.0),
+            ]);
+        }
+
+        overlay.draw(display, target, viewport, &vertices, PrimitiveType::TrianglesList, WHITE);
+    }
+
+    /// Call right after the frame from `draw` has been put on the screen
+    pub fn presented(&mut self) {
+        let now = Utc::now();
+        self.shown.push_back((self.counter, now));
+        self.counter = self.counter.wrapping_add(1);
+
+        let oldest = now - Duration::milliseconds(DISPLAY_HISTORY_MILLIS);
+        while matches!(self.shown.front(), Some(&(_, shown_at)) if shown_at < oldest) {
+            self.shown.pop_front();
+        }
+    }
+
+    /// When the frame with a decoded timecode was put on the screen
+    pub fn shown_at(&self, timecode: u32) -> Option<DateTime<Utc>> {
+        self.shown.iter().rev()
+            .find(|&&(counter, _)| counter & timecode_mask() == timecode)
+            .map(|&(_, shown_at)| shown_at)
+    }
+}
+
+/// Reads the timecode from a photo of the screen. The stripes are read along the middle row and
+/// the middle column in both directions, so it doesn't matter how the camera is turned
+pub fn decode(image: &GrayImage) -> Option<u32> {
+    let row = center_row(image);
+    let column = center_column(image);
+
+    let reversed_row: Vec<f32> = row.iter().rev().copied().collect();
+    let reversed_column: Vec<f32> = column.iter().rev().copied().collect();
+
+    [row, reversed_row, column, reversed_column].iter()
+        .find_map(|scanline| decode_scanline(scanline))
+}
+
+fn encode(counter: u32) -> Vec<bool> {
+    let gray = to_gray(counter & timecode_mask());
+
+    let bits: Vec<bool> = (0..TIMECODE_BITS).rev()
+        .map(|bit| gray & (1 << bit) != 0)
+        .collect();
+    let parity = bits.iter().filter(|&&bit| bit).count() % 2 == 1;
+
+    PREAMBLE.iter().copied()
+        .chain(bits)
+        .chain(Some(parity))
+        .collect()
+}
+
+fn decode_scanline(scanline: &[f32]) -> Option<u32> {
+    let darkest = scanline.iter().copied().fold(f32::INFINITY, f32::min);
+    let brightest = scanline.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if brightest - darkest < MIN_CONTRAST { return None }
+
+    let threshold = (darkest + brightest) / 2.0;
+    let runs = runs(scanline, threshold);
+
+    // try every place the preamble could start, and keep the first one that passes the parity check
+    (0..runs.len()).find_map(|start| {
+        let preamble = runs.get(start..start + PREAMBLE.len())?;
+
+        if preamble.iter().zip(PREAMBLE.iter()).any(|(run, &white)| run.white != white) { return None }
+
+        // the last preamble cell is black, so it runs together with any black bits after it
+        let (last, measured) = preamble.split_last()?;
+        let cell_width = measured.iter().map(|run| run.len as f64).sum::<f64>() / measured.len() as f64;
+        let consistent = measured.iter()
+            .all(|run| (run.len as f64 - cell_width).abs() <= CELL_WIDTH_TOLERANCE*cell_width);
+        if !consistent || (last.len as f64) < (1.0 - CELL_WIDTH_TOLERANCE)*cell_width { return None }
+
+        // sample the middle of every cell after the preamble
+        let origin = preamble[0].start as f64;
+        let cells: Vec<bool> = (PREAMBLE.len()..CELL_COUNT)
+            .map(|cell| {
+                let x = (origin + cell_width*(cell as f64 + 0.5)) as usize;
+                scanline.get(x).map(|&luma| luma > threshold)
+            })
+            .collect::<Option<_>>()?;
+
+        let (bits, parity) = cells.split_at(TIMECODE_BITS);
+        let ones = bits.iter().filter(|&&bit| bit).count();
+        if (ones % 2 == 1) != parity[0] { return None }
+
+        let gray = bits.iter().fold(0, |gray, &bit| (gray << 1) | bit as u32);
+        Some(from_gray(gray))
+    })
+}
+
+struct Run {
+    white: bool,
+    start: usize,
+    len: usize,
+}
+
+fn runs(scanline: &[f32], threshold: f32) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+
+    for (i, &luma) in scanline.iter().enumerate() {
+        let white = luma > threshold;
+        match runs.last_mut() {
+            Some(run) if run.white == white => run.len += 1,
+            _ => runs.push(Run { white, start: i, len: 1 }),
+        }
+    }
+
+    runs
+}
+
+fn center_row(image: &GrayImage) -> Vec<f32> {
+    let center = image.height() / 2;
+    let rows = center.saturating_sub(SCANLINE_HALF_THICKNESS)..(center + SCANLINE_HALF_THICKNESS + 1).min(image.height());
+
+    (0..image.width())
+        .map(|x| average(rows.clone().map(|y| image.get_pixel(x, y)[0])))
+        .collect()
+}
+
+fn center_column(image: &GrayImage) -> Vec<f32> {
+    let center = image.width() / 2;
+    let columns = center.saturating_sub(SCANLINE_HALF_THICKNESS)..(center + SCANLINE_HALF_THICKNESS + 1).min(image.width());
+
+    (0..image.height())
+        .map(|y| average(columns.clone().map(|x| image.get_pixel(x, y)[0])))
+        .collect()
+}
+
+fn average(values: impl Iterator<Item=u8>) -> f32 {
+    let (sum, count) = values.fold((0.0, 0.0), |(sum, count), value| (sum + value as f32, count + 1.0));
+    if count == 0.0 { 0.0 } else { sum / count }
+}
+
+fn timecode_mask() -> u32 {
+    (1 << TIMECODE_BITS) - 1
+}
+
+/// Neighboring values differ by one bit, so a photo taken while the screen changes is off by one
+/// frame at worst
+fn to_gray(value: u32) -> u32 {
+    value ^ (value >> 1)
+}
+
+fn from_gray(gray: u32) -> u32 {
+    let mut value = gray;
+    let mut shift = gray >> 1;
+    while shift != 0 {
+        value ^= shift;
+        shift >>= 1;
+    }
+    value
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    const CELL_PIXELS: u32 = 12;
+    const HEIGHT: u32 = 40;
+
+    /// What a camera pointed straight at the screen would see
+    fn photograph(cells: &[bool]) -> GrayImage {
+        let width = (cells.len() + 2*QUIET_CELLS) as u32 * CELL_PIXELS;
+
+        GrayImage::from_fn(width, HEIGHT, |x, _| {
+            let cell = (x / CELL_PIXELS) as usize;
+            let white = cell >= QUIET_CELLS && cells.get(cell - QUIET_CELLS).copied().unwrap_or(false);
+            Luma([if white { 230 } else { 20 }])
+        })
+    }
+
+    #[test]
+    fn gray_code_round_trips() {
+        for value in 0..=timecode_mask() {
+            assert_eq!(from_gray(to_gray(value)), value);
+        }
+    }
+
+    #[test]
+    fn neighboring_gray_codes_differ_by_one_bit() {
+        for value in 0..timecode_mask() {
+            assert_eq!((to_gray(value) ^ to_gray(value + 1)).count_ones(), 1);
+        }
+    }
+
+    #[test]
+    fn encoded_timecodes_decode() {
+        for &counter in &[0, 1, 2, 1000, 0x7fff, 0x8000, 0xaaaa, 0xffff] {
+            let cells = encode(counter);
+            assert_eq!(cells.len(), CELL_COUNT);
+            assert_eq!(decode(&photograph(&cells)), Some(counter));
+        }
+    }
+
+    #[test]
+    fn the_counter_wraps_around_to_the_timecode_bits() {
+        let counter = timecode_mask() + 6;
+        assert_eq!(decode(&photograph(&encode(counter))), Some(counter & timecode_mask()));
+    }
+
+    #[test]
+    fn timecodes_decode_however_the_camera_is_turned() {
+        let image = photograph(&encode(0x1234));
+
+        assert_eq!(decode(&image::imageops::rotate90(&image)), Some(0x1234));
+        assert_eq!(decode(&image::imageops::rotate180(&image)), Some(0x1234));
+        assert_eq!(decode(&image::imageops::rotate270(&image)), Some(0x1234));
+    }
+
+    #[test]
+    fn bad_parity_is_rejected() {
+        let mut cells = encode(0x1234);
+        let parity = cells.len() - 1;
+        cells[parity] = !cells[parity];
+
+        assert_eq!(decode(&photograph(&cells)), None);
+    }
+
+    #[test]
+    fn a_flipped_bit_is_rejected() {
+        let mut cells = encode(0x1234);
+        cells[PREAMBLE.len() + 3] = !cells[PREAMBLE.len() + 3];
+
+        assert_eq!(decode(&photograph(&cells)), None);
+    }
+
+    #[test]
+    fn a_missing_preamble_is_rejected() {
+        let mut cells = encode(0x1234);
+        for cell in cells.iter_mut().take(PREAMBLE.len()) {
+            *cell = false;
+        }
+
+        assert_eq!(decode(&photograph(&cells)), None);
+    }
+
+    #[test]
+    fn a_blank_picture_is_rejected() {
+        assert_eq!(decode(&GrayImage::from_pixel(200, HEIGHT, Luma([128]))), None);
+        assert_eq!(decode(&photograph(&[])), None);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Utc};
+use glium::{Display, Rect, Surface};
+use glium::index::PrimitiveType;
+
+use orbit_types::CapturedFrame;
+
+use crate::overlay::{Overlay, OverlayVertex, square_marker};
+use crate::streams::{Streams, StreamSource};
+
+/// How many captures the history graph goes back
+const HISTORY_LENGTH: usize = 50;
+const MARKER_HALF_SIZE: f32 = 0.015;
+
+const BORDER_COLOR: [f32; 3] = [0.3, 0.3, 0.3];
+const REQUESTED_TIME_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+const TOLERANCE_COLOR: [f32; 3] = [0.5, 0.5, 0.5];
+const IN_SYNC_COLOR: [f32; 3] = [0.0, 1.0, 0.0];
+const OUT_OF_SYNC_COLOR: [f32; 3] = [1.0, 0.0, 0.0];
+/// Each helper gets its own color in the history graph
+const HELPER_COLORS: [[f32; 3]; 6] = [
+    [0.2, 0.6, 1.0],
+    [1.0, 0.6, 0.2],
+    [0.8, 0.3, 1.0],
+    [1.0, 1.0, 0.3],
+    [0.3, 1.0, 1.0],
+    [1.0, 0.4, 0.7],
+];
+
+/// When each camera captured its still, relative to the time we asked for
+struct CaptureTiming {
+    /// In the order of the tiles
+    offsets_millis: Vec<(StreamSource, f64)>,
+}
+
+impl CaptureTiming {
+    fn spread_millis(&self) -> f64 {
+        let min = self.offsets_millis.iter().map(|&(_, offset)| offset).fold(f64::INFINITY, f64::min);
+        let max = self.offsets_millis.iter().map(|&(_, offset)| offset).fold(f64::NEG_INFINITY, f64::max);
+        if self.offsets_millis.is_empty() { 0.0 } else { max - min }
+    }
+
+    /// The average offset of the cameras on one helper, which follows the helper's clock
+    fn helper_offset_millis(&self, helper: SocketAddr) -> Option<f64> {
+        let mut total = 0.0;
+        let mut count = 0.0;
+
+        for &(source, offset) in self.offsets_millis.iter() {
+            if source.socket_addr() == helper {
+                total += offset;
+                count += 1.0;
+            }
+        }
+
+        if count == 0.0 { None } else { Some(total / count) }
+    }
+}
+
+pub struct TimingHistory {
+    captures: VecDeque<CaptureTiming>,
+}
+
+impl TimingHistory {
+    pub fn new() -> TimingHistory {
+        TimingHistory { captures: VecDeque::new() }
+    }
+
+    pub fn record(
+        &mut self,
+        requested_at: DateTime<Utc>,
+        devices: &[(SocketAddr, Vec<CapturedFrame>)],
+        streams: &Streams,
+        tolerance_millis: f64,
+    ) {
+        let mut offsets_millis: Vec<(StreamSource, f64)> = devices.iter()
+            .flat_map(|(addr, stills)| stills.iter().map(move |still| (
+                StreamSource::new(*addr, still.device_id()),
+                millis(*still.captured_at() - requested_at),
+            )))
+            .collect();
+
+        offsets_millis.sort_by_key(|&(source, _)| streams.get_stream_tile(source));
+
+        for &(source, offset) in offsets_millis.iter() {
+            if offset.abs() > tolerance_millis {
+                println!("{:?} captured {:.1}ms from the requested time, outside of the sync tolerance", source, offset);
+            }
+        }
+
+        let timing = CaptureTiming { offsets_millis };
+        println!("capture timing spread was {:.1}ms", timing.spread_millis());
+
+        self.captures.push_back(timing);
+        if self.captures.len() > HISTORY_LENGTH {
+            self.captures.pop_front();
+        }
+    }
+
+    fn helpers(&self) -> Vec<SocketAddr> {
+        let mut helpers: Vec<SocketAddr> = self.captures.iter()
+            .flat_map(|capture| capture.offsets_millis.iter().map(|(source, _)| source.socket_addr()))
+            .collect();
+
+        helpers.sort();
+        helpers.dedup();
+        helpers
+    }
+}
+
+/// Draws the latest capture on the top half of the window, with a row for each camera showing how
+/// far it was from the requested time and a bar on top for the spread. The bottom half graphs the
+/// average offset of each helper over the past captures, so a helper with a drifting clock shows
+/// up as a sloped line
+pub fn draw_timing_view(
+    history: &TimingHistory,
+    tolerance_millis: f64,
+    overlay: &Overlay,
+    display: &Display,
+    target: &mut glium::Frame,
+) {
+    let (width, height) = target.get_dimensions();
+
+    let latest_viewport = Rect { left: 0, bottom: height / 2, width, height: height / 2 };
+    let history_viewport = Rect { left: 0, bottom: 0, width, height: height / 2 };
+
+    overlay.draw_border(display, target, latest_viewport, BORDER_COLOR);
+    overlay.draw_border(display, target, history_viewport, BORDER_COLOR);
+
+    if let Some(latest) = history.captures.back() {
+        draw_latest(latest, tolerance_millis, latest_viewport, overlay, display, target);
+    }
+
+    draw_history(history, tolerance_millis, history_viewport, overlay, display, target);
+}
+
+fn draw_latest(
+    latest: &CaptureTiming,
+    tolerance_millis: f64,
+    viewport: Rect,
+    overlay: &Overlay,
+    display: &Display,
+    target: &mut glium::Frame,
+) {
+    let extent = latest.offsets_millis.iter()
+        .map(|&(_, offset)| offset.abs())
+        .fold(2.0*tolerance_millis, f64::max) * 1.1;
+    let to_x = |offset: f64| (offset / extent) as f32;
+
+    draw_vertical_line(0.0, REQUESTED_TIME_COLOR, viewport, overlay, display, target);
+    draw_vertical_line(to_x(-tolerance_millis), TOLERANCE_COLOR, viewport, overlay, display, target);
+    draw_vertical_line(to_x(tolerance_millis), TOLERANCE_COLOR, viewport, overlay, display, target);
+
+    // the top row is for the spread, then there's a row for each camera
+    let row_height = 2.0 / (latest.offsets_millis.len() + 1) as f32;
+    let row_y = |row: usize| 1.0 - row_height*(row as f32 + 0.5);
+
+    let (min, max) = latest.offsets_millis.iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &(_, offset)| (min.min(offset), max.max(offset)));
+
+    if min <= max {
+        let color = if max - min <= tolerance_millis { IN_SYNC_COLOR } else { OUT_OF_SYNC_COLOR };
+        let spread = [OverlayVertex::new(to_x(min), row_y(0)), OverlayVertex::new(to_x(max), row_y(0))];
+        overlay.draw(display, target, viewport, &spread, PrimitiveType::LinesList, color);
+    }
+
+    for (row, &(_, offset)) in (1..).zip(latest.offsets_millis.iter()) {
+        let color = if offset.abs() <= tolerance_millis { IN_SYNC_COLOR } else { OUT_OF_SYNC_COLOR };
+
+        let position = OverlayVertex::new(to_x(offset), row_y(row));
+        let mut vertices = square_marker(position, MARKER_HALF_SIZE).to_vec();
+        vertices.push(OverlayVertex::new(0.0, row_y(row)));
+        vertices.push(position);
+
+        overlay.draw(display, target, viewport, &vertices, PrimitiveType::LinesList, color);
+    }
+}
+
+fn draw_history(
+    history: &TimingHistory,
+    tolerance_millis: f64,
+    viewport: Rect,
+    overlay: &Overlay,
+    display: &Display,
+    target: &mut glium::Frame,
+) {
+    let extent = history.captures.iter()
+        .flat_map(|capture| capture.offsets_millis.iter().map(|&(_, offset)| offset.abs()))
+        .fold(2.0*tolerance_millis, f64::max) * 1.1;
+
+    let to_y = |offset: f64| (offset / extent) as f32;
+    let to_x = |capture: usize| -1.0 + 2.0*capture as f32 / (HISTORY_LENGTH - 1) as f32;
+
+    draw_horizontal_line(0.0, REQUESTED_TIME_COLOR, viewport, overlay, display, target);
+    draw_horizontal_line(to_y(-tolerance_millis), TOLERANCE_COLOR, viewport, overlay, display, target);
+    draw_horizontal_line(to_y(tolerance_millis), TOLERANCE_COLOR, viewport, overlay, display, target);
+
+    for (i, helper) in history.helpers().into_iter().enumerate() {
+        let color = HELPER_COLORS[i % HELPER_COLORS.len()];
+
+        let offsets: Vec<Option<f64>> = history.captures.iter()
+            .map(|capture| capture.helper_offset_millis(helper))
+            .collect();
+
+        let mut vertices = Vec::new();
+        for (capture, offset) in offsets.iter().enumerate() {
+            if let Some(offset) = *offset {
+                let position = OverlayVertex::new(to_x(capture), to_y(offset));
+                vertices.extend_from_slice(&square_marker(position, MARKER_HALF_SIZE));
+
+                // connect to the previous capture if this helper was in it
+                if let Some(Some(previous)) = capture.checked_sub(1).map(|previous| offsets[previous]) {
+                    vertices.push(OverlayVertex::new(to_x(capture - 1), to_y(previous)));
+                    vertices.push(position);
+                }
+            }
+        }
+
+        overlay.draw(display, target, viewport, &vertices, PrimitiveType::LinesList, color);
+    }
+}
+
+fn draw_vertical_line(x: f32, color: [f32; 3], viewport: Rect, overlay: &Overlay, display: &Display, target: &mut glium::Frame) {
+    let line = [OverlayVertex::new(x, -1.0), OverlayVertex::new(x, 1.0)];
+    overlay.draw(display, target, viewport, &line, PrimitiveType::LinesList, color);
+}
+
+fn draw_horizontal_line(y: f32, color: [f32; 3], viewport: Rect, overlay: &Overlay, display: &Display, target: &mut glium::Frame) {
+    let line = [OverlayVertex::new(-1.0, y), OverlayVertex::new(1.0, y)];
+    overlay.draw(display, target, viewport, &line, PrimitiveType::LinesList, color);
+}
+
+fn millis(duration: chrono::Duration) -> f64 {
+    duration.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0
+}
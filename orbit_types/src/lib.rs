@@ -2,11 +2,29 @@ use chrono::{DateTime, Utc};
 use v4l::buffer::StreamItem;
 use v4l::{Buffer, Format, Timestamp};
 use serde::{Serialize, Deserialize};
-use std::io::{Write, Read};
+use image::RgbImage;
+use image::codecs::jpeg::JpegEncoder;
+use std::io::{self, Write, Read};
+use std::borrow::Cow;
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
 pub struct DeviceId(u32);
 
+impl DeviceId {
+    /// The other way around from `Display`, for things named after cameras
+    pub fn from_name(name: &str) -> Option<DeviceId> {
+        name.strip_prefix("camera")?.parse().ok().map(DeviceId)
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "camera{}", self.0)
+    }
+}
+
+#[derive(Default)]
 pub struct DeviceIdGenerator(u32);
 
 impl DeviceIdGenerator {
@@ -14,6 +32,7 @@ impl DeviceIdGenerator {
         DeviceIdGenerator(0)
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> DeviceId {
         self.0 += 1;
         DeviceId(self.0)
@@ -22,8 +41,132 @@ impl DeviceIdGenerator {
 
 #[derive(Serialize, Deserialize)]
 pub enum Request {
-    Stream,
+    /// Start a session: the helper streams previews over the connection and keeps reading more
+    /// requests from it, sending their responses back between the preview frames
+    Stream(StreamSettings),
+    /// Take a picture with every camera as close as possible to the given time. If the helper is
+    /// armed, this is a "fire" and the frames come straight from the ring buffers, so the time can
+    /// also be in the recent past
     Snap(DateTime<Utc>),
+    /// Open every camera at snap resolution and keep it streaming into a ring buffer
+    Arm,
+    /// Close the cameras opened by `Arm`
+    Disarm,
+    /// Every frame that every camera captures between `start` and `end`. Cameras that capture
+    /// more than `max_frames` in the window return evenly spaced frames from it
+    Burst {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        max_frames: u32,
+    },
+    /// Like `Snap`, but the helper searches the stills for AprilTags from the 36h11 family, so the
+    /// station doesn't have to. Helpers built without the `detection` feature send back an error
+    /// for every camera instead
+    Detect {
+        target_time: DateTime<Utc>,
+        search: TagSearch,
+    },
+    /// Arm the cameras if they aren't already, and write everything they capture to disk on the
+    /// helper, at snap resolution
+    StartRecording,
+    /// Stop writing frames to disk. Cameras that were only armed for the recording are closed
+    StopRecording,
+    /// The recorded frames captured between `start` and `end`, evenly spaced if there are more
+    /// than `max_frames` from a camera
+    FetchRecording {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        max_frames: u32,
+    },
+    /// Changes the previews of one camera for the rest of the session, like to get a closer look
+    /// at it. `None` puts it back to the session's settings
+    Preview(DeviceId, Option<PreviewSettings>),
+    /// Like `Snap`, but the stills are scaled down to `thumbnail` before they're sent. The helper
+    /// keeps the originals under `capture_id` until they're fetched with `FetchOriginal`, or until
+    /// newer ones push them out
+    SnapThumbnails {
+        target_time: DateTime<Utc>,
+        capture_id: CaptureId,
+        thumbnail: PreviewSettings,
+    },
+    /// At most `max_len` bytes of the original still of a camera from `SnapThumbnails`, starting
+    /// `offset` bytes in, so a transfer that was cut off can pick up where it left off
+    FetchOriginal {
+        capture_id: CaptureId,
+        device_id: DeviceId,
+        offset: u64,
+        max_len: u32,
+    },
+    /// How the helper is holding up. Sessions also get this pushed to them every few seconds
+    Status,
+    /// The helper's `.orbit_log`, as a `String`. Only answered outside of a session
+    Log,
+    /// Start the helper process over, closing every camera and connection
+    Restart,
+    /// How late the timestamps of each camera are, in microseconds, replacing the ones sent
+    /// before. Captures aim that much later for each camera, so that once the station corrects
+    /// the timestamps, the frames line up. Cameras that are left out aren't late
+    Latencies(Vec<(DeviceId, i64)>),
+    /// The cameras that are plugged in and the formats they would stream, as a `DevicesResponse`.
+    /// The helper works the formats out from what the cameras say they can do, without starting
+    /// them, so it's fine to ask while a session is streaming. Only answered outside of a session
+    Devices,
+}
+
+/// Names the stills of one `SnapThumbnails`, so their originals can be asked for later
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CaptureId(pub u64);
+
+/// How the helper reads frames from its cameras during a session
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct StreamSettings {
+    /// How many buffers each camera fills while we're busy with the previous frame. With only one,
+    /// the camera misses every frame that arrives while we're sending the last one
+    pub buffer_count: u32,
+    /// Previews skip over older buffered frames and send the newest one, so a slow connection
+    /// makes them drop frames instead of falling behind. Snaps and bursts look at every frame anyway
+    pub latest_frame_wins: bool,
+    pub preview: PreviewSettings,
+}
+
+impl Default for StreamSettings {
+    fn default() -> StreamSettings {
+        StreamSettings {
+            buffer_count: 1,
+            latest_frame_wins: false,
+            preview: PreviewSettings::default(),
+        }
+    }
+}
+
+/// How much of the connection the previews get. The cameras keep capturing in the same format,
+/// the helper just sends less of it
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PreviewSettings {
+    /// Frames bigger than this are scaled down to fit, keeping their aspect ratio
+    pub width: u32,
+    pub height: u32,
+    /// Each camera sends at most this many frames per second, skipping the rest
+    pub fps: u32,
+    /// The JPEG quality of frames that had to be scaled down, from 1 to 100
+    pub quality: u8,
+}
+
+impl Default for PreviewSettings {
+    fn default() -> PreviewSettings {
+        PreviewSettings {
+            width: 640,
+            height: 360,
+            fps: 30,
+            quality: 80,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DevicesResponse {
+    /// Each camera with its formats, or why they couldn't be worked out
+    pub cameras: Vec<(DeviceId, Result<CameraFormats, String>)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -31,9 +174,125 @@ pub struct SnapResponse {
     pub stills: Vec<CapturedFrame>,
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct TagSearch {
+    pub tag_size_meters: f64,
+    /// The focal length of the cameras on stills `focal_length_width` pixels wide
+    pub focal_length_pixels: f64,
+    pub focal_length_width: u32,
+    /// Send the stills back along with what was found in them
+    pub include_stills: bool,
+}
+
+impl TagSearch {
+    /// The focal length on a still `image_width` pixels wide. The cameras can negotiate different
+    /// resolutions, but they all have the same lens
+    pub fn focal_length_at(&self, image_width: u32) -> f64 {
+        self.focal_length_pixels * image_width as f64 / self.focal_length_width as f64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DetectResponse {
+    pub cameras: Vec<CameraDetections>,
+}
+
+/// The tags one camera saw in its still
+#[derive(Serialize, Deserialize)]
+pub struct CameraDetections {
+    pub device_id: DeviceId,
+    pub captured_at: DateTime<Utc>,
+    pub detections: Vec<TagDetection>,
+    pub still: Option<CapturedFrame>,
+    /// Why the still couldn't be searched, in which case there are no detections
+    pub error: Option<String>,
+}
+
+/// Everything `apriltag::ApriltagDetection` knows about a tag, in a form that can be sent over the
+/// network
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct TagDetection {
+    pub tag_id: usize,
+    /// How well the pose fits the corners, lower is better
+    pub error: f64,
+    pub image_center: (u32, u32),
+    pub image_tag_corners: [(u32, u32); 4],
+    /// The rows of the matrix that takes the frame of the camera to the frame of the tag
+    pub rotation: [[f64; 3]; 3],
+    /// The position of the tag in the frame of the camera, in meters
+    pub translation: [f64; 3],
+}
+
+/// How a helper is holding up, so the station can tell when one is overheating, swapping, or
+/// running out of disk before it crashes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelperHealth {
+    /// The version of orbit_helper that's running
+    pub version: String,
+    /// The helper's clock when it made the report, for finding out how far off it is
+    pub clock: DateTime<Utc>,
+    pub uptime_secs: u64,
+    /// The hottest of the board's thermal zones, or `None` if it doesn't have any
+    pub cpu_temp_celsius: Option<f32>,
+    /// The 1, 5 and 15 minute load averages
+    pub load: [f32; 3],
+    /// These four are `None` if `/proc/meminfo` doesn't have them
+    pub memory_total_bytes: Option<u64>,
+    pub memory_available_bytes: Option<u64>,
+    pub swap_total_bytes: Option<u64>,
+    pub swap_free_bytes: Option<u64>,
+    /// Free space on the disk that recordings are written to, or `None` if it couldn't be measured
+    pub disk_free_bytes: Option<u64>,
+    /// How many frames per second each open camera is capturing
+    pub camera_fps: Vec<(DeviceId, f32)>,
+}
+
+/// Part of an original still, which is a `CapturedFrame` serialized with bincode once all of its
+/// parts are put together
+#[derive(Serialize, Deserialize)]
+pub struct OriginalChunk {
+    pub capture_id: CaptureId,
+    pub device_id: DeviceId,
+    pub offset: u64,
+    /// The size of the whole original, or `None` if the helper doesn't have it (anymore)
+    pub total_len: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BurstResponse {
+    /// The frames of each camera, in the order they were captured
+    pub bursts: Vec<Vec<CapturedFrame>>,
+}
+
 pub enum StreamResponse {
     Stop(DeviceId),
     Frame(CapturedFrame),
+    /// The response to a `Snap` sent during a session
+    Stills(SnapResponse),
+    /// The response to a `Burst` sent during a session
+    Burst(BurstResponse),
+    /// The response to a `Detect` sent during a session
+    Detections(DetectResponse),
+    /// The response to a `FetchRecording` sent during a session
+    Recorded(BurstResponse),
+    /// The response to a `FetchOriginal` sent during a session
+    Original(OriginalChunk),
+    /// The response to a `Status` sent during a session, or the helper's periodic report
+    Status(HelperHealth),
+    /// A camera was plugged in, and its frames are on the way
+    DeviceAdded(DeviceId),
+    /// A camera was unplugged
+    DeviceRemoved(DeviceId),
+    /// A camera stopped delivering frames, and the helper is trying to get it going again
+    Recovering(DeviceId, RecoveryAttempt),
+    /// The preview frames a camera dropped since its last report
+    Dropped(DeviceId, DropCounts),
+    /// The formats a camera ended up with, sent whenever it starts streaming previews
+    Formats(DeviceId, CameraFormats),
+    /// Why a camera stopped for good, like there not being enough USB bandwidth for it. A `Stop`
+    /// follows
+    Unavailable(DeviceId, String),
 }
 
 impl StreamResponse {
@@ -45,6 +304,42 @@ impl StreamResponse {
                 bincode::serialize_into(&mut writer, &StreamResponseInfo::Frame(frame.metadata))?;
                 writer.write_all(&frame.frame_data)?;
             },
+            StreamResponse::DeviceAdded(device_id) =>
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::DeviceAdded(device_id))?,
+            StreamResponse::DeviceRemoved(device_id) =>
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::DeviceRemoved(device_id))?,
+            StreamResponse::Recovering(device_id, attempt) =>
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Recovering(device_id, attempt))?,
+            StreamResponse::Dropped(device_id, counts) =>
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Dropped(device_id, counts))?,
+            StreamResponse::Formats(device_id, formats) =>
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Formats(device_id, formats))?,
+            StreamResponse::Unavailable(device_id, ref reason) =>
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Unavailable(device_id, reason.clone()))?,
+            StreamResponse::Stills(ref response) => {
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Stills)?;
+                bincode::serialize_into(&mut writer, response)?;
+            },
+            StreamResponse::Burst(ref response) => {
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Burst)?;
+                bincode::serialize_into(&mut writer, response)?;
+            },
+            StreamResponse::Detections(ref response) => {
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Detections)?;
+                bincode::serialize_into(&mut writer, response)?;
+            },
+            StreamResponse::Recorded(ref response) => {
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Recorded)?;
+                bincode::serialize_into(&mut writer, response)?;
+            },
+            StreamResponse::Original(ref chunk) => {
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Original)?;
+                bincode::serialize_into(&mut writer, chunk)?;
+            },
+            StreamResponse::Status(ref health) => {
+                bincode::serialize_into(&mut writer, &StreamResponseInfo::Status)?;
+                bincode::serialize_into(&mut writer, health)?;
+            },
         };
         Ok(())
     }
@@ -59,6 +354,18 @@ impl StreamResponse {
                 reader.read_exact(&mut frame_data)?;
                 StreamResponse::Frame(CapturedFrame { metadata, frame_data })
             },
+            StreamResponseInfo::DeviceAdded(device_id) => StreamResponse::DeviceAdded(device_id),
+            StreamResponseInfo::DeviceRemoved(device_id) => StreamResponse::DeviceRemoved(device_id),
+            StreamResponseInfo::Recovering(device_id, attempt) => StreamResponse::Recovering(device_id, attempt),
+            StreamResponseInfo::Dropped(device_id, counts) => StreamResponse::Dropped(device_id, counts),
+            StreamResponseInfo::Formats(device_id, formats) => StreamResponse::Formats(device_id, formats),
+            StreamResponseInfo::Unavailable(device_id, reason) => StreamResponse::Unavailable(device_id, reason),
+            StreamResponseInfo::Stills => StreamResponse::Stills(bincode::deserialize_from(&mut reader)?),
+            StreamResponseInfo::Burst => StreamResponse::Burst(bincode::deserialize_from(&mut reader)?),
+            StreamResponseInfo::Detections => StreamResponse::Detections(bincode::deserialize_from(&mut reader)?),
+            StreamResponseInfo::Recorded => StreamResponse::Recorded(bincode::deserialize_from(&mut reader)?),
+            StreamResponseInfo::Original => StreamResponse::Original(bincode::deserialize_from(&mut reader)?),
+            StreamResponseInfo::Status => StreamResponse::Status(bincode::deserialize_from(&mut reader)?),
         })
     }
 }
@@ -67,6 +374,53 @@ impl StreamResponse {
 enum StreamResponseInfo {
     Stop(DeviceId),
     Frame(FrameMetadata),
+    Stills,
+    Burst,
+    Detections,
+    Recorded,
+    Original,
+    Status,
+    DeviceAdded(DeviceId),
+    DeviceRemoved(DeviceId),
+    Recovering(DeviceId, RecoveryAttempt),
+    Dropped(DeviceId, DropCounts),
+    Formats(DeviceId, CameraFormats),
+    Unavailable(DeviceId, String),
+}
+
+/// What a camera actually captures, which isn't always what we asked for
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CameraFormat {
+    pub width: u32,
+    pub height: u32,
+    pub fourcc: [u8; 4],
+    pub fps: u32,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CameraFormats {
+    pub preview: CameraFormat,
+    /// What snaps, bursts, and armed cameras capture
+    pub snap: CameraFormat,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Default, Debug)]
+pub struct DropCounts {
+    /// Frames the camera captured while it had no free buffer to put them in
+    pub missed: u32,
+    /// Frames that were skipped over because a newer one was already waiting
+    pub discarded: u32,
+}
+
+/// What the helper does when a camera stalls, from the least to the most drastic
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RecoveryAttempt {
+    /// Close the camera and open it again
+    Reopen,
+    /// Reset the camera's USB port, so it gets plugged in again as a new device
+    ResetPort,
+    /// Nothing worked, so the camera stays stopped
+    GaveUp,
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
@@ -76,16 +430,39 @@ struct FrameMetadata {
     height: u32,
     encoding_repr: [u8; 4],
     captured_at: DateTime<Utc>,
+    sequence: u32,
     frame_data_len: u32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CapturedFrame {
     metadata: FrameMetadata,
     frame_data: Vec<u8>,
 }
 
-impl<'a> CapturedFrame {
+impl CapturedFrame {
+    pub fn new(
+        device_id: DeviceId,
+        width: u32,
+        height: u32,
+        encoding_repr: [u8; 4],
+        captured_at: DateTime<Utc>,
+        sequence: u32,
+        frame_data: Vec<u8>,
+    ) -> CapturedFrame {
+        let metadata = FrameMetadata {
+            device_id,
+            width,
+            height,
+            encoding_repr,
+            captured_at,
+            sequence,
+            frame_data_len: frame_data.len() as u32,
+        };
+
+        CapturedFrame { metadata, frame_data }
+    }
+
     pub fn from_frame(
         frame: &'a StreamItem<'a, Buffer<'a>>,
         used_format: Format,
@@ -100,6 +477,7 @@ impl<'a> CapturedFrame {
             height: used_format.height,
             encoding_repr: used_format.fourcc.repr,
             captured_at: timestamp_to_utc(frame.meta().timestamp, boot_time_utc),
+            sequence: frame.meta().sequence,
             frame_data_len: frame_data.len() as u32,
         };
 
@@ -126,9 +504,88 @@ impl<'a> CapturedFrame {
         &self.metadata.captured_at
     }
 
+    /// Moves the capture time earlier by `latency`, for cameras whose timestamps are known to be late
+    pub fn correct_captured_at(&mut self, latency: chrono::Duration) {
+        self.metadata.captured_at = self.metadata.captured_at - latency;
+    }
+
+    /// Counts up by one for every frame the camera captures, so gaps mean dropped frames
+    pub fn sequence(&self) -> u32 {
+        self.metadata.sequence
+    }
+
     pub fn frame_data(&self) -> &[u8] {
         &self.frame_data
     }
+
+    /// Whether the camera sent the frame as it is, rather than as a JPEG
+    pub fn is_yuyv(&self) -> bool {
+        self.metadata.encoding_repr == YUYV
+    }
+
+    /// Swaps the picture for a smaller JPEG of it, keeping which camera captured it and when
+    pub fn replace_with_jpeg(&mut self, width: u32, height: u32, jpeg: Vec<u8>) {
+        self.metadata.width = width;
+        self.metadata.height = height;
+        self.metadata.encoding_repr = *b"MJPG";
+        self.metadata.frame_data_len = jpeg.len() as u32;
+        self.frame_data = jpeg;
+    }
+}
+
+/// Cameras that can't compress their frames send two pixels in every four bytes: both of their
+/// brightnesses, and the color they share
+pub const YUYV: [u8; 4] = *b"YUYV";
+
+/// The pixels of a YUYV picture as RGB, three bytes each, row by row. `None` if there are fewer
+/// pixels than the size says
+pub fn yuyv_to_rgb(data: &[u8], width: u32, height: u32) -> Option<Vec<u8>> {
+    let len = width as usize * height as usize * 2;
+    if data.len() < len { return None }
+
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+
+    for chunk in data[..len].chunks_exact(4) {
+        let u = chunk[1] as f32 - 128.0;
+        let v = chunk[3] as f32 - 128.0;
+
+        for &y in &[chunk[0], chunk[2]] {
+            let y = y as f32;
+            rgb.push((y + 1.402*v).clamp(0.0, 255.0) as u8);
+            rgb.push((y - 0.344*u - 0.714*v).clamp(0.0, 255.0) as u8);
+            rgb.push((y + 1.772*u).clamp(0.0, 255.0) as u8);
+        }
+    }
+
+    Some(rgb)
+}
+
+// how much of the picture is kept when YUYV frames are saved as JPEGs
+pub const JPEG_QUALITY: u8 = 90;
+
+/// The frame as a JPEG file. MJPG frames are JPEGs already, YUYV ones have to be compressed first
+pub fn frame_jpeg(frame: &CapturedFrame) -> io::Result<Cow<'_, [u8]>> {
+    if !frame.is_yuyv() {
+        return Ok(Cow::Borrowed(frame.frame_data()));
+    }
+
+    let image = yuyv_to_rgb(frame.frame_data(), frame.width(), frame.height())
+        .and_then(|rgb| RgbImage::from_raw(frame.width(), frame.height(), rgb))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the frame is smaller than its size says"))?;
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&image)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(Cow::Owned(jpeg))
+}
+
+/// The brightness of every pixel of a YUYV picture, which is every other byte
+pub fn yuyv_to_luma(data: &[u8], width: u32, height: u32) -> Option<Vec<u8>> {
+    let len = width as usize * height as usize * 2;
+    if data.len() < len { return None }
+
+    Some(data[..len].iter().step_by(2).copied().collect())
 }
 
 fn timestamp_to_utc(timestamp: Timestamp, boot_time_utc: DateTime<Utc>) -> DateTime<Utc> {
@@ -136,4 +593,80 @@ fn timestamp_to_utc(timestamp: Timestamp, boot_time_utc: DateTime<Utc>) -> DateT
         + chrono::Duration::microseconds(timestamp.usec as i64);
 
     boot_time_utc + time_after_boot
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use image::GenericImageView;
+
+    #[test]
+    fn device_ids_round_trip_through_their_names() {
+        let device_id = DeviceIdGenerator::new().next();
+        assert_eq!(DeviceId::from_name(&device_id.to_string()), Some(device_id));
+        assert_eq!(DeviceId::from_name("camera"), None);
+        assert_eq!(DeviceId::from_name("video1"), None);
+    }
+
+    #[test]
+    fn focal_length_scales_with_the_still() {
+        let search = TagSearch { tag_size_meters: 0.1, focal_length_pixels: 1484.0, focal_length_width: 1280, include_stills: false };
+
+        assert_eq!(search.focal_length_at(1280), 1484.0);
+        assert_eq!(search.focal_length_at(640), 742.0);
+        assert_eq!(search.focal_length_at(1920), 2226.0);
+    }
+
+    #[test]
+    fn gray_yuyv_stays_gray() {
+        // two pixels sharing no color, one dark and one bright
+        assert_eq!(yuyv_to_rgb(&[20, 128, 200, 128], 2, 1), Some(vec![20, 20, 20, 200, 200, 200]));
+        assert_eq!(yuyv_to_luma(&[20, 128, 200, 128], 2, 1), Some(vec![20, 200]));
+    }
+
+    #[test]
+    fn yuyv_colors_are_clamped() {
+        // as red as it gets
+        assert_eq!(yuyv_to_rgb(&[255, 128, 255, 255], 2, 1), Some(vec![255, 164, 255, 255, 164, 255]));
+    }
+
+    #[test]
+    fn short_yuyv_is_rejected() {
+        assert_eq!(yuyv_to_rgb(&[0; 6], 2, 2), None);
+        assert_eq!(yuyv_to_luma(&[0; 6], 2, 2), None);
+    }
+
+    #[test]
+    fn extra_yuyv_is_ignored() {
+        assert_eq!(yuyv_to_luma(&[1, 128, 2, 128, 3, 128], 2, 1), Some(vec![1, 2]));
+    }
+
+    fn frame(fourcc: [u8; 4], width: u32, height: u32, data: Vec<u8>) -> CapturedFrame {
+        CapturedFrame::new(DeviceIdGenerator::new().next(), width, height, fourcc, Utc.timestamp_millis(0), 0, data)
+    }
+
+    #[test]
+    fn yuyv_frames_are_compressed() {
+        let yuyv = frame(YUYV, 16, 8, vec![128; 16 * 8 * 2]);
+        let jpeg = frame_jpeg(&yuyv).unwrap();
+
+        let image = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (16, 8));
+    }
+
+    #[test]
+    fn jpeg_frames_are_saved_as_they_are() {
+        let mjpg = frame(*b"MJPG", 16, 8, vec![1, 2, 3]);
+        let jpeg = frame_jpeg(&mjpg).unwrap();
+
+        assert!(matches!(jpeg, Cow::Borrowed(&[1, 2, 3])));
+    }
+
+    #[test]
+    fn short_yuyv_frames_are_not_jpegs() {
+        let error = frame_jpeg(&frame(YUYV, 16, 8, vec![128; 10])).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,296 @@
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, Utc};
+use serde::de::DeserializeOwned;
+use orbit_types::{frame_jpeg, CameraFormat, DevicesResponse, HelperHealth, Request, SnapResponse};
+
+const HELPER_PORT: u16 = 2000;
+// helper0, helper1 and so on are given 192.168.2.100, 192.168.2.101 and so on, room for twenty
+const DEFAULT_RANGE: AddressRange = AddressRange { first: Ipv4Addr::new(192, 168, 2, 100), last: 119 };
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(300);
+// snaps open every camera, which takes a while
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+// the fastest round trip gives the best guess at the clock offset
+const PING_COUNT: u32 = 5;
+// every helper gets the request before the time comes, so the stills line up
+const SNAP_DELAY_MILLIS: i64 = 1000;
+
+const COMMANDS: [&str; 6] = ["discover", "devices", "ping", "log", "restart", "snap"];
+
+const USAGE: &str = "\
+usage: orbitctl <command> [--out <dir>] [--range <first>-<last>] [helper...]
+
+commands:
+    discover    find the helpers on the rig's network
+    devices     list the cameras of each helper and the formats they would stream
+    ping        measure the round trip to each helper, and how far off its clock is
+    log         print each helper's .orbit_log, or save them to --out
+    restart     start orbit_helper over on each helper
+    snap        take a picture with every camera and save them as JPEGs to --out
+
+A helper is a host with an optional port, like 192.168.2.100 or helper0:2000. Without any, the
+command goes to every helper that discover finds. Discover tries the addresses in --range, which
+is 192.168.2.100-119 unless given, and only the last part of the address can change";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    if !COMMANDS.contains(&command.as_str()) {
+        exit_with_usage(&format!("unknown command {:?}", command));
+    }
+
+    let mut out = None;
+    let mut range = DEFAULT_RANGE;
+    let mut helpers = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => out = args.next().map(PathBuf::from),
+            "--range" => match args.next().as_deref().and_then(AddressRange::parse) {
+                Some(given) => range = given,
+                None => exit_with_usage("--range takes addresses like 192.168.2.100-119"),
+            },
+            helper => match resolve(helper) {
+                Some(socket_addr) => helpers.push(socket_addr),
+                None => exit_with_usage(&format!("couldn't find the helper {}", helper)),
+            },
+        }
+    }
+
+    if command == "discover" {
+        discover(range);
+        return;
+    }
+
+    if helpers.is_empty() {
+        helpers = discover(range).into_iter().map(|(socket_addr, _)| socket_addr).collect();
+        println!();
+    }
+
+    match command.as_str() {
+        "devices" => for_each_helper(&helpers, devices),
+        "ping" => for_each_helper(&helpers, ping),
+        "log" => for_each_helper(&helpers, |socket_addr| log(socket_addr, out.as_deref())),
+        "restart" => for_each_helper(&helpers, |socket_addr| send(socket_addr, &Request::Restart)),
+        "snap" => snap(&helpers, out),
+        _ => unreachable!(),
+    }
+}
+
+fn exit_with_usage(problem: &str) -> ! {
+    println!("{}\n\n{}", problem, USAGE);
+    process::exit(1);
+}
+
+/// The addresses from `first` up to the one that ends in `last`
+#[derive(Copy, Clone)]
+struct AddressRange {
+    first: Ipv4Addr,
+    last: u8,
+}
+
+impl AddressRange {
+    /// Parses ranges like `192.168.2.100-119`
+    fn parse(s: &str) -> Option<AddressRange> {
+        let (first, last) = s.split_once('-')?;
+        let first: Ipv4Addr = first.parse().ok()?;
+        let last: u8 = last.parse().ok()?;
+        if last < first.octets()[3] { return None }
+
+        Some(AddressRange { first, last })
+    }
+
+    fn addresses(self) -> impl Iterator<Item=Ipv4Addr> {
+        let [a, b, c, first] = self.first.octets();
+        (first..=self.last).map(move |d| Ipv4Addr::new(a, b, c, d))
+    }
+}
+
+/// Helpers listen on `HELPER_PORT` unless told otherwise
+fn resolve(helper: &str) -> Option<SocketAddr> {
+    let with_port = if helper.contains(':') { helper.to_string() } else { format!("{}:{}", helper, HELPER_PORT) };
+    with_port.to_socket_addrs().ok()?.next()
+}
+
+fn for_each_helper(helpers: &[SocketAddr], f: impl Fn(SocketAddr) -> bincode::Result<()>) {
+    for &socket_addr in helpers {
+        if let Err(e) = f(socket_addr) {
+            println!("{}: {}", socket_addr, e);
+        }
+    }
+}
+
+/// Asks every address the helpers could have at the same time, and prints the ones that answer
+fn discover(range: AddressRange) -> Vec<(SocketAddr, HelperHealth)> {
+    let handles: Vec<_> = range.addresses()
+        .map(|address| {
+            let socket_addr = SocketAddr::from((address, HELPER_PORT));
+            thread::spawn(move || (socket_addr, request::<HelperHealth>(socket_addr, &Request::Status)))
+        })
+        .collect();
+
+    let helpers: Vec<_> = handles.into_iter()
+        .filter_map(|handle| match handle.join().unwrap() {
+            (socket_addr, Ok(health)) => Some((socket_addr, health)),
+            (_, Err(_)) => None,
+        })
+        .collect();
+
+    for (socket_addr, health) in helpers.iter() {
+        println!(
+            "{}: orbit_helper {}, up {}, {} cameras streaming",
+            socket_addr,
+            health.version,
+            format_uptime(health.uptime_secs),
+            health.camera_fps.len(),
+        );
+    }
+    if helpers.is_empty() {
+        println!("no helpers found");
+    }
+
+    helpers
+}
+
+fn devices(socket_addr: SocketAddr) -> bincode::Result<()> {
+    let response: DevicesResponse = request(socket_addr, &Request::Devices)?;
+
+    println!("{}: {} cameras", socket_addr, response.cameras.len());
+    for (device_id, formats) in response.cameras {
+        match formats {
+            Ok(formats) => println!("    {}: previews {}, snaps {}", device_id, format_camera_format(formats.preview), format_camera_format(formats.snap)),
+            Err(e) => println!("    {}: formats unknown: {}", device_id, e),
+        }
+    }
+
+    Ok(())
+}
+
+fn ping(socket_addr: SocketAddr) -> bincode::Result<()> {
+    let mut fastest: Option<(Duration, chrono::Duration)> = None;
+
+    for _ in 0..PING_COUNT {
+        let mut connection = connect(socket_addr)?;
+
+        let sent_at = Utc::now();
+        let started = Instant::now();
+        bincode::serialize_into(&mut connection, &Request::Status)?;
+        let health: HelperHealth = bincode::deserialize_from(&mut connection)?;
+        let round_trip = started.elapsed();
+
+        // the helper read its clock about halfway through
+        let halfway = sent_at + chrono::Duration::from_std(round_trip / 2).unwrap_or_else(|_| chrono::Duration::zero());
+        let offset = health.clock - halfway;
+
+        let is_fastest = match fastest {
+            Some((fastest, _)) => round_trip < fastest,
+            None => true,
+        };
+        if is_fastest {
+            fastest = Some((round_trip, offset));
+        }
+    }
+
+    if let Some((round_trip, offset)) = fastest {
+        println!(
+            "{}: round trip {:.1}ms, clock {:+.1}ms from ours",
+            socket_addr,
+            round_trip.as_secs_f64() * 1000.0,
+            offset.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0,
+        );
+    }
+
+    Ok(())
+}
+
+fn log(socket_addr: SocketAddr, out: Option<&Path>) -> bincode::Result<()> {
+    let log: String = request(socket_addr, &Request::Log)?;
+
+    match out {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            let path = dir.join(format!("{}.orbit_log", socket_addr.ip()));
+            fs::write(&path, log)?;
+            println!("{}: saved to {:?}", socket_addr, path);
+        },
+        None => println!("==> {} <==\n{}", socket_addr, log),
+    }
+
+    Ok(())
+}
+
+/// Asks every helper at once for the same time, like the station does
+fn snap(helpers: &[SocketAddr], out: Option<PathBuf>) {
+    let target_time = Utc::now() + chrono::Duration::milliseconds(SNAP_DELAY_MILLIS);
+
+    let handles: Vec<_> = helpers.iter()
+        .map(|&socket_addr| thread::spawn(move || (socket_addr, request::<SnapResponse>(socket_addr, &Request::Snap(target_time)))))
+        .collect();
+
+    let dir = out.unwrap_or_else(|| PathBuf::from("snaps").join(Local::now().format("%Y-%m-%dT%H-%M-%S").to_string()));
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("couldn't create {:?}: {}", dir, e);
+        return;
+    }
+
+    for handle in handles {
+        let (socket_addr, response) = handle.join().unwrap();
+        let stills = match response {
+            Ok(response) => response.stills,
+            Err(e) => {
+                println!("{}: {}", socket_addr, e);
+                continue;
+            },
+        };
+
+        for still in stills {
+            let path = dir.join(format!("{}_{}.jpg", socket_addr.ip(), still.device_id()));
+            let offset = *still.captured_at() - target_time;
+
+            match frame_jpeg(&still).and_then(|jpeg| fs::write(&path, jpeg)) {
+                Ok(()) => println!(
+                    "{}: saved {} to {:?}, captured {:+.1}ms from the requested time",
+                    socket_addr,
+                    still.device_id(),
+                    path,
+                    offset.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0,
+                ),
+                Err(e) => println!("{}: couldn't save {}: {}", socket_addr, still.device_id(), e),
+            }
+        }
+    }
+}
+
+fn connect(socket_addr: SocketAddr) -> io::Result<TcpStream> {
+    let connection = TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT)?;
+    connection.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    Ok(connection)
+}
+
+/// Sends a request that gets a single response, over a connection of its own
+fn request<T: DeserializeOwned>(socket_addr: SocketAddr, request: &Request) -> bincode::Result<T> {
+    let mut connection = connect(socket_addr)?;
+    bincode::serialize_into(&mut connection, request)?;
+    bincode::deserialize_from(&mut connection)
+}
+
+/// Sends a request that doesn't get a response
+fn send(socket_addr: SocketAddr, request: &Request) -> bincode::Result<()> {
+    let mut connection = connect(socket_addr)?;
+    bincode::serialize_into(&mut connection, request)?;
+    println!("{}: sent", socket_addr);
+    Ok(())
+}
+
+fn format_camera_format(format: CameraFormat) -> String {
+    format!("{}x{} {} at {}fps", format.width, format.height, String::from_utf8_lossy(&format.fourcc), format.fps)
+}
+
+fn format_uptime(secs: u64) -> String {
+    format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+}